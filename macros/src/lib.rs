@@ -0,0 +1,200 @@
+//! Attribute macros writing the `Factory` boilerplate of
+//! `cli-music-player`. Use them through `cli_music_player::common::factory`,
+//! whose items the generated code refers to.
+
+use proc_macro::TokenStream;
+use proc_macro2::Span;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, parse_quote, AttributeArgs, Error, Fields, ItemStruct, ItemTrait, Lit, Meta, NestedMeta, Path, TypeParamBound};
+
+/// The type a factory called `<Type><suffix>` generates, unless given
+/// explicitly
+fn target_type(explicit: Option<Path>, item: &ItemStruct, suffix: &str) -> Result<Path, Error> {
+    if let Some(target) = explicit {
+        return Ok(target);
+    }
+    let name = item.ident.to_string();
+    match name.strip_suffix(suffix).filter(|target| !target.is_empty()) {
+        Some(target) => Ok(format_ident!("{}", target).into()),
+        None => Err(Error::new_spanned(&item.ident, format!(
+            "cannot tell what {name} generates; name it <Type>{suffix} or pass the type, like #[..({name}Target)]")))
+    }
+}
+
+fn unit_struct(item: &ItemStruct) -> Result<(), Error> {
+    match item.fields {
+        Fields::Unit => Ok(()),
+        _ => Err(Error::new_spanned(&item.fields, "factories are unit structs; their input comes as JSON"))
+    }
+}
+
+/// `Default` and `new()` for the unit struct `item`
+fn constructors(item: &ItemStruct) -> proc_macro2::TokenStream {
+    let name = &item.ident;
+    let doc = format!("Creates an instance of [{name}]");
+    quote! {
+        impl #name {
+            #[doc = #doc]
+            pub fn new() -> Self {
+                Self {}
+            }
+        }
+
+        impl ::core::default::Default for #name {
+            fn default() -> Self {
+                Self::new()
+            }
+        }
+    }
+}
+
+/// Turns a unit struct into a factory whose JSON input is the
+/// declaration of the generated value itself. The generated type is the
+/// struct's name without `Forward`, or the one passed as argument.
+/// ```
+/// use cli_music_player::common::factory::{Factory, forward_factory};
+///
+/// #[derive(serde::Deserialize, Debug, PartialEq)]
+/// struct Station { name: String, bitrate: u32 }
+///
+/// #[forward_factory]
+/// struct StationForward;
+///
+/// let station = StationForward::new().generate(serde_json::json!({ "name": "Radio Paradise", "bitrate": 320 }));
+/// assert_eq!(Station { name: "Radio Paradise".to_string(), bitrate: 320 }, station);
+/// ```
+#[proc_macro_attribute]
+pub fn forward_factory(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let explicit = match attr.is_empty() {
+        true => None,
+        false => Some(parse_macro_input!(attr as Path))
+    };
+    let item = parse_macro_input!(item as ItemStruct);
+    let expanded = unit_struct(&item)
+        .and_then(|_| target_type(explicit, &item, "Forward"))
+        .map(|target| {
+            let name = &item.ident;
+            let constructors = constructors(&item);
+            quote! {
+                #item
+                #constructors
+
+                impl ::cli_music_player::common::factory::Factory<#target> for #name {
+                    /// Generates by forwarding the declaration of a json object.
+                    /// Panics on bad input.
+                    fn generate(&self, args: ::cli_music_player::common::factory::serde_json::Value) -> #target {
+                        ::cli_music_player::common::factory::serde_json::from_value(args)
+                            .unwrap_or_else(|err| panic!("{} cannot generate {}: {}", stringify!(#name), stringify!(#target), err))
+                    }
+                }
+            }
+        });
+    expanded.unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Turns a unit struct into a factory filling in fields its JSON input
+/// leaves out. Fields are given as `name = literal`; a leading type names
+/// what is generated, which is otherwise the struct's name without
+/// `Default`.
+/// ```
+/// use cli_music_player::common::factory::{Factory, default_factory};
+///
+/// #[derive(serde::Deserialize, Debug, PartialEq)]
+/// struct Station { name: String, bitrate: u32 }
+///
+/// #[default_factory(Station, bitrate = 128)]
+/// struct LowBitrate;
+///
+/// let station = LowBitrate::new().generate(serde_json::json!({ "name": "SomaFM" }));
+/// assert_eq!(Station { name: "SomaFM".to_string(), bitrate: 128 }, station);
+/// let station = LowBitrate::new().generate(serde_json::json!({ "name": "SomaFM", "bitrate": 64 }));
+/// assert_eq!(64, station.bitrate);
+/// ```
+#[proc_macro_attribute]
+pub fn default_factory(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let args = parse_macro_input!(attr as AttributeArgs);
+    let item = parse_macro_input!(item as ItemStruct);
+    let mut explicit = None;
+    let mut fields = vec![];
+    for (idx, arg) in args.into_iter().enumerate() {
+        match arg {
+            NestedMeta::Meta(Meta::Path(path)) if idx == 0 => explicit = Some(path),
+            NestedMeta::Meta(Meta::NameValue(pair)) if pair.path.get_ident().is_some() => match pair.lit {
+                Lit::Str(_) | Lit::Int(_) | Lit::Float(_) | Lit::Bool(_) => {
+                    let field = pair.path.get_ident().expect("checked above").to_string();
+                    fields.push((field, pair.lit));
+                },
+                lit => return Error::new_spanned(lit, "expected a string, number or bool").into_compile_error().into()
+            },
+            arg => return Error::new_spanned(arg, "expected `field = literal`").into_compile_error().into()
+        }
+    }
+    let expanded = unit_struct(&item)
+        .and_then(|_| target_type(explicit, &item, "Default"))
+        .map(|target| {
+            let name = &item.ident;
+            let constructors = constructors(&item);
+            let (names, values): (Vec<_>, Vec<_>) = fields.into_iter().unzip();
+            quote! {
+                #item
+                #constructors
+
+                impl ::cli_music_player::common::factory::Factory<#target> for #name {
+                    /// Generates from a json object, filling in the fields it
+                    /// leaves out. Panics on bad input.
+                    fn generate(&self, args: ::cli_music_player::common::factory::serde_json::Value) -> #target {
+                        use ::cli_music_player::common::factory::serde_json::{self, Map, Value};
+                        let mut declared = Map::new();
+                        #(declared.insert(#names.to_string(), Value::from(#values));)*
+                        match args {
+                            Value::Object(given) => declared.extend(given),
+                            other => panic!("{} expects a json object, got {}", stringify!(#name), other)
+                        }
+                        serde_json::from_value(Value::Object(declared))
+                            .unwrap_or_else(|err| panic!("{} cannot generate {}: {}", stringify!(#name), stringify!(#target), err))
+                    }
+                }
+            }
+        });
+    expanded.unwrap_or_else(Error::into_compile_error).into()
+}
+
+/// Declares a trait standing for one instantiation of a generic trait,
+/// e.g. `Factory<DownloadConfig>`, and implements it for everything
+/// implementing that. `#[enum_dispatch]` takes generic traits for
+/// pass-throughs, so enums dispatch the specialization instead. Provided
+/// methods of the trait are kept.
+/// ```
+/// use cli_music_player::common::factory::{Factory, forward_factory, enum_dispatch_specialize};
+///
+/// #[derive(serde::Deserialize)]
+/// struct Station { name: String }
+///
+/// #[forward_factory]
+/// struct StationForward;
+///
+/// #[enum_dispatch_specialize(Factory<Station>)]
+/// pub trait StationFactory {
+///     fn name_of(&self, args: serde_json::Value) -> String {
+///         self.generate(args).name
+///     }
+/// }
+///
+/// assert_eq!("SomaFM", StationForward::new().name_of(serde_json::json!({ "name": "SomaFM" })));
+/// ```
+#[proc_macro_attribute]
+pub fn enum_dispatch_specialize(attr: TokenStream, item: TokenStream) -> TokenStream {
+    let bound = parse_macro_input!(attr as TypeParamBound);
+    let mut item = parse_macro_input!(item as ItemTrait);
+    if !item.generics.params.is_empty() {
+        return Error::new_spanned(&item.generics, "the specialization itself cannot be generic").into_compile_error().into();
+    }
+    item.supertraits.push(bound.clone());
+    let name = &item.ident;
+    let implementor = syn::Ident::new("T", Span::mixed_site());
+    let generic: syn::GenericParam = parse_quote!(#implementor: #bound);
+    quote! {
+        #item
+        impl <#generic> #name for #implementor {}
+    }.into()
+}
@@ -1,5 +1,45 @@
+use std::path::Path;
+
 use directories::ProjectDirs;
+use serde::{Serialize, de::DeserializeOwned};
 
 pub fn project_dirs() -> ProjectDirs { 
     ProjectDirs::from("com", "Pegasust", "cli-music-player").unwrap()
-}
\ No newline at end of file
+}
+
+/// Reads the JSON file at `path`, or gives the default if there is none
+/// yet. `what` names the file in errors, e.g. "download queue".
+pub fn load_json<T: DeserializeOwned + Default>(path: &Path, what: &str) -> Result<T, String> {
+    if !path.exists() {
+        return Ok(T::default());
+    }
+    std::fs::read_to_string(path)
+        .map_err(|err| err.to_string())
+        .and_then(|json| serde_json::from_str(&json).map_err(|err| err.to_string()))
+        .map_err(|err| format!("Cannot read {what} {path:?}: {err}"))
+}
+
+/// Writes `value` as JSON to `path`, creating its dir if needed. Writes
+/// next to the file first, so a crash never leaves it half written.
+/// ```
+/// use cli_music_player::common::config::{load_json, save_json};
+///
+/// let dir = tempfile::tempdir().unwrap();
+/// let path = dir.path().join("nested/state.json");
+/// assert_eq!(Ok(Vec::<u32>::new()), load_json(&path, "state"));
+/// save_json(&path, "state", &vec![1, 2]).unwrap();
+/// assert_eq!(Ok(vec![1, 2]), load_json::<Vec<u32>>(&path, "state"));
+/// std::fs::write(&path, "{").unwrap();
+/// assert!(load_json::<Vec<u32>>(&path, "state").unwrap_err().starts_with("Cannot read state"));
+/// ```
+pub fn save_json<T: Serialize + ?Sized>(path: &Path, what: &str, value: &T) -> Result<(), String> {
+    if let Some(dir) = path.parent().filter(|dir| !dir.exists()) {
+        std::fs::create_dir_all(dir).map_err(|err| format!("Cannot std::fs::create_dir_all({dir:?}): {err:?}"))?;
+    }
+    let staging = path.with_extension("json.tmp");
+    serde_json::to_string_pretty(value)
+        .map_err(|err| err.to_string())
+        .and_then(|json| std::fs::write(&staging, json).map_err(|err| err.to_string()))
+        .and_then(|_| std::fs::rename(&staging, path).map_err(|err| err.to_string()))
+        .map_err(|err| format!("Cannot write {what} {path:?}: {err}"))
+}
@@ -1,9 +1,96 @@
+use std::{fmt::Display, path::PathBuf};
+
 use enum_dispatch::enum_dispatch;
 use serde_json::Value;
 
+pub use cli_music_player_macros::{default_factory, forward_factory, enum_dispatch_specialize};
+// for the code those generate
+#[doc(hidden)]
+pub use serde_json;
+
 #[enum_dispatch]
 pub trait Factory<T> {
     fn generate(&self, args: Value) -> T;
 }
 
+/// [Factory] for input that may be wrong, e.g. typed by a user or read
+/// from a config file
+pub trait TryFactory<T> {
+    fn try_generate(&self, args: Value) -> Result<T, FactoryError>;
+}
+
+/// Why a [TryFactory] rejected its input
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FactoryError {
+    /// The arguments were not a JSON object. `got` is what they were.
+    NotAnObject { got: String },
+    /// A required field is absent
+    MissingField { field: String },
+    /// A field nobody asked for, most likely a typo of `did_you_mean`
+    UnknownField { field: String, did_you_mean: Option<String> },
+    /// A field has the wrong type or an unusable value
+    InvalidField { field: String, message: String },
+    /// The URI does not have the shape the provider expects
+    InvalidUri { uri: String, message: String, did_you_mean: Option<String> },
+    /// Downloads could not be written to this directory
+    Unwritable { path: PathBuf, message: String }
+}
+
+impl Display for FactoryError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let hint = |did_you_mean: &Option<String>| did_you_mean.as_ref()
+            .map(|suggestion| format!("; did you mean {suggestion:?}?"))
+            .unwrap_or_default();
+        match self {
+            Self::NotAnObject { got } => write!(f, "Expected a JSON object, got {got}"),
+            Self::MissingField { field } => write!(f, "Missing field {field:?}"),
+            Self::UnknownField { field, did_you_mean } => write!(f, "Unknown field {field:?}{}", hint(did_you_mean)),
+            Self::InvalidField { field, message } => write!(f, "Invalid field {field:?}: {message}"),
+            Self::InvalidUri { uri, message, did_you_mean } => write!(f, "Invalid URI {uri:?}: {message}{}", hint(did_you_mean)),
+            Self::Unwritable { path, message } => write!(f, "Cannot write to {path:?}: {message}")
+        }
+    }
+}
+
+impl From<FactoryError> for String {
+    fn from(err: FactoryError) -> Self {
+        err.to_string()
+    }
+}
 
+/// Number of single-character edits turning `a` into `b`
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut row = (0..=b.len()).collect::<Vec<_>>();
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substituted = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substituted.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+    row[b.len()]
+}
+
+/// The candidate `word` is most likely a typo of, if any is close enough
+/// ```
+/// use cli_music_player::common::factory::did_you_mean;
+///
+/// let fields = ["uri", "local_path", "filename_template"];
+/// assert_eq!(Some("uri"), did_you_mean("url", fields));
+/// assert_eq!(Some("local_path"), did_you_mean("localpath", fields));
+/// assert_eq!(Some("filename_template"), did_you_mean("Filename_Template", fields));
+/// assert_eq!(None, did_you_mean("genre", fields));
+/// ```
+pub fn did_you_mean<'a, I: IntoIterator<Item = &'a str>>(word: &str, candidates: I) -> Option<&'a str> {
+    let word = word.to_lowercase();
+    // allow about one typo per three characters
+    let allowed = (word.chars().count() / 3).max(1);
+    candidates.into_iter()
+        .map(|candidate| (edit_distance(&word, &candidate.to_lowercase()), candidate))
+        .filter(|(distance, _)| *distance <= allowed)
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, candidate)| candidate)
+}
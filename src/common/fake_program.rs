@@ -0,0 +1,16 @@
+//! Stand-ins for the external programs we drive (yt-dlp, ffmpeg, ...), so
+//! tests do not depend on what the machine has installed.
+
+use std::path::{Path, PathBuf};
+
+/// Writes an executable `sh` script named `name` into `dir`
+pub fn fake_program<P: AsRef<Path>>(dir: P, name: &str, body: &str) -> PathBuf {
+    let path = dir.as_ref().join(name);
+    std::fs::write(&path, format!("#!/bin/sh\n{body}\n")).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+    path
+}
@@ -0,0 +1,220 @@
+//! Minimal RSS 2.0 and Atom parsing, covering the bits podcast and video
+//! feeds actually use (enclosures, iTunes durations, media thumbnails).
+
+use std::{path::Path, time::Duration};
+
+use chrono::{DateTime, Utc};
+use reqwest::blocking::Client;
+use roxmltree::{Document, Node};
+use serde::{Deserialize, Serialize};
+
+use super::metadata::parse_clock_duration;
+
+const ATOM_NS: &str = "http://www.w3.org/2005/Atom";
+const ITUNES_NS: &str = "http://www.itunes.com/dtds/podcast-1.0.dtd";
+const MEDIA_NS: &str = "http://search.yahoo.com/mrss/";
+
+/// The media file attached to a feed entry
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Enclosure {
+    pub url: String,
+    /// Size in bytes as advertised by the feed; often 0 or wrong
+    pub length: Option<u64>,
+    pub mime_type: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FeedEntry {
+    /// RSS `guid` or Atom `id`; falls back to the link
+    pub id: String,
+    pub title: String,
+    pub link: Option<String>,
+    pub published: Option<DateTime<Utc>>,
+    pub enclosure: Option<Enclosure>,
+    pub duration: Option<Duration>,
+    pub author: Option<String>,
+    pub thumbnail_url: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Feed {
+    pub title: String,
+    pub link: Option<String>,
+    pub author: Option<String>,
+    pub entries: Vec<FeedEntry>
+}
+
+fn child<'a, 'input>(node: Node<'a, 'input>, name: &str, ns: Option<&str>) -> Option<Node<'a, 'input>> {
+    node.children().find(|n| n.is_element() && n.tag_name().name() == name
+        && ns.is_none_or(|ns| n.tag_name().namespace() == Some(ns)))
+}
+
+fn child_text(node: Node, name: &str, ns: Option<&str>) -> Option<String> {
+    child(node, name, ns)
+        .and_then(|n| n.text())
+        .map(|text| text.trim().to_string())
+        .filter(|text| !text.is_empty())
+}
+
+fn parse_date(date: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc2822(date)
+        .or_else(|_| DateTime::parse_from_rfc3339(date))
+        .map(|date| date.with_timezone(&Utc))
+        .ok()
+}
+
+fn media_thumbnail(node: Node) -> Option<String> {
+    node.descendants()
+        .find(|n| n.tag_name().name() == "thumbnail" && n.tag_name().namespace() == Some(MEDIA_NS))
+        .and_then(|n| n.attribute("url"))
+        .map(str::to_string)
+}
+
+impl Feed {
+    /// Parses either an RSS 2.0 or an Atom document
+    pub fn parse<AnyStr: AsRef<str>>(xml: AnyStr) -> Result<Feed, String> {
+        let doc = Document::parse(xml.as_ref()).map_err(|err| format!("Malformed feed: {err}"))?;
+        let root = doc.root_element();
+        match (root.tag_name().name(), root.tag_name().namespace()) {
+            ("rss", _) => child(root, "channel", None)
+                .ok_or_else(|| "RSS feed has no <channel>".to_string())
+                .map(Self::parse_rss),
+            ("feed", Some(ATOM_NS)) => Ok(Self::parse_atom(root)),
+            (name, _) => Err(format!("Unknown feed format with root <{name}>"))
+        }
+    }
+
+    /// Reads a feed from an http(s) URL, fetched with `client`, a `file://`
+    /// URL or a local path
+    pub fn fetch<AnyStr: AsRef<str>>(client: &Client, location: AnyStr) -> Result<Feed, String> {
+        let location = location.as_ref();
+        let xml = if location.starts_with("http://") || location.starts_with("https://") {
+            client.get(location).send()
+                .and_then(|resp| resp.error_for_status())
+                .and_then(|resp| resp.text())
+                .map_err(|err| format!("Cannot fetch feed {location}: {err}"))?
+        } else {
+            let path = Path::new(location.strip_prefix("file://").unwrap_or(location));
+            std::fs::read_to_string(path).map_err(|err| format!("Cannot read feed {path:?}: {err}"))?
+        };
+        Self::parse(xml)
+    }
+
+    fn parse_rss(channel: Node) -> Feed {
+        let entries = channel.children()
+            .filter(|n| n.tag_name().name() == "item")
+            .map(|item| {
+                let link = child_text(item, "link", None);
+                let enclosure = child(item, "enclosure", None)
+                    .and_then(|enc| enc.attribute("url").map(|url| Enclosure {
+                        url: url.to_string(),
+                        length: enc.attribute("length").and_then(|len| len.parse().ok()).filter(|len| *len > 0),
+                        mime_type: enc.attribute("type").map(str::to_string)
+                    }));
+                FeedEntry {
+                    id: child_text(item, "guid", None)
+                        .or_else(|| link.clone())
+                        .or_else(|| enclosure.as_ref().map(|enc| enc.url.clone()))
+                        .unwrap_or_default(),
+                    title: child_text(item, "title", None).unwrap_or_default(),
+                    published: child_text(item, "pubDate", None).as_deref().and_then(parse_date),
+                    duration: child_text(item, "duration", Some(ITUNES_NS)).and_then(parse_clock_duration),
+                    author: child_text(item, "author", Some(ITUNES_NS))
+                        .or_else(|| child_text(item, "author", None)),
+                    thumbnail_url: child(item, "image", Some(ITUNES_NS))
+                        .and_then(|img| img.attribute("href").map(str::to_string))
+                        .or_else(|| media_thumbnail(item)),
+                    link,
+                    enclosure
+                }
+            })
+            .collect();
+        Feed {
+            title: child_text(channel, "title", None).unwrap_or_default(),
+            link: child_text(channel, "link", None),
+            author: child_text(channel, "author", Some(ITUNES_NS)),
+            entries
+        }
+    }
+
+    fn atom_link(node: Node, rel: &str) -> Option<String> {
+        node.children()
+            .filter(|n| n.tag_name().name() == "link")
+            .find(|n| n.attribute("rel").unwrap_or("alternate") == rel)
+            .and_then(|n| n.attribute("href"))
+            .map(str::to_string)
+    }
+
+    fn parse_atom(feed: Node) -> Feed {
+        let author_of = |node: Node| child(node, "author", Some(ATOM_NS))
+            .and_then(|author| child_text(author, "name", Some(ATOM_NS)));
+        let entries = feed.children()
+            .filter(|n| n.tag_name().name() == "entry")
+            .map(|entry| {
+                let link = Self::atom_link(entry, "alternate");
+                FeedEntry {
+                    id: child_text(entry, "id", Some(ATOM_NS)).or_else(|| link.clone()).unwrap_or_default(),
+                    title: child_text(entry, "title", Some(ATOM_NS)).unwrap_or_default(),
+                    published: child_text(entry, "published", Some(ATOM_NS))
+                        .or_else(|| child_text(entry, "updated", Some(ATOM_NS)))
+                        .as_deref().and_then(parse_date),
+                    enclosure: entry.children()
+                        .filter(|n| n.tag_name().name() == "link" && n.attribute("rel") == Some("enclosure"))
+                        .find_map(|enc| enc.attribute("href").map(|url| Enclosure {
+                            url: url.to_string(),
+                            length: enc.attribute("length").and_then(|len| len.parse().ok()).filter(|len| *len > 0),
+                            mime_type: enc.attribute("type").map(str::to_string)
+                        })),
+                    duration: child_text(entry, "duration", Some(ITUNES_NS)).and_then(parse_clock_duration),
+                    author: author_of(entry),
+                    thumbnail_url: media_thumbnail(entry),
+                    link
+                }
+            })
+            .collect();
+        Feed {
+            title: child_text(feed, "title", Some(ATOM_NS)).unwrap_or_default(),
+            link: Self::atom_link(feed, "alternate"),
+            author: author_of(feed),
+            entries
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parses_atom_with_enclosure() {
+        let feed = Feed::parse(r#"<?xml version="1.0" encoding="utf-8"?>
+            <feed xmlns="http://www.w3.org/2005/Atom">
+              <title>Atom Cast</title>
+              <author><name>Someone</name></author>
+              <entry>
+                <id>urn:uuid:1</id>
+                <title>First</title>
+                <updated>2022-07-01T10:00:00+02:00</updated>
+                <link href="https://example.com/1"/>
+                <link rel="enclosure" type="audio/ogg" length="1000" href="https://example.com/1.ogg"/>
+              </entry>
+            </feed>"#).unwrap();
+        assert_eq!("Atom Cast", feed.title);
+        assert_eq!(Some("Someone".to_string()), feed.author);
+        let entry = &feed.entries[0];
+        assert_eq!("urn:uuid:1", entry.id);
+        assert_eq!(Some("https://example.com/1".to_string()), entry.link);
+        assert_eq!(Some("2022-07-01T08:00:00+00:00".to_string()), entry.published.map(|d| d.to_rfc3339()));
+        assert_eq!(Some(Enclosure {
+            url: "https://example.com/1.ogg".to_string(),
+            length: Some(1000),
+            mime_type: Some("audio/ogg".to_string())
+        }), entry.enclosure);
+    }
+
+    #[test]
+    fn rejects_unknown_root() {
+        assert!(Feed::parse("<html></html>").is_err());
+        assert!(Feed::parse("not xml").is_err());
+    }
+}
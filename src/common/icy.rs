@@ -0,0 +1,200 @@
+//! Icecast/Shoutcast in-band ("ICY") metadata.
+//!
+//! When a client asks with `Icy-MetaData: 1`, the server answers with an
+//! `icy-metaint: N` header and interleaves a metadata block after every N
+//! bytes of audio. A block is one length byte (times 16) followed by text
+//! like `StreamTitle='Artist - Song';`.
+
+use std::{collections::HashMap, io::{ErrorKind, Read}};
+
+use reqwest::blocking::Client;
+
+/// The key/value pairs of one metadata block
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IcyMetadata {
+    pub fields: HashMap<String, String>
+}
+
+impl IcyMetadata {
+    /// Parses the text of a metadata block. Values are single-quoted and may
+    /// themselves contain quotes, so a field only ends at `';`.
+    /// ```
+    /// use cli_music_player::common::icy::IcyMetadata;
+    ///
+    /// let meta = IcyMetadata::parse("StreamTitle='Guns N' Roses - Patience';StreamUrl='';");
+    /// assert_eq!(Some("Guns N' Roses - Patience"), meta.stream_title());
+    /// assert_eq!(None, IcyMetadata::parse("StreamTitle='';").stream_title());
+    /// ```
+    pub fn parse<AnyStr: AsRef<str>>(text: AnyStr) -> Self {
+        let mut fields = HashMap::new();
+        let mut rest = text.as_ref().trim_end_matches('\0');
+        while let Some(eq) = rest.find("='") {
+            let key = rest[..eq].trim().to_string();
+            let value_start = eq + 2;
+            let value_end = rest[value_start..].find("';")
+                .map(|idx| value_start + idx)
+                .unwrap_or_else(|| rest.trim_end_matches(['\'', ';']).len().max(value_start));
+            fields.insert(key, rest[value_start..value_end].to_string());
+            rest = rest.get(value_end + 2..).unwrap_or_default();
+        }
+        Self { fields }
+    }
+
+    /// The currently playing song, usually "Artist - Title"
+    pub fn stream_title(&self) -> Option<&str> {
+        self.fields.get("StreamTitle").map(String::as_str).filter(|title| !title.trim().is_empty())
+    }
+}
+
+/// Wraps an ICY stream, yielding only audio bytes while keeping track of
+/// the latest metadata block. Hand this to the decoder and poll
+/// [IcyReader::stream_title] to show what is playing.
+pub struct IcyReader<R: Read> {
+    inner: R,
+    metaint: usize,
+    until_meta: usize,
+    metadata: Option<IcyMetadata>
+}
+
+impl<R: Read> IcyReader<R> {
+    /// `metaint` is the value of the `icy-metaint` response header
+    pub fn new(inner: R, metaint: usize) -> Self {
+        Self { inner, metaint, until_meta: metaint, metadata: None }
+    }
+
+    pub fn metadata(&self) -> Option<&IcyMetadata> {
+        self.metadata.as_ref()
+    }
+
+    pub fn stream_title(&self) -> Option<&str> {
+        self.metadata.as_ref().and_then(IcyMetadata::stream_title)
+    }
+
+    /// Reads the next metadata block. Returns false if the stream ended
+    /// right before it, which is where a stream may end cleanly.
+    fn read_metadata_block(&mut self) -> std::io::Result<bool> {
+        let mut len = [0u8; 1];
+        match self.inner.read_exact(&mut len) {
+            Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(false),
+            result => result?
+        }
+        if len[0] > 0 {
+            let mut block = vec![0u8; len[0] as usize * 16];
+            self.inner.read_exact(&mut block)?;
+            self.metadata = Some(IcyMetadata::parse(String::from_utf8_lossy(&block)));
+        }
+        self.until_meta = self.metaint;
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for IcyReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.metaint == 0 {
+            return self.inner.read(buf);
+        }
+        if self.until_meta == 0 && !self.read_metadata_block()? {
+            return Ok(0);
+        }
+        let max = buf.len().min(self.until_meta);
+        let read = self.inner.read(&mut buf[..max])?;
+        self.until_meta -= read;
+        Ok(read)
+    }
+}
+
+/// Connects to a station with `client` and returns the title of what it is
+/// playing right now, or None if the station does not send ICY metadata.
+///
+/// Note: only HTTP/1.x servers are supported; legacy Shoutcast v1 servers
+/// answering `ICY 200 OK` are rejected by the HTTP client.
+pub fn now_playing<AnyStr: AsRef<str>>(client: &Client, stream_url: AnyStr) -> Result<Option<String>, String> {
+    let url = stream_url.as_ref();
+    let resp = client
+        .get(url)
+        .header("Icy-MetaData", "1")
+        .send()
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| format!("Cannot connect to {url}: {err}"))?;
+    let metaint = resp.headers().get("icy-metaint")
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<usize>().ok());
+    let metaint = match metaint {
+        Some(metaint) if metaint > 0 => metaint,
+        _ => return Ok(None)
+    };
+    let mut reader = IcyReader::new(resp, metaint);
+    // read through the first audio chunk to reach the first metadata block
+    let found = std::io::copy(&mut (&mut reader).take(metaint as u64), &mut std::io::sink())
+        .and_then(|_| reader.read_metadata_block())
+        .map_err(|err| format!("Stream {url} ended before its metadata: {err}"))?;
+    if !found {
+        return Err(format!("Stream {url} ended before its metadata"));
+    }
+    Ok(reader.stream_title().map(str::to_string))
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::test_server::{TestServer, TestResponse};
+
+    use super::*;
+
+    /// Builds `chunks` of audio, each followed by a metadata block
+    fn icy_stream(metaint: usize, titles: &[&str]) -> Vec<u8> {
+        let mut stream = vec![];
+        for (idx, title) in titles.iter().enumerate() {
+            stream.extend(std::iter::repeat_n(idx as u8, metaint));
+            let mut block = format!("StreamTitle='{title}';").into_bytes();
+            block.resize(block.len().div_ceil(16) * 16, 0);
+            stream.push((block.len() / 16) as u8);
+            stream.extend(block);
+        }
+        stream
+    }
+
+    #[test]
+    fn reader_strips_metadata() {
+        let stream = icy_stream(8, &["First - Song", "Second - Song"]);
+        let mut reader = IcyReader::new(stream.as_slice(), 8);
+        let mut audio = vec![0u8; 8];
+        reader.read_exact(&mut audio).unwrap();
+        assert_eq!(vec![0u8; 8], audio);
+        assert_eq!(None, reader.stream_title());
+
+        reader.read_exact(&mut audio).unwrap();
+        assert_eq!(vec![1u8; 8], audio);
+        assert_eq!(Some("First - Song"), reader.stream_title());
+
+        let mut rest = vec![];
+        reader.read_to_end(&mut rest).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(Some("Second - Song"), reader.stream_title());
+    }
+
+    #[test]
+    fn stream_may_end_before_a_metadata_block() {
+        let mut stream = icy_stream(8, &["First - Song"]);
+        stream.extend([7u8; 8]);
+        let mut reader = IcyReader::new(stream.as_slice(), 8);
+        let mut audio = vec![];
+        reader.read_to_end(&mut audio).unwrap();
+        assert_eq!([vec![0u8; 8], vec![7u8; 8]].concat(), audio);
+        assert_eq!(Some("First - Song"), reader.stream_title());
+        assert_eq!(0, reader.read(&mut [0u8; 4]).unwrap());
+
+        // a block cut short is still an error
+        let mut stream = icy_stream(8, &["First - Song"]);
+        stream.truncate(10);
+        assert!(IcyReader::new(stream.as_slice(), 8).read_to_end(&mut vec![]).is_err());
+    }
+
+    #[test]
+    fn now_playing_from_server() {
+        let server = TestServer::serve(|req| match req.headers.get("icy-metadata").map(String::as_str) {
+            Some("1") => TestResponse::ok(icy_stream(32, &["Live - On Air"])).header("icy-metaint", "32"),
+            _ => TestResponse::ok(vec![0u8; 64])
+        });
+        assert_eq!(Ok(Some("Live - On Air".to_string())), now_playing(&Client::new(), server.url("/stream")));
+    }
+}
@@ -0,0 +1,332 @@
+//! Installs the external programs we depend on into
+//! [SetupConfig::exec_path], for machines where we cannot (or may not)
+//! use a system package manager.
+
+use std::{path::{Path, PathBuf}, process::Command, time::Duration};
+
+use reqwest::blocking::{Client, Response};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use super::self_setup::SetupConfig;
+
+/// Release artifacts run to tens of megabytes, which slow links take a
+/// while over
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(30 * 60);
+
+/// A specific release of a program, and how to check we got the right bytes
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PinnedRelease {
+    /// File name of the installed program, e.g. "yt-dlp"
+    pub name: String,
+    /// Expected to appear in the output of `<name> <version_args>`
+    pub version: String,
+    pub version_args: Vec<String>,
+    /// Where the release artifact is downloaded from
+    pub url: String,
+    /// Expected SHA-256 (hex) of the artifact. When None, the checksum is
+    /// looked up in [PinnedRelease::checksums_url].
+    #[serde(default)]
+    pub sha256: Option<String>,
+    /// A `sha256sum`-style listing published alongside the release
+    /// (URL, `file://` URL or local path)
+    #[serde(default)]
+    pub checksums_url: Option<String>,
+    /// If the artifact is a tarball, the path of the program inside it
+    #[serde(default)]
+    pub archive_member: Option<String>,
+    /// Install from this local copy of the artifact instead of downloading,
+    /// for machines without internet access. The checksum still applies.
+    #[serde(default)]
+    pub local_archive: Option<PathBuf>
+}
+
+impl PinnedRelease {
+    /// yt-dlp as a Python zipapp, so it needs `python3` to run
+    pub fn yt_dlp() -> Self {
+        let base = "https://github.com/yt-dlp/yt-dlp/releases/download/2022.07.18";
+        Self {
+            name: "yt-dlp".to_string(),
+            version: "2022.07.18".to_string(),
+            version_args: vec!["--version".to_string()],
+            url: format!("{base}/yt-dlp"),
+            sha256: None,
+            checksums_url: Some(format!("{base}/SHA2-256SUMS")),
+            archive_member: None,
+            local_archive: None
+        }
+    }
+    /// The static linux64 build of ffmpeg 5.1 yt-dlp maintains, from a
+    /// dated (so unchanging) autobuild
+    pub fn ffmpeg() -> Self {
+        let base = "https://github.com/yt-dlp/FFmpeg-Builds/releases/download/autobuild-2022-08-31-12-52";
+        Self {
+            name: "ffmpeg".to_string(),
+            version: "ffmpeg version n5.1".to_string(),
+            version_args: vec!["-version".to_string()],
+            url: format!("{base}/ffmpeg-n5.1-latest-linux64-gpl-5.1.tar.xz"),
+            sha256: None,
+            checksums_url: Some(format!("{base}/checksums.sha256")),
+            archive_member: Some("ffmpeg-n5.1-latest-linux64-gpl-5.1/bin/ffmpeg".to_string()),
+            local_archive: None
+        }
+    }
+
+    fn artifact_name(&self) -> &str {
+        self.url.rsplit('/').next().unwrap_or(&self.url)
+    }
+}
+
+fn is_http(location: &str) -> bool {
+    location.starts_with("http://") || location.starts_with("https://")
+}
+
+fn get(client: &Client, location: &str) -> Result<Response, String> {
+    client.get(location).timeout(DOWNLOAD_TIMEOUT).send()
+        .and_then(|resp| resp.error_for_status())
+        .map_err(|err| format!("Cannot fetch {location}: {err}"))
+}
+
+/// Reads an http(s) URL, a `file://` URL or a local path
+fn read_location(client: &Client, location: &str) -> Result<Vec<u8>, String> {
+    if is_http(location) {
+        get(client, location)?.bytes().map(|bytes| bytes.to_vec()).map_err(|err| format!("Cannot fetch {location}: {err}"))
+    } else {
+        let path = location.strip_prefix("file://").unwrap_or(location);
+        std::fs::read(path).map_err(|err| format!("Cannot read {path:?}: {err}"))
+    }
+}
+
+/// Streams an http(s) URL into `target`, without holding it in memory
+fn download_to(client: &Client, location: &str, target: &Path) -> Result<(), String> {
+    let mut resp = get(client, location)?;
+    let mut file = std::fs::File::create(target).map_err(|err| format!("Cannot create {target:?}: {err}"))?;
+    std::io::copy(&mut resp, &mut file).map(|_| ()).map_err(|err| format!("Cannot fetch {location}: {err}"))
+}
+
+pub fn sha256_hex(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+fn sha256_file(path: &Path) -> Result<String, String> {
+    let mut file = std::fs::File::open(path).map_err(|err| format!("Cannot read {path:?}: {err}"))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).map_err(|err| format!("Cannot read {path:?}: {err}"))?;
+    Ok(hasher.finalize().iter().map(|b| format!("{b:02x}")).collect())
+}
+
+/// Runs `program <version_args>` and returns its first line of output
+/// if it contains the expected version.
+pub fn detect_version(program: &Path, release: &PinnedRelease) -> Result<String, String> {
+    let output = Command::new(program).args(&release.version_args).output()
+        .map_err(|err| format!("Cannot run {program:?}: {err}"))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let first_line = stdout.lines().next().unwrap_or_default().trim().to_string();
+    if !output.status.success() {
+        return Err(format!("{program:?} {:?} exited with {}", release.version_args, output.status));
+    }
+    stdout.contains(&release.version).then_some(first_line)
+        .ok_or_else(|| format!("{program:?} reports {stdout:?}, expected version {:?}", release.version))
+}
+
+/// Where `release` gets installed
+pub fn installed_path(setup: &SetupConfig, release: &PinnedRelease) -> PathBuf {
+    setup.exec_path.join(&release.name)
+}
+
+fn expected_sha256(client: &Client, release: &PinnedRelease) -> Result<String, String> {
+    if let Some(sha256) = &release.sha256 {
+        return Ok(sha256.to_lowercase());
+    }
+    let checksums_url = release.checksums_url.as_ref()
+        .ok_or_else(|| format!("Release of {} pins neither sha256 nor checksums_url", release.name))?;
+    let listing = String::from_utf8_lossy(&read_location(client, checksums_url)?).to_string();
+    // lines look like "<hex>  <file>" or "<hex> *<file>"
+    listing.lines()
+        .filter_map(|line| line.split_once(char::is_whitespace))
+        .find(|(_, file)| file.trim().trim_start_matches('*') == release.artifact_name())
+        .map(|(hex, _)| hex.to_lowercase())
+        .ok_or_else(|| format!("{checksums_url} has no checksum for {}", release.artifact_name()))
+}
+
+/// Fetches (or reads locally) the release with `client`, verifies its
+/// checksum, puts the program into [SetupConfig::exec_path] and verifies
+/// its version. Returns the path of the installed program.
+pub fn install(client: &Client, setup: &SetupConfig, release: &PinnedRelease) -> Result<PathBuf, String> {
+    setup.create_dirs()?;
+    let target = installed_path(setup, release);
+    let staging = setup.exec_path.join(format!(".install-{}", release.name));
+    std::fs::create_dir_all(&staging).map_err(|err| format!("Cannot create {staging:?}: {err}"))?;
+    let result = fetch_artifact(client, &staging, release)
+        .and_then(|artifact| stage(&staging, &artifact, release))
+        .and_then(|program| std::fs::rename(&program, &target)
+            .map_err(|err| format!("Cannot move {program:?} to {target:?}: {err}")));
+    let _ = std::fs::remove_dir_all(&staging);
+    result?;
+
+    detect_version(&target, release).map_err(|err| {
+        let _ = std::fs::remove_file(&target);
+        format!("Installed {} failed verification: {err}", release.name)
+    })?;
+    log::info!("Installed {} {} into {target:?}", release.name, release.version);
+    Ok(target)
+}
+
+/// Downloads the release into `staging` (or takes the local copy) and
+/// verifies its checksum. Returns the path of the artifact.
+fn fetch_artifact(client: &Client, staging: &Path, release: &PinnedRelease) -> Result<PathBuf, String> {
+    let artifact = match &release.local_archive {
+        Some(local) => local.clone(),
+        None if is_http(&release.url) => {
+            let artifact = staging.join(format!("{}.download", release.artifact_name()));
+            download_to(client, &release.url, &artifact)?;
+            artifact
+        },
+        None => PathBuf::from(release.url.strip_prefix("file://").unwrap_or(&release.url))
+    };
+    let expected = expected_sha256(client, release)?;
+    let actual = sha256_file(&artifact)?;
+    if actual != expected {
+        return Err(format!("Checksum mismatch for {}: expected {expected}, got {actual}", release.artifact_name()));
+    }
+    Ok(artifact)
+}
+
+/// Puts the program into `staging`, extracting it if needed
+fn stage(staging: &Path, artifact: &Path, release: &PinnedRelease) -> Result<PathBuf, String> {
+    let program = match &release.archive_member {
+        None => {
+            let program = staging.join(&release.name);
+            std::fs::copy(artifact, &program).map_err(|err| format!("Cannot copy {artifact:?} to {program:?}: {err}"))?;
+            program
+        },
+        Some(member) => {
+            let status = Command::new("tar").arg("-xf").arg(artifact).arg("-C").arg(staging).arg(member).status()
+                .map_err(|err| format!("Cannot run tar: {err}"))?;
+            if !status.success() {
+                return Err(format!("tar could not extract {member} from {artifact:?}"));
+            }
+            staging.join(member)
+        }
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&program, std::fs::Permissions::from_mode(0o755))
+            .map_err(|err| format!("Cannot make {program:?} executable: {err}"))?;
+    }
+    Ok(program)
+}
+
+/// Returns a working program for `release`: `preferred` if given, then
+/// whatever is on PATH, then our own install, installing it if needed.
+/// Programs the user picked (or found on PATH) may be of any version.
+/// Installs are fetched with `client`, which is where proxy settings go.
+pub fn ensure_installed(client: &Client, setup: &SetupConfig, release: &PinnedRelease, preferred: Option<&Path>) -> Result<PathBuf, String> {
+    let any_version = PinnedRelease { version: String::new(), ..release.clone() };
+    if let Some(program) = preferred {
+        return detect_version(program, &any_version).map(|_| program.to_path_buf());
+    }
+    let installed = installed_path(setup, release);
+    if detect_version(Path::new(&release.name), &any_version).is_ok() {
+        return Ok(PathBuf::from(&release.name));
+    }
+    if detect_version(&installed, release).is_ok() {
+        return Ok(installed);
+    }
+    install(client, setup, release)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{common::{fake_program::fake_program, test_server::{TestResponse, TestServer}}, download_provider::network::{NetworkOptions, USER_AGENT}};
+
+    use super::*;
+
+    fn setup_in(dir: &Path) -> SetupConfig {
+        SetupConfig {
+            include_path: dir.join("include"),
+            lib_path: dir.join("lib"),
+            exec_path: dir.join("bin"),
+            data_path: dir.join("data")
+        }
+    }
+
+    fn fake_release(dir: &Path, version: &str) -> (PinnedRelease, Vec<u8>) {
+        let program = fake_program(dir, "fake-tool", &format!("echo 'fake-tool {version}'"));
+        let bytes = std::fs::read(program).unwrap();
+        (PinnedRelease {
+            name: "fake-tool".to_string(),
+            version: "1.2.3".to_string(),
+            version_args: vec!["--version".to_string()],
+            url: "https://example.invalid/fake-tool".to_string(),
+            sha256: Some(sha256_hex(&bytes)),
+            checksums_url: None,
+            archive_member: None,
+            local_archive: None
+        }, bytes)
+    }
+
+    #[test]
+    fn offline_install_from_tarball() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut release, _) = fake_release(dir.path(), "1.2.3");
+        std::fs::create_dir(dir.path().join("pkg")).unwrap();
+        std::fs::rename(dir.path().join("fake-tool"), dir.path().join("pkg/fake-tool")).unwrap();
+        let tarball = dir.path().join("fake-tool.tar.gz");
+        assert!(Command::new("tar").arg("-czf").arg(&tarball).arg("-C").arg(dir.path()).arg("pkg").status().unwrap().success());
+        let bytes = std::fs::read(&tarball).unwrap();
+        std::fs::write(dir.path().join("SUMS"), format!("{}  fake-tool.tar.gz\n0000  other\n", sha256_hex(&bytes))).unwrap();
+        release.url = "https://example.invalid/fake-tool.tar.gz".to_string();
+        release.sha256 = None;
+        release.checksums_url = Some(dir.path().join("SUMS").to_string_lossy().to_string());
+        release.archive_member = Some("pkg/fake-tool".to_string());
+        release.local_archive = Some(tarball);
+
+        let setup = setup_in(dir.path());
+        let installed = ensure_installed(&Client::new(), &setup, &release, None).unwrap();
+        assert_eq!(setup.exec_path.join("fake-tool"), installed);
+        assert_eq!(Ok("fake-tool 1.2.3".to_string()), detect_version(&installed, &release));
+        assert!(setup.data_path.is_dir());
+        assert!(!setup.exec_path.join(".install-fake-tool").exists());
+    }
+
+    #[test]
+    fn installs_over_http() {
+        let dir = tempfile::tempdir().unwrap();
+        let (mut release, bytes) = fake_release(dir.path(), "1.2.3");
+        let server = TestServer::serve(move |_| TestResponse::ok(bytes.clone()));
+        release.url = server.url("/fake-tool");
+
+        let setup = setup_in(dir.path());
+        let installed = install(&Client::new(), &setup, &release).unwrap();
+        assert_eq!(Ok("fake-tool 1.2.3".to_string()), detect_version(&installed, &release));
+        assert!(!setup.exec_path.join(".install-fake-tool").exists());
+
+        // through a proxy, which gets the whole URL
+        std::fs::remove_file(&installed).unwrap();
+        release.url = "http://releases.example.invalid/fake-tool".to_string();
+        let proxied = NetworkOptions { proxy: Some(server.url("")), ..Default::default() }.client(USER_AGENT).unwrap();
+        assert_eq!(installed, install(&proxied, &setup, &release).unwrap());
+        assert!(install(&Client::new(), &setup, &release).is_err());
+    }
+
+    #[test]
+    fn rejects_bad_checksum_and_version() {
+        let dir = tempfile::tempdir().unwrap();
+        let setup = setup_in(dir.path());
+
+        let (mut release, _) = fake_release(dir.path(), "1.2.3");
+        release.local_archive = Some(dir.path().join("fake-tool"));
+        release.sha256 = Some("00".repeat(32));
+        let err = install(&Client::new(), &setup, &release).unwrap_err();
+        assert!(err.starts_with("Checksum mismatch"), "{err}");
+        assert!(!installed_path(&setup, &release).exists());
+
+        let (mut release, _) = fake_release(dir.path(), "0.9.0");
+        release.local_archive = Some(dir.path().join("fake-tool"));
+        let err = install(&Client::new(), &setup, &release).unwrap_err();
+        assert!(err.contains("failed verification"), "{err}");
+        assert!(!installed_path(&setup, &release).exists());
+    }
+}
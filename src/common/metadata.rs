@@ -0,0 +1,69 @@
+//! Descriptive information about a single piece of media, shared between
+//! search results and downloads.
+
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+/// What we know about a track, either from a search provider or from the
+/// downloader itself. Every field is optional since sources vary wildly
+/// in what they expose.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct TrackMetadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub album_artist: Option<String>,
+    /// 1-based position of the track within [TrackMetadata::album]
+    pub track_number: Option<u32>,
+    /// Number of tracks on [TrackMetadata::album]
+    pub track_total: Option<u32>,
+    pub year: Option<i32>,
+    pub duration: Option<Duration>,
+    /// Where this track was found, usually a watch URL
+    pub source_url: Option<String>,
+    pub thumbnail_url: Option<String>,
+}
+
+impl TrackMetadata {
+    /// Fills the fields we do not know from `other`
+    /// ```
+    /// use cli_music_player::common::metadata::TrackMetadata;
+    ///
+    /// let searched = TrackMetadata { title: Some("Money".to_string()), ..Default::default() };
+    /// let downloaded = TrackMetadata { title: Some("Money (Remastered)".to_string()), year: Some(1973), ..Default::default() };
+    /// let merged = searched.or(downloaded);
+    /// assert_eq!((Some("Money".to_string()), Some(1973)), (merged.title, merged.year));
+    /// ```
+    pub fn or(self, other: TrackMetadata) -> TrackMetadata {
+        TrackMetadata {
+            title: self.title.or(other.title),
+            artist: self.artist.or(other.artist),
+            album: self.album.or(other.album),
+            album_artist: self.album_artist.or(other.album_artist),
+            track_number: self.track_number.or(other.track_number),
+            track_total: self.track_total.or(other.track_total),
+            year: self.year.or(other.year),
+            duration: self.duration.or(other.duration),
+            source_url: self.source_url.or(other.source_url),
+            thumbnail_url: self.thumbnail_url.or(other.thumbnail_url)
+        }
+    }
+}
+
+/// Parses a clock-like duration such as "3:45" or "1:02:03".
+/// ```
+/// use std::time::Duration;
+/// use cli_music_player::common::metadata::parse_clock_duration;
+///
+/// assert_eq!(Some(Duration::from_secs(225)), parse_clock_duration("3:45"));
+/// assert_eq!(Some(Duration::from_secs(3723)), parse_clock_duration("1:02:03"));
+/// assert_eq!(Some(Duration::from_secs(42)), parse_clock_duration("42"));
+/// assert_eq!(None, parse_clock_duration("3:4a"));
+/// ```
+pub fn parse_clock_duration<AnyStr: AsRef<str>>(clock: AnyStr) -> Option<Duration> {
+    clock.as_ref().trim().split(':')
+        .try_fold(0u64, |acc, part| part.parse::<u64>().ok().map(|v| acc * 60 + v))
+        .map(Duration::from_secs)
+}
@@ -1,3 +1,11 @@
 pub mod self_setup;
 pub mod factory;
-pub mod config;
\ No newline at end of file
+pub mod config;
+pub mod metadata;
+pub mod feed;
+pub mod icy;
+pub mod install;
+#[cfg(test)]
+pub(crate) mod test_server;
+#[cfg(test)]
+pub(crate) mod fake_program;
@@ -3,15 +3,42 @@ use std::path::PathBuf;
 use enum_dispatch::enum_dispatch;
 use serde::{Deserialize, Serialize};
 
-#[derive(Deserialize, Serialize)]
+use super::config::project_dirs;
+
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
 pub struct SetupConfig {
     pub include_path: PathBuf,
     pub lib_path: PathBuf,
+    /// Where self-installed programs (yt-dlp, ffmpeg) are put
     pub exec_path: PathBuf,
     pub data_path: PathBuf
 }
 
+impl Default for SetupConfig {
+    /// Everything lives under the project's data dir
+    fn default() -> Self {
+        let root = project_dirs().data_dir().to_path_buf();
+        Self {
+            include_path: root.join("include"),
+            lib_path: root.join("lib"),
+            exec_path: root.join("bin"),
+            data_path: root.join("data")
+        }
+    }
+}
+
+impl SetupConfig {
+    /// Creates every configured directory that does not exist yet
+    pub fn create_dirs(&self) -> Result<(), String> {
+        [&self.include_path, &self.lib_path, &self.exec_path, &self.data_path].into_iter()
+            .filter(|dir| !dir.exists())
+            .try_for_each(|dir| std::fs::create_dir_all(dir)
+                .map_err(|err| format!("Cannot std::fs::create_dir_all({dir:?}): {err:?}")))
+    }
+}
+
 #[enum_dispatch]
 pub trait SelfSetup {
     fn setup(&self) -> Result<(), String>;
-}
\ No newline at end of file
+}
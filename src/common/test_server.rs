@@ -0,0 +1,118 @@
+//! A tiny HTTP/1.1 server for tests, so providers can be exercised against
+//! recorded responses instead of the real services.
+#![allow(dead_code)] // not every test needs every knob
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Read, Write},
+    net::{TcpListener, TcpStream},
+    sync::Arc,
+};
+
+#[derive(Debug, Clone)]
+pub struct TestRequest {
+    pub method: String,
+    /// Path including the query string
+    pub path: String,
+    /// Header names are lower-cased
+    pub headers: HashMap<String, String>,
+    pub body: Vec<u8>,
+}
+
+#[derive(Debug, Clone)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    pub fn ok<B: Into<Vec<u8>>>(body: B) -> Self {
+        Self { status: 200, headers: vec![], body: body.into() }
+    }
+    pub fn not_found() -> Self {
+        Self { status: 404, headers: vec![], body: b"not found".to_vec() }
+    }
+    pub fn header<K: AsRef<str>, V: AsRef<str>>(mut self, key: K, value: V) -> Self {
+        self.headers.push((key.as_ref().to_string(), value.as_ref().to_string()));
+        self
+    }
+}
+
+pub struct TestServer {
+    port: u16,
+}
+
+impl TestServer {
+    /// Spawns a server on an ephemeral local port. The accept loop lives
+    /// for as long as the test process does.
+    pub fn serve<F>(handler: F) -> Self
+        where F: Fn(&TestRequest) -> TestResponse + Send + Sync + 'static
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("bind test server");
+        let port = listener.local_addr().unwrap().port();
+        let handler = Arc::new(handler);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = handler.clone();
+                std::thread::spawn(move || {
+                    if let Err(err) = Self::handle(stream, handler.as_ref()) {
+                        log::warn!("test server: {err}");
+                    }
+                });
+            }
+        });
+        Self { port }
+    }
+
+    /// Serves fixed bodies keyed by path (query string excluded)
+    pub fn routes(routes: Vec<(&str, Vec<u8>)>) -> Self {
+        let routes: HashMap<String, Vec<u8>> = routes.into_iter()
+            .map(|(path, body)| (path.to_string(), body))
+            .collect();
+        Self::serve(move |req| {
+            let path = req.path.split('?').next().unwrap_or_default();
+            routes.get(path).map(|body| TestResponse::ok(body.clone()))
+                .unwrap_or_else(TestResponse::not_found)
+        })
+    }
+
+    pub fn url<AnyStr: AsRef<str>>(&self, path: AnyStr) -> String {
+        format!("http://127.0.0.1:{}{}", self.port, path.as_ref())
+    }
+
+    fn handle<F: Fn(&TestRequest) -> TestResponse>(stream: TcpStream, handler: &F) -> std::io::Result<()> {
+        let mut reader = BufReader::new(stream.try_clone()?);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line)?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_string();
+        let path = parts.next().unwrap_or_default().to_string();
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line)? == 0 || line.trim().is_empty() {
+                break;
+            }
+            if let Some((key, value)) = line.split_once(':') {
+                headers.insert(key.trim().to_lowercase(), value.trim().to_string());
+            }
+        }
+        let len = headers.get("content-length").and_then(|len| len.parse().ok()).unwrap_or(0);
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+
+        let response = handler(&TestRequest { method, path, headers, body });
+        let mut stream = stream;
+        write!(stream, "HTTP/1.1 {} TEST\r\n", response.status)?;
+        for (key, value) in &response.headers {
+            write!(stream, "{key}: {value}\r\n")?;
+        }
+        if !response.headers.iter().any(|(key, _)| key.eq_ignore_ascii_case("content-length")) {
+            write!(stream, "Content-Length: {}\r\n", response.body.len())?;
+        }
+        write!(stream, "Connection: close\r\n\r\n")?;
+        stream.write_all(&response.body)?;
+        stream.flush()
+    }
+}
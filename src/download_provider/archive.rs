@@ -0,0 +1,228 @@
+//! Remembers what was downloaded already, so running the same batch again
+//! only fetches what is new. Entries are keyed by provider, media id (a
+//! YouTube video id, a direct URL, ...) and the folder it was downloaded
+//! into, so the same media may well live in several libraries.
+
+use std::{path::{Path, PathBuf}, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{config::{load_json, project_dirs, save_json}, install::sha256_hex};
+
+use super::{interface::Downloaded, progress::{DownloadPhase, DownloadProgress, ProgressCallback}};
+
+lazy_static! {
+    /// Every archive update is a read-modify-write of the whole file, and
+    /// download workers run in parallel
+    static ref ARCHIVE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchivedFile {
+    pub path: PathBuf,
+    /// SHA-256 (hex) of the file as it was after downloading
+    pub sha256: String
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveEntry {
+    pub provider: String,
+    pub media_id: String,
+    /// The [DownloadConfig::local_path](super::DownloadConfig::local_path)
+    /// downloaded into. None for entries recorded before we kept track,
+    /// which count for every folder.
+    #[serde(default)]
+    pub local_path: Option<PathBuf>,
+    /// What the download produced, see [Downloaded::files]
+    pub files: Vec<ArchivedFile>,
+    pub downloaded_at: DateTime<Utc>
+}
+
+impl ArchiveEntry {
+    fn is_for(&self, provider: &str, media_id: &str, local_path: &Path) -> bool {
+        self.provider == provider && self.media_id == media_id
+            && self.local_path.as_deref().is_none_or(|path| path == local_path)
+    }
+
+    /// The archived files, as a download the archive skipped
+    pub fn downloaded(&self) -> Downloaded {
+        Downloaded { files: self.files.iter().map(|file| file.path.clone()).collect(), skipped: true }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+struct ArchiveFile {
+    entries: Vec<ArchiveEntry>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct DownloadArchive {
+    /// Whether downloads are looked up and recorded at all
+    ///
+    /// Default: true
+    pub enabled: bool,
+    /// JSON file holding the entries
+    ///
+    /// Default: `<data dir>/download_archive.json`
+    pub path: PathBuf
+}
+
+impl Default for DownloadArchive {
+    fn default() -> Self {
+        Self { enabled: true, path: project_dirs().data_dir().join("download_archive.json") }
+    }
+}
+
+impl DownloadArchive {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self { enabled: true, path: path.as_ref().to_path_buf() }
+    }
+
+    fn load(&self) -> Result<ArchiveFile, String> {
+        load_json(&self.path, "download archive")
+    }
+
+    fn save(&self, archive: &ArchiveFile) -> Result<(), String> {
+        save_json(&self.path, "download archive", archive)
+    }
+
+    pub fn entries(&self) -> Result<Vec<ArchiveEntry>, String> {
+        let _guard = ARCHIVE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        self.load().map(|archive| archive.entries)
+    }
+
+    /// The entry for this media downloaded into `local_path`, if its files
+    /// are all still around
+    pub fn lookup(&self, provider: &str, media_id: &str, local_path: &Path) -> Result<Option<ArchiveEntry>, String> {
+        Ok(self.entries()?.into_iter()
+            .find(|entry| entry.is_for(provider, media_id, local_path))
+            .filter(|entry| !entry.files.is_empty() && entry.files.iter().all(|file| file.path.is_file())))
+    }
+
+    /// Remembers that `media_id` of `provider`, downloaded into
+    /// `local_path`, now lives in `files`, replacing any earlier entry for
+    /// it there
+    pub fn record(&self, provider: &str, media_id: &str, local_path: &Path, files: &[PathBuf]) -> Result<ArchiveEntry, String> {
+        let files = files.iter()
+            .map(|path| Ok(ArchivedFile {
+                path: path.clone(),
+                sha256: sha256_hex(&std::fs::read(path).map_err(|err| format!("Cannot read {path:?}: {err}"))?)
+            }))
+            .collect::<Result<Vec<_>, String>>()?;
+        let entry = ArchiveEntry {
+            provider: provider.to_string(),
+            media_id: media_id.to_string(),
+            local_path: Some(local_path.to_path_buf()),
+            files,
+            downloaded_at: Utc::now()
+        };
+        let _guard = ARCHIVE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut archive = self.load()?;
+        archive.entries.retain(|old| !old.is_for(provider, media_id, local_path));
+        archive.entries.push(entry.clone());
+        self.save(&archive).map(|_| entry)
+    }
+
+    /// Takes the current checksums of `files`, for when they were changed
+    /// on purpose after their download was recorded
+    pub fn refresh(&self, files: &[PathBuf]) -> Result<(), String> {
+        if !self.enabled {
+            return Ok(());
+        }
+        let _guard = ARCHIVE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut archive = self.load()?;
+        for file in archive.entries.iter_mut().flat_map(|entry| entry.files.iter_mut()).filter(|file| files.contains(&file.path)) {
+            let path = &file.path;
+            file.sha256 = sha256_hex(&std::fs::read(path).map_err(|err| format!("Cannot read {path:?}: {err}"))?);
+        }
+        self.save(&archive)
+    }
+
+    /// Entries with a file that is gone or no longer matches its checksum
+    pub fn verify(&self) -> Result<Vec<ArchiveEntry>, String> {
+        Ok(self.entries()?.into_iter()
+            .filter(|entry| entry.files.iter().any(|file| match std::fs::read(&file.path) {
+                Ok(bytes) => sha256_hex(&bytes) != file.sha256,
+                Err(_) => true
+            }))
+            .collect())
+    }
+
+    /// Runs `download` unless the archive already has this media in
+    /// `local_path` (or `force` is set), then records what it produced. A skipped download
+    /// reports a single [DownloadPhase::Finished] and returns the archived
+    /// files as [Downloaded::skipped].
+    pub fn download_once<F>(&self, provider: &str, media_id: &str, local_path: &Path, force: bool, on_progress: ProgressCallback, download: F) -> Result<Downloaded, String>
+        where F: FnOnce(ProgressCallback) -> Result<Downloaded, String>
+    {
+        if !self.enabled {
+            return download(on_progress);
+        }
+        if !force {
+            if let Some(entry) = self.lookup(provider, media_id, local_path)? {
+                let downloaded = entry.downloaded();
+                log::info!("Skipping {provider} {media_id}: already downloaded to {:?}", downloaded.files);
+                let size = downloaded.files.iter()
+                    .filter_map(|path| std::fs::metadata(path).ok())
+                    .map(|meta| meta.len())
+                    .sum();
+                on_progress(DownloadProgress { downloaded_bytes: size, total_bytes: Some(size), ..DownloadProgress::phase(DownloadPhase::Finished) });
+                return Ok(downloaded);
+            }
+        }
+        let downloaded = download(on_progress)?;
+        self.record(provider, media_id, local_path, &downloaded.files)?;
+        Ok(downloaded)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn skips_until_forced_or_gone() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive = DownloadArchive::new(dir.path().join("archive.json"));
+        let mut runs = 0;
+        let download = |force: bool, runs: &mut u32| archive.download_once("http", "https://cdn.example/a.mp3", dir.path(), force, &mut |_| {}, |_| {
+            *runs += 1;
+            let path = dir.path().join("a.mp3");
+            std::fs::write(&path, format!("take {runs}")).map_err(|err| err.to_string())?;
+            Ok(Downloaded::file(path))
+        });
+
+        let downloaded = download(false, &mut runs).unwrap();
+        assert!(!downloaded.skipped);
+        assert_eq!(Downloaded { skipped: true, ..downloaded.clone() }, download(false, &mut runs).unwrap());
+        let path = downloaded.path().unwrap();
+        assert_eq!(1, runs);
+        download(true, &mut runs).unwrap();
+        assert_eq!(2, runs);
+        let entries = archive.entries().unwrap();
+        assert_eq!(1, entries.len());
+        assert_eq!(sha256_hex(b"take 2"), entries[0].files[0].sha256);
+        assert!(archive.verify().unwrap().is_empty());
+
+        std::fs::write(path, "edited").unwrap();
+        assert_eq!(1, archive.verify().unwrap().len());
+        std::fs::remove_file(path).unwrap();
+        download(false, &mut runs).unwrap();
+        assert_eq!(3, runs);
+
+        // another library gets its own copy, and entries from before we kept
+        // track of folders count for all of them
+        let other = dir.path().join("other");
+        assert!(archive.lookup("http", "https://cdn.example/a.mp3", &other).unwrap().is_none());
+        archive.record("http", "https://cdn.example/a.mp3", &other, &[path.to_path_buf()]).unwrap();
+        assert_eq!(2, archive.entries().unwrap().len());
+        let mut legacy = serde_json::to_value(&archive.entries().unwrap()[0]).unwrap();
+        legacy.as_object_mut().unwrap().remove("local_path");
+        let legacy: ArchiveEntry = serde_json::from_value(legacy).unwrap();
+        assert!(legacy.is_for("http", "https://cdn.example/a.mp3", &dir.path().join("elsewhere")));
+    }
+}
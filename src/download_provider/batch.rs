@@ -0,0 +1,239 @@
+//! Downloads a whole playlist or list of URIs, carrying on past failures,
+//! and reports what became of every entry in a form scripts can read.
+
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::metadata::TrackMetadata;
+use super::{interface::{DownloadConfig, ProvideDownload}, hooks::{self, HookCommand, HookFailure, HookPayload}};
+
+/// Error messages meaning the media is gone or locked away, so retrying
+/// will not help. Matched case-insensitively.
+const UNAVAILABLE_PATTERNS: [&str; 12] = [
+    "private video", "video unavailable", "is not available", "has been removed", "deleted video",
+    "members-only", "join this channel", "sign in to confirm your age", "copyright claim",
+    "account associated with this video has been terminated", "404 not found", "410 gone"
+];
+
+/// Whether a download error says the media cannot be had at all, rather
+/// than that something went wrong on the way
+/// ```
+/// use cli_music_player::download_provider::batch::is_unavailable;
+///
+/// assert!(is_unavailable("yt-dlp failed to download https://youtu.be/abc: [youtube] abc: Private video. Sign in if you've been granted access"));
+/// assert!(is_unavailable("Cannot fetch https://cdn.example/a.mp3: HTTP status client error (404 Not Found) for url (https://cdn.example/a.mp3)"));
+/// assert!(!is_unavailable("Download of https://cdn.example/a.mp3 interrupted at byte 1024: connection reset"));
+/// ```
+pub fn is_unavailable(error: &str) -> bool {
+    let error = error.to_lowercase();
+    UNAVAILABLE_PATTERNS.iter().any(|pattern| error.contains(pattern))
+}
+
+/// One entry of a batch
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BatchItem {
+    pub uri: String,
+    #[serde(default)]
+    pub title: Option<String>,
+    /// Why the entry is known to be unavailable before even trying, e.g.
+    /// a private video listed in a playlist
+    #[serde(default)]
+    pub unavailable: Option<String>,
+    /// Why the entry cannot be downloaded at all, e.g. a playlist entry
+    /// without a URL. It is reported as failed.
+    #[serde(default)]
+    pub broken: Option<String>
+}
+
+impl BatchItem {
+    pub fn new<AnyStr: AsRef<str>>(uri: AnyStr) -> Self {
+        Self { uri: uri.as_ref().to_string(), title: None, unavailable: None, broken: None }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum BatchOutcome {
+    Succeeded { files: Vec<PathBuf> },
+    /// The download archive had it already
+    Skipped { files: Vec<PathBuf> },
+    Failed { reason: String },
+    Unavailable { reason: String }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BatchEntry {
+    pub uri: String,
+    pub title: Option<String>,
+    #[serde(flatten)]
+    pub outcome: BatchOutcome,
+    /// Download hooks that failed on this entry
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hook_failures: Vec<HookFailure>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    pub unavailable: usize
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct BatchReport {
+    pub summary: BatchSummary,
+    /// In the order of the batch
+    pub entries: Vec<BatchEntry>,
+    /// Batch hooks that failed
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub hook_failures: Vec<HookFailure>
+}
+
+impl BatchReport {
+    pub fn new(entries: Vec<BatchEntry>) -> Self {
+        let mut summary = BatchSummary::default();
+        for entry in &entries {
+            *match entry.outcome {
+                BatchOutcome::Succeeded { .. } => &mut summary.succeeded,
+                BatchOutcome::Skipped { .. } => &mut summary.skipped,
+                BatchOutcome::Failed { .. } => &mut summary.failed,
+                BatchOutcome::Unavailable { .. } => &mut summary.unavailable
+            } += 1;
+        }
+        Self { summary, entries, hook_failures: vec![] }
+    }
+
+    /// Whether every entry that could be downloaded was. Unavailable
+    /// entries do not count against it.
+    pub fn is_success(&self) -> bool {
+        self.summary.failed == 0
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string_pretty(self).expect("reports serialize")
+    }
+}
+
+/// Downloads `items` one after the other with `provider`, each with
+/// `base` as config except for its URI. `on_entry` hears about every
+/// entry as soon as it is done. Download `hooks` run after every entry
+/// downloaded, batch ones once all are done.
+pub fn download_batch<P: ProvideDownload>(provider: &P, items: Vec<BatchItem>, base: &DownloadConfig, hooks: &[HookCommand], on_entry: &mut dyn FnMut(&BatchEntry)) -> BatchReport {
+    let entries = items.into_iter()
+        .map(|item| {
+            let outcome = match (item.unavailable, item.broken) {
+                (_, Some(reason)) => BatchOutcome::Failed { reason },
+                (Some(reason), None) => BatchOutcome::Unavailable { reason },
+                (None, None) => {
+                    let config = DownloadConfig { uri: item.uri.clone(), ..base.clone() };
+                    match provider.download(config) {
+                        Ok(downloaded) if downloaded.skipped => BatchOutcome::Skipped { files: downloaded.files },
+                        Ok(downloaded) => BatchOutcome::Succeeded { files: downloaded.files },
+                        Err(reason) if is_unavailable(&reason) => BatchOutcome::Unavailable { reason },
+                        Err(reason) => BatchOutcome::Failed { reason }
+                    }
+                }
+            };
+            if let BatchOutcome::Failed { reason } | BatchOutcome::Unavailable { reason } = &outcome {
+                log::warn!("Batch entry {} not downloaded: {reason}", item.uri);
+            }
+            let hook_failures = match &outcome {
+                BatchOutcome::Succeeded { files } => {
+                    // what the batch says about all entries, but the entry's own title
+                    let metadata = match (&base.metadata, &item.title) {
+                        (None, None) => None,
+                        (metadata, title) => Some(TrackMetadata { title: title.clone(), ..metadata.clone().unwrap_or_default() })
+                    };
+                    let payload = HookPayload::Download { uri: item.uri.clone(), files: files.clone(), metadata };
+                    hooks::run_hooks(hooks, &payload)
+                },
+                _ => vec![]
+            };
+            let entry = BatchEntry { uri: item.uri, title: item.title, outcome, hook_failures };
+            on_entry(&entry);
+            entry
+        })
+        .collect();
+    let mut report = BatchReport::new(entries);
+    report.hook_failures = hooks::run_hooks(hooks, &HookPayload::Batch { report: report.clone() });
+    report
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{common::self_setup::SelfSetup, download_provider::{Downloaded, archive::DownloadArchive, hooks::HookEvent, progress::ProgressCallback}};
+
+    use super::*;
+
+    /// Downloads through an archive, reporting no progress of its own;
+    /// URIs ending in "private" or "flaky" fail
+    struct FakeProvider {
+        archive: DownloadArchive
+    }
+
+    impl SelfSetup for FakeProvider {
+        fn setup(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    impl ProvideDownload for FakeProvider {
+        fn download_with_progress(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+            self.archive.download_once("fake", &config.uri, &config.local_path, config.force, on_progress, |_| {
+                if config.uri.ends_with("private") {
+                    return Err(format!("yt-dlp failed to download {}: Private video", config.uri));
+                }
+                if config.uri.ends_with("flaky") {
+                    return Err("connection reset".to_string());
+                }
+                let path = config.local_path.join(config.uri.rsplit('/').next().unwrap());
+                std::fs::write(&path, "audio").map_err(|err| err.to_string())?;
+                Ok(Downloaded::file(path))
+            })
+        }
+    }
+
+    #[test]
+    fn reports_every_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FakeProvider { archive: DownloadArchive::new(dir.path().join("archive.json")) };
+        let base = DownloadConfig {
+            metadata: Some(TrackMetadata { title: Some("Batch".to_string()), album: Some("Mixtape".to_string()), ..Default::default() }),
+            ..DownloadConfig::new("", dir.path())
+        };
+        provider.download(DownloadConfig { uri: "https://cdn.example/old".to_string(), ..base.clone() }).unwrap();
+        let items = vec![
+            BatchItem { title: Some("New song".to_string()), ..BatchItem::new("https://cdn.example/new") },
+            BatchItem::new("https://cdn.example/old"),
+            BatchItem::new("https://cdn.example/flaky"),
+            BatchItem::new("https://cdn.example/private"),
+            BatchItem { title: Some("[Deleted video]".to_string()), unavailable: Some("deleted".to_string()), ..BatchItem::new("https://cdn.example/gone") },
+            BatchItem { broken: Some("Playlist entry 6 has no URL".to_string()), ..BatchItem::new("https://cdn.example/playlist") }
+        ];
+        let mut heard = 0;
+        let hooks = [
+            HookCommand { command: vec!["sh".to_string(), "-c".to_string(), "test \"$CMP_SUCCEEDED\" = 1".to_string()], on: HookEvent::Batch, ..Default::default() },
+            HookCommand { command: vec!["false".to_string()], ..Default::default() },
+            HookCommand { command: vec!["sh".to_string(), "-c".to_string(), "test \"$CMP_TITLE/$CMP_ALBUM\" = 'New song/Mixtape'".to_string()], ..Default::default() }
+        ];
+        let report = download_batch(&provider, items, &base, &hooks, &mut |_| heard += 1);
+
+        assert_eq!(6, heard);
+        assert_eq!(BatchSummary { succeeded: 1, skipped: 1, failed: 2, unavailable: 2 }, report.summary);
+        assert!(!report.is_success());
+        assert_eq!(BatchOutcome::Succeeded { files: vec![dir.path().join("new")] }, report.entries[0].outcome);
+        assert_eq!(BatchOutcome::Skipped { files: vec![dir.path().join("old")] }, report.entries[1].outcome);
+        // the download hook failed on the one entry downloaded, the batch hook went fine
+        assert_eq!(1, report.entries[0].hook_failures.len());
+        assert!(report.entries[1].hook_failures.is_empty() && report.hook_failures.is_empty());
+
+        let json: serde_json::Value = serde_json::from_str(&report.to_json()).unwrap();
+        assert_eq!(2, json["summary"]["failed"]);
+        assert_eq!("failed", json["entries"][2]["status"]);
+        assert_eq!("connection reset", json["entries"][2]["reason"]);
+        assert_eq!("[Deleted video]", json["entries"][4]["title"]);
+        assert_eq!("Playlist entry 6 has no URL", json["entries"][5]["reason"]);
+    }
+}
@@ -0,0 +1,320 @@
+//! Keeps the download dir under a size quota, so it can serve as a
+//! streaming buffer on small disks. When the dir grows past the quota,
+//! the least recently played files go first; files never played count as
+//! played when they were downloaded. Pinned files are never evicted.
+
+use std::{collections::BTreeMap, fmt::Display, path::{Path, PathBuf}, sync::Mutex};
+
+use chrono::{DateTime, Utc};
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
+
+use crate::common::config::{load_json, project_dirs, save_json};
+
+lazy_static! {
+    /// Download workers evict and players mark plays at the same time
+    static ref CACHE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+/// Files of these types count towards the quota; everything else in the
+/// dir (queue, archive, installed programs, covers) is left alone
+pub const AUDIO_EXTENSIONS: [&str; 11] = ["mp3", "m4a", "opus", "ogg", "oga", "flac", "wav", "aac", "webm", "mka", "mp4"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct CacheConfig {
+    /// Where the downloads are
+    ///
+    /// Default: `<data dir>`
+    pub dir: PathBuf,
+    /// How many bytes of audio [CacheConfig::dir] may hold. None means
+    /// no limit.
+    ///
+    /// Default: None
+    pub quota_bytes: Option<u64>,
+    /// JSON file remembering plays and pins
+    ///
+    /// Default: `<data dir>/download_cache.json`
+    pub state_path: PathBuf
+}
+
+impl Default for CacheConfig {
+    fn default() -> Self {
+        Self {
+            dir: project_dirs().data_dir().to_path_buf(),
+            quota_bytes: None,
+            state_path: project_dirs().data_dir().join("download_cache.json")
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+struct CacheEntry {
+    last_played: Option<DateTime<Utc>>,
+    pinned: bool
+}
+
+/// What the state file holds, keyed by path relative to [CacheConfig::dir]
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+struct CacheState {
+    files: BTreeMap<PathBuf, CacheEntry>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct CachedFile {
+    pub path: PathBuf,
+    pub size: u64,
+    /// When it was last played, or downloaded if never played
+    pub last_used: DateTime<Utc>,
+    pub played: bool,
+    pub pinned: bool
+}
+
+/// What [DownloadCache::evict] removed, or would remove on a dry run
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EvictionReport {
+    pub dry_run: bool,
+    pub quota_bytes: Option<u64>,
+    /// Audio in the dir before evicting
+    pub total_bytes: u64,
+    pub pinned_bytes: u64,
+    /// Least recently used first
+    pub evicted: Vec<CachedFile>,
+    pub freed_bytes: u64
+}
+
+impl EvictionReport {
+    pub fn remaining_bytes(&self) -> u64 {
+        self.total_bytes - self.freed_bytes
+    }
+
+    /// Whether the dir stays over quota, because of pinned or protected files
+    pub fn over_quota(&self) -> bool {
+        self.quota_bytes.is_some_and(|quota| self.remaining_bytes() > quota)
+    }
+}
+
+impl Display for EvictionReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let verb = if self.dry_run { "Would remove" } else { "Removed" };
+        for file in &self.evicted {
+            let used = if file.played { "last played" } else { "downloaded" };
+            writeln!(f, "{verb} {:?} ({} bytes, {used} {})", file.path, file.size, file.last_used.format("%Y-%m-%d %H:%M"))?;
+        }
+        let quota = self.quota_bytes.map(|quota| format!("{quota} bytes")).unwrap_or_else(|| "no limit".to_string());
+        write!(f, "{verb} {} files, {} bytes; {} bytes left ({} pinned), quota {quota}",
+            self.evicted.len(), self.freed_bytes, self.remaining_bytes(), self.pinned_bytes)?;
+        if self.over_quota() {
+            write!(f, "; still over quota")?;
+        }
+        Ok(())
+    }
+}
+
+/// Audio files under `dir`, skipping hidden files and dirs
+fn audio_files(dir: &Path, found: &mut Vec<PathBuf>) -> Result<(), String> {
+    let entries = std::fs::read_dir(dir).map_err(|err| format!("Cannot list {dir:?}: {err}"))?;
+    for entry in entries {
+        let path = entry.map_err(|err| format!("Cannot list {dir:?}: {err}"))?.path();
+        if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')) {
+            continue;
+        }
+        if path.is_dir() {
+            audio_files(&path, found)?;
+        } else if path.extension().is_some_and(|ext| AUDIO_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())) {
+            found.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Plays, pins and the quota of the download dir
+pub struct DownloadCache {
+    config: CacheConfig
+}
+
+impl DownloadCache {
+    pub fn new(config: CacheConfig) -> Self {
+        Self { config }
+    }
+
+    pub fn config(&self) -> &CacheConfig {
+        &self.config
+    }
+
+    fn load(&self) -> Result<CacheState, String> {
+        load_json(&self.config.state_path, "download cache state")
+    }
+
+    fn save(&self, state: &CacheState) -> Result<(), String> {
+        save_json(&self.config.state_path, "download cache state", state)
+    }
+
+    /// `path` relative to [CacheConfig::dir], which is how the state knows it
+    fn key(&self, path: &Path) -> Result<PathBuf, String> {
+        match path.strip_prefix(&self.config.dir) {
+            Ok(relative) => Ok(relative.to_path_buf()),
+            Err(_) if path.is_relative() => Ok(path.to_path_buf()),
+            Err(_) => Err(format!("{path:?} is not in the download dir {:?}", self.config.dir))
+        }
+    }
+
+    fn update(&self, path: &Path, change: impl FnOnce(&mut CacheEntry)) -> Result<(), String> {
+        let key = self.key(path)?;
+        let _guard = CACHE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut state = self.load()?;
+        change(state.files.entry(key).or_default());
+        self.save(&state)
+    }
+
+    /// Marks `path` as played just now, moving it to the back of the
+    /// eviction order. Streams do this for what they serve, see
+    /// [DownloadStream::start_cached](super::stream::DownloadStream::start_cached).
+    pub fn played(&self, path: &Path) -> Result<(), String> {
+        self.update(path, |entry| entry.last_played = Some(Utc::now()))
+    }
+
+    /// Pinned files are never evicted
+    pub fn pin(&self, path: &Path, pinned: bool) -> Result<(), String> {
+        self.update(path, |entry| entry.pinned = pinned)
+    }
+
+    /// The audio files in the dir, least recently used first
+    pub fn files(&self) -> Result<Vec<CachedFile>, String> {
+        let state = {
+            let _guard = CACHE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            self.load()?
+        };
+        let mut paths = vec![];
+        if self.config.dir.is_dir() {
+            audio_files(&self.config.dir, &mut paths)?;
+        }
+        let mut files = paths.into_iter()
+            .map(|path| {
+                let meta = std::fs::metadata(&path).map_err(|err| format!("Cannot stat {path:?}: {err}"))?;
+                let entry = state.files.get(&self.key(&path)?).cloned().unwrap_or_default();
+                let downloaded = meta.modified().map(DateTime::<Utc>::from).unwrap_or_else(|_| Utc::now());
+                Ok(CachedFile {
+                    size: meta.len(),
+                    last_used: entry.last_played.unwrap_or(downloaded),
+                    played: entry.last_played.is_some(),
+                    pinned: entry.pinned,
+                    path
+                })
+            })
+            .collect::<Result<Vec<_>, String>>()?;
+        files.sort_by(|a, b| a.last_used.cmp(&b.last_used).then_with(|| a.path.cmp(&b.path)));
+        Ok(files)
+    }
+
+    /// Removes the least recently used files until the dir fits the
+    /// quota, never touching pinned files or those in `protect` (say, the
+    /// download that just finished). A dry run only reports what would go.
+    pub fn evict(&self, dry_run: bool, protect: &[&Path]) -> Result<EvictionReport, String> {
+        let files = self.files()?;
+        let total_bytes = files.iter().map(|file| file.size).sum();
+        let mut report = EvictionReport {
+            dry_run,
+            quota_bytes: self.config.quota_bytes,
+            total_bytes,
+            pinned_bytes: files.iter().filter(|file| file.pinned).map(|file| file.size).sum(),
+            evicted: vec![],
+            freed_bytes: 0
+        };
+        let Some(quota) = self.config.quota_bytes else {
+            return Ok(report);
+        };
+        for file in files {
+            if report.remaining_bytes() <= quota {
+                break;
+            }
+            if file.pinned || protect.contains(&file.path.as_path()) {
+                continue;
+            }
+            report.freed_bytes += file.size;
+            report.evicted.push(file);
+        }
+        if dry_run || report.evicted.is_empty() {
+            return Ok(report);
+        }
+
+        for file in &report.evicted {
+            log::info!("Evicting {:?} from the download cache", file.path);
+            std::fs::remove_file(&file.path).map_err(|err| format!("Cannot remove {:?}: {err}", file.path))?;
+            // drop the artist and album dirs templates created, once empty
+            let mut dir = file.path.parent();
+            while let Some(empty) = dir.filter(|dir| *dir != self.config.dir && std::fs::remove_dir(dir).is_ok()) {
+                dir = empty.parent();
+            }
+        }
+        let _guard = CACHE_LOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut state = self.load()?;
+        state.files.retain(|key, _| self.config.dir.join(key).exists());
+        self.save(&state)?;
+        Ok(report)
+    }
+
+    /// Evicts for real after a download, if there is a quota. A dir that
+    /// stays over quota (because everything left is pinned or protected)
+    /// is only logged; the download that filled it is done either way.
+    pub fn evict_or_warn(&self, protect: &[&Path]) {
+        if self.config.quota_bytes.is_none() {
+            return;
+        }
+        match self.evict(false, protect) {
+            Ok(report) if report.over_quota() => log::warn!("{report}"),
+            Ok(report) if !report.evicted.is_empty() => log::info!("{report}"),
+            Ok(_) => {},
+            Err(err) => log::warn!("Cannot enforce the download cache quota: {err}")
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn evicts_least_recently_played_unless_pinned() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut cache = DownloadCache::new(CacheConfig {
+            dir: dir.path().to_path_buf(),
+            quota_bytes: Some(250),
+            state_path: dir.path().join("cache.json")
+        });
+        std::fs::create_dir_all(dir.path().join("Artist/Album")).unwrap();
+        let file = |name: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, [0; 100]).unwrap();
+            path
+        };
+        let (oldest, pinned, played_first, played_last) = (file("Artist/Album/c.mp3"), file("d.opus"), file("b.m4a"), file("a.mp3"));
+        std::fs::write(dir.path().join("Artist/Album/cover.jpg"), "not audio").unwrap();
+        cache.pin(&pinned, true).unwrap();
+        cache.played(&played_first).unwrap();
+        cache.played(&played_last).unwrap();
+        cache.played(Path::new("d.opus")).unwrap();
+
+        let planned = cache.evict(true, &[]).unwrap();
+        assert_eq!(vec![oldest.clone(), played_first.clone()], planned.evicted.iter().map(|file| file.path.clone()).collect::<Vec<_>>());
+        assert_eq!((400, 100, 200), (planned.total_bytes, planned.pinned_bytes, planned.remaining_bytes()));
+        assert!(oldest.exists() && played_first.exists());
+
+        let protected = cache.evict(true, &[&oldest]).unwrap();
+        assert_eq!(vec![played_first.clone(), played_last.clone()], protected.evicted.iter().map(|file| file.path.clone()).collect::<Vec<_>>());
+
+        let report = cache.evict(false, &[]).unwrap();
+        assert_eq!(planned.evicted, report.evicted);
+        assert!(!oldest.exists() && !played_first.exists() && played_last.exists() && pinned.exists());
+        // the album dir keeps its cover
+        assert!(dir.path().join("Artist/Album/cover.jpg").exists());
+        assert!(!cache.evict(false, &[]).unwrap().over_quota());
+
+        cache.config.quota_bytes = Some(50);
+        let tight = cache.evict(false, &[]).unwrap();
+        assert!(tight.over_quota());
+        assert_eq!(vec![played_last], tight.evicted.into_iter().map(|file| file.path).collect::<Vec<_>>());
+    }
+}
@@ -0,0 +1,276 @@
+//! Splits full-album uploads into their tracks, going by the chapters of
+//! the video or, failing those, the timestamps listed in its description.
+
+use std::{fmt::Display, path::{Path, PathBuf}, process::Command, time::Duration};
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::common::metadata::{TrackMetadata, parse_clock_duration};
+use super::{interface::DownloadConfig, tagging, template};
+
+lazy_static! {
+    /// "3:15" or "1:02:03", standing on its own or in brackets
+    static ref TIMESTAMP: Regex = Regex::new(r"(?:^|[\s\[(])((?:\d{1,2}:)?\d{1,2}:\d{2})(?:$|[\s\])])").unwrap();
+    /// "01." or "1)" numbering in front of a title
+    static ref NUMBERING: Regex = Regex::new(r"^\d{1,3}[.)]\s+").unwrap();
+}
+
+/// A part of a longer recording, usually one track of an album
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Chapter {
+    pub title: String,
+    pub start: Duration,
+    /// None for "until the end of the file"
+    pub end: Option<Duration>
+}
+
+/// Which tracks a recording will be split into
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct SplitPlan {
+    pub chapters: Vec<Chapter>
+}
+
+impl SplitPlan {
+    /// What track `number` (1-based) of the plan is, given what is known
+    /// about the whole recording: the recording becomes the album
+    pub fn metadata(&self, number: usize, recording: &TrackMetadata) -> TrackMetadata {
+        let chapter = &self.chapters[number - 1];
+        TrackMetadata {
+            title: Some(chapter.title.clone()),
+            album: recording.album.clone().or_else(|| recording.title.clone()),
+            album_artist: recording.album_artist.clone().or_else(|| recording.artist.clone()),
+            track_number: Some(number as u32),
+            track_total: Some(self.chapters.len() as u32),
+            duration: chapter.end.map(|end| end.saturating_sub(chapter.start)),
+            thumbnail_url: None,
+            ..recording.clone()
+        }
+    }
+}
+
+fn clock(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{hours}:{:02}:{:02}", secs / 60 % 60, secs % 60)
+    }
+}
+
+/// One line per track, for showing the split points before committing
+/// to them
+impl Display for SplitPlan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.chapters.is_empty() {
+            return write!(f, "No chapters or timestamps found");
+        }
+        for (idx, chapter) in self.chapters.iter().enumerate() {
+            let end = chapter.end.map(clock).unwrap_or_else(|| "end".to_string());
+            writeln!(f, "{:02}. {} - {end}  {}", idx + 1, clock(chapter.start), chapter.title)?;
+        }
+        Ok(())
+    }
+}
+
+/// Reads a tracklist out of free text such as a video description: lines
+/// holding a timestamp, the rest of the line being the title. It only
+/// counts as one when it has at least two tracks, starts at 0:00 and
+/// goes forward, so timestamps mentioned in passing are not taken for one.
+/// ```
+/// use std::time::Duration;
+/// use cli_music_player::download_provider::chapters::parse_timestamps;
+///
+/// let description = "Full album, remastered.
+///
+/// Tracklist:
+/// 01. Speak to Me 0:00
+/// 02. Breathe (In the Air) - 1:13
+/// [03:58] On the Run
+/// 1:02:03 | Eclipse";
+/// let chapters = parse_timestamps(description);
+/// assert_eq!(vec!["Speak to Me", "Breathe (In the Air)", "On the Run", "Eclipse"],
+///     chapters.iter().map(|chapter| chapter.title.as_str()).collect::<Vec<_>>());
+/// assert_eq!(Some(Duration::from_secs(73)), chapters[0].end);
+/// assert_eq!((Duration::from_secs(3723), None), (chapters[3].start, chapters[3].end));
+///
+/// assert!(parse_timestamps("The solo at 2:30 is the best part").is_empty());
+/// ```
+pub fn parse_timestamps<AnyStr: AsRef<str>>(text: AnyStr) -> Vec<Chapter> {
+    let marks = text.as_ref().lines()
+        .filter_map(|line| {
+            let found = TIMESTAMP.captures(line)?;
+            let start = parse_clock_duration(found.get(1)?.as_str())?;
+            // brackets around the timestamp go with it
+            let (before, after) = (&line[..found.get(0)?.start()], &line[found.get(0)?.end()..]);
+            let rest = format!("{before} {after}");
+            let title = rest.trim_matches(|c: char| c.is_whitespace() || "-–—|:•*".contains(c));
+            let title = NUMBERING.replace(title, "").trim().to_string();
+            Some((start, title))
+        })
+        .collect::<Vec<_>>();
+    let is_tracklist = marks.len() >= 2
+        && marks[0].0.is_zero()
+        && marks.windows(2).all(|pair| pair[0].0 < pair[1].0);
+    if !is_tracklist {
+        return vec![];
+    }
+    let ends = marks.iter().skip(1).map(|(start, _)| Some(*start)).chain([None]);
+    marks.iter().zip(ends)
+        .enumerate()
+        .map(|(idx, ((start, title), end))| Chapter {
+            title: if title.is_empty() { format!("Track {}", idx + 1) } else { title.clone() },
+            start: *start,
+            end
+        })
+        .collect()
+}
+
+/// The chapters yt-dlp reports in a video's info JSON, else the
+/// timestamps in its description
+/// ```
+/// use std::time::Duration;
+/// use cli_music_player::download_provider::chapters::chapters_from_info_json;
+///
+/// let info = serde_json::json!({"duration": 300.0, "chapters": [
+///     {"start_time": 0.0, "end_time": 120.0, "title": "Intro"},
+///     {"start_time": 120.0, "end_time": 300.0, "title": "Outro"}
+/// ]});
+/// let chapters = chapters_from_info_json(&info);
+/// assert_eq!(("Outro", Duration::from_secs(120)), (chapters[1].title.as_str(), chapters[1].start));
+///
+/// let info = serde_json::json!({"duration": 300.0, "description": "0:00 Intro\n2:00 Outro"});
+/// assert_eq!(Some(Duration::from_secs(300)), chapters_from_info_json(&info)[1].end);
+/// ```
+pub fn chapters_from_info_json(info: &Value) -> Vec<Chapter> {
+    let seconds = |value: Option<&Value>| value.and_then(Value::as_f64).filter(|secs| *secs >= 0.0).map(Duration::from_secs_f64);
+    let chapters = info.get("chapters").and_then(Value::as_array).map(|chapters| chapters.iter()
+            .enumerate()
+            .filter_map(|(idx, chapter)| Some(Chapter {
+                title: chapter.get("title").and_then(Value::as_str).map(str::trim).filter(|title| !title.is_empty())
+                    .map(str::to_string)
+                    .unwrap_or_else(|| format!("Track {}", idx + 1)),
+                start: seconds(chapter.get("start_time"))?,
+                end: seconds(chapter.get("end_time"))
+            }))
+            .collect::<Vec<_>>())
+        .filter(|chapters| chapters.len() >= 2);
+    let mut chapters = chapters.unwrap_or_else(|| parse_timestamps(info.get("description").and_then(Value::as_str).unwrap_or_default()));
+    if let Some(last) = chapters.last_mut() {
+        last.end = last.end.or(seconds(info.get("duration")));
+    }
+    chapters
+}
+
+/// Cuts `source` into one file per chapter of `plan`, tagged as tracks
+/// of an album made of `recording` and placed as `config` asks; without
+/// a [DownloadConfig::filename_template], they go next to the source as
+/// "<number> - <title>". Streams are copied, not re-encoded. The source
+/// is removed once all tracks are written; on failure it stays and the
+/// tracks written so far are removed.
+pub fn split(ffmpeg: &Path, source: &Path, plan: &SplitPlan, recording: &TrackMetadata, config: &DownloadConfig) -> Result<Vec<PathBuf>, String> {
+    let dir = source.parent().unwrap_or_else(|| Path::new("."));
+    let ext = source.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    let mut tracks = vec![];
+    for (idx, chapter) in plan.chapters.iter().enumerate() {
+        let track = template::unique_path(&dir.join(format!("{:02} - {}{ext}", idx + 1, template::sanitize(&chapter.title))));
+        let mut command = Command::new(ffmpeg);
+        command.args(["-hide_banner", "-loglevel", "error", "-y", "-i"]).arg(source)
+            .arg("-ss").arg(format!("{:.3}", chapter.start.as_secs_f64()));
+        if let Some(end) = chapter.end {
+            command.arg("-to").arg(format!("{:.3}", end.as_secs_f64()));
+        }
+        command.args(["-map", "0:a", "-c", "copy", "-map_metadata", "-1"]).arg(&track);
+        log::info!("Running {command:?}");
+        let metadata = plan.metadata(idx + 1, recording);
+        let placed = command.output()
+            .map_err(|err| format!("Cannot run {ffmpeg:?}: {err}"))
+            .and_then(|output| if output.status.success() { Ok(()) } else {
+                Err(format!("ffmpeg could not cut {:?} out of {source:?}: {}", chapter.title, String::from_utf8_lossy(&output.stderr).trim()))
+            })
+            .and_then(|_| {
+                tagging::tag_or_warn(&track, &metadata);
+                template::place(&track, config, Some(&metadata))
+            });
+        match placed {
+            Ok(placed) => tracks.push(placed),
+            Err(err) => {
+                for written in tracks.iter().chain([&track]) {
+                    let _ = std::fs::remove_file(written);
+                }
+                return Err(err);
+            }
+        }
+    }
+    std::fs::remove_file(source).map_err(|err| format!("Cannot remove {source:?}: {err}"))?;
+    Ok(tracks)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::fake_program::fake_program;
+
+    use super::*;
+
+    /// Writes the cut it was asked for into the output file
+    const FAKE_FFMPEG: &str = r#"
+for arg in "$@"; do last="$arg"; done
+case "$last" in *Broken*) echo "Invalid data found" >&2; exit 1;; esac
+echo "$*" > "$last"
+"#;
+
+    fn plan() -> SplitPlan {
+        SplitPlan { chapters: parse_timestamps("0:00 Speak to Me\n1:13 Breathe/Reprise") }
+    }
+
+    fn config(dir: &tempfile::TempDir, filename_template: Option<&str>) -> DownloadConfig {
+        DownloadConfig {
+            filename_template: filename_template.map(str::to_string),
+            ..DownloadConfig::new("https://www.youtube.com/watch?v=album", dir.path())
+        }
+    }
+
+    #[test]
+    fn splits_into_tagged_tracks() {
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", FAKE_FFMPEG);
+        let source = dir.path().join("album.m4a");
+        std::fs::write(&source, "audio").unwrap();
+        let recording = TrackMetadata { title: Some("The Dark Side of the Moon".to_string()), artist: Some("Pink Floyd".to_string()), ..Default::default() };
+
+        assert_eq!("01. 0:00 - 1:13  Speak to Me\n02. 1:13 - end  Breathe/Reprise\n", plan().to_string());
+        let tracks = split(&ffmpeg, &source, &plan(), &recording, &config(&dir, None)).unwrap();
+        assert_eq!(vec![dir.path().join("01 - Speak to Me.m4a"), dir.path().join("02 - Breathe_Reprise.m4a")], tracks);
+        assert!(!source.exists());
+        let second = std::fs::read_to_string(&tracks[1]).unwrap();
+        assert!(second.contains("-ss 73.000 -map 0:a") && !second.contains("-to"), "{second}");
+        let first = std::fs::read_to_string(&tracks[0]).unwrap();
+        assert!(first.contains("-ss 0.000 -to 73.000"), "{first}");
+
+        let metadata = plan().metadata(2, &recording);
+        assert_eq!(Some("The Dark Side of the Moon".to_string()), metadata.album);
+        assert_eq!((Some(2), Some(2)), (metadata.track_number, metadata.track_total));
+
+        // tracks go through the filename template like any download
+        std::fs::write(&source, "audio").unwrap();
+        let tracks = split(&ffmpeg, &source, &plan(), &recording, &config(&dir, Some("{album_artist}/{album}/{track:02}. {title}.{ext}"))).unwrap();
+        let album = dir.path().join("Pink Floyd/The Dark Side of the Moon");
+        assert_eq!(vec![album.join("01. Speak to Me.m4a"), album.join("02. Breathe_Reprise.m4a")], tracks);
+        assert!(tracks.iter().all(|track| track.is_file()));
+    }
+
+    #[test]
+    fn keeps_the_source_when_a_cut_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", FAKE_FFMPEG);
+        let source = dir.path().join("album.m4a");
+        std::fs::write(&source, "audio").unwrap();
+        let plan = SplitPlan { chapters: parse_timestamps("0:00 Fine\n1:00 Broken") };
+
+        let err = split(&ffmpeg, &source, &plan, &TrackMetadata::default(), &config(&dir, None)).unwrap_err();
+        assert!(err.contains("Invalid data found"), "{err}");
+        assert!(source.exists());
+        assert!(!dir.path().join("01 - Fine.m4a").exists());
+    }
+}
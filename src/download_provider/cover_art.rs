@@ -0,0 +1,209 @@
+//! Fetches cover art for downloaded tracks and embeds it as the front
+//! cover. Thumbnails go through ffmpeg, which turns whatever the source
+//! serves (WebP, PNG, ...) into a JPEG and crops letterboxed video frames
+//! down to the square album art inside them.
+
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+use lofty::{MimeType, Picture, PictureType, Tag, TagExt};
+use reqwest::{Url, blocking::Client};
+use serde::{Deserialize, Serialize};
+
+use crate::common::metadata::TrackMetadata;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct CoverArtOptions {
+    /// Embed the cover as front cover in the file's tags
+    ///
+    /// Default: true
+    pub embed: bool,
+    /// Crop 16:9 video thumbnails (and YouTube's letterboxed 4:3 ones) to
+    /// the square in their middle
+    ///
+    /// Default: true
+    pub crop_to_square: bool,
+    /// Also save the cover as `cover.jpg` in the folder of a track that
+    /// belongs to an album, unless one is there already
+    ///
+    /// Default: false
+    pub save_cover_file: bool
+}
+
+impl Default for CoverArtOptions {
+    fn default() -> Self {
+        Self { embed: true, crop_to_square: true, save_cover_file: false }
+    }
+}
+
+/// Thumbnail URLs to try, best first. YouTube serves the same thumbnail
+/// in several sizes, not all of which exist for every video.
+/// ```
+/// use cli_music_player::download_provider::cover_art::thumbnail_candidates;
+///
+/// assert_eq!(vec![
+///     "https://i.ytimg.com/vi/abc/maxresdefault.jpg",
+///     "https://i.ytimg.com/vi/abc/sddefault.jpg",
+///     "https://i.ytimg.com/vi/abc/hqdefault.jpg",
+///     "https://i.ytimg.com/vi_webp/abc/hqdefault.webp",
+/// ], thumbnail_candidates("https://i.ytimg.com/vi_webp/abc/hqdefault.webp"));
+/// assert_eq!(vec!["https://cdn.example/art.png"], thumbnail_candidates("https://cdn.example/art.png"));
+/// ```
+pub fn thumbnail_candidates<AnyStr: AsRef<str>>(url: AnyStr) -> Vec<String> {
+    let url = url.as_ref();
+    let video_id = Url::parse(url).ok()
+        .filter(|parsed| parsed.host_str().is_some_and(|host| host.ends_with("ytimg.com")))
+        .and_then(|parsed| {
+            let mut segments = parsed.path_segments()?;
+            matches!(segments.next(), Some("vi" | "vi_webp")).then(|| segments.next().map(str::to_string)).flatten()
+        });
+    let mut candidates = video_id.map(|id| ["maxresdefault", "sddefault", "hqdefault"].iter()
+            .map(|size| format!("https://i.ytimg.com/vi/{id}/{size}.jpg"))
+            .collect::<Vec<_>>())
+        .unwrap_or_default();
+    if !candidates.iter().any(|candidate| candidate == url) {
+        candidates.push(url.to_string());
+    }
+    candidates
+}
+
+/// The ffmpeg filter cropping a thumbnail to the square in its middle:
+/// the full height of 16:9 frames, or the height of the 16:9 picture
+/// letterboxed into a 4:3 one. Other shapes are left alone.
+pub fn crop_filter(letterboxed: bool) -> String {
+    let side = if letterboxed {
+        "if(gt(iw/ih,1.7),ih,if(between(iw/ih,1.3,1.36),iw*9/16,-1))"
+    } else {
+        "if(gt(iw/ih,1.7),ih,-1)"
+    };
+    // -1 means "keep as is"
+    format!("crop=w='if(lt({side},0),iw,{side})':h='if(lt({side},0),ih,{side})'")
+}
+
+fn fetch(client: &Client, url: &str) -> Result<Vec<u8>, String> {
+    client.get(url).send()
+        .and_then(|resp| resp.error_for_status())
+        .and_then(|resp| resp.bytes())
+        .map(|bytes| bytes.to_vec())
+        .map_err(|err| format!("Cannot fetch {url}: {err}"))
+}
+
+/// Fetches the best thumbnail for `thumbnail_url` and returns it as JPEG,
+/// through `client`, using `scratch` (plus suffixes) for intermediate files
+pub fn fetch_cover(client: &Client, thumbnail_url: &str, ffmpeg: &Path, options: &CoverArtOptions, scratch: &Path) -> Result<Vec<u8>, String> {
+    let (url, image) = thumbnail_candidates(thumbnail_url).into_iter()
+        .find_map(|url| fetch(client, &url).map_err(|err| log::debug!("{err}")).ok().map(|image| (url, image)))
+        .ok_or_else(|| format!("No thumbnail could be fetched for {thumbnail_url}"))?;
+    let source = scratch.with_extension("cover-source");
+    let jpeg = scratch.with_extension("cover.jpg");
+    std::fs::write(&source, image).map_err(|err| format!("Cannot write {source:?}: {err}"))?;
+    let mut command = Command::new(ffmpeg);
+    command.args(["-hide_banner", "-loglevel", "error", "-y", "-i"]).arg(&source);
+    if options.crop_to_square {
+        command.arg("-vf").arg(crop_filter(url.contains("ytimg.com")));
+    }
+    command.args(["-frames:v", "1", "-q:v", "2"]).arg(&jpeg);
+    log::info!("Running {command:?}");
+    let result = command.output();
+    let _ = std::fs::remove_file(&source);
+    let output = result.map_err(|err| format!("Cannot run {ffmpeg:?}: {err}"))?;
+    let converted = std::fs::read(&jpeg);
+    let _ = std::fs::remove_file(&jpeg);
+    if !output.status.success() {
+        return Err(format!("ffmpeg could not convert the thumbnail {url}: {}", String::from_utf8_lossy(&output.stderr).trim()));
+    }
+    converted.map_err(|err| format!("ffmpeg left no thumbnail at {jpeg:?}: {err}"))
+}
+
+/// Replaces the front cover of the file at `path` with `jpeg`
+pub fn embed_cover(path: &Path, jpeg: Vec<u8>) -> Result<(), String> {
+    let mut tagged = lofty::read_from_path(path, false)
+        .map_err(|err| format!("Cannot read tags of {path:?}: {err}"))?;
+    let tag_type = tagged.primary_tag_type();
+    if tagged.tag(tag_type).is_none() {
+        tagged.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged.tag_mut(tag_type).expect("tag was just inserted");
+    tag.remove_picture_type(PictureType::CoverFront);
+    tag.push_picture(Picture::new_unchecked(PictureType::CoverFront, MimeType::Jpeg, None, jpeg));
+    tag.save_to_path(path).map_err(|err| format!("Cannot write cover of {path:?}: {err}"))
+}
+
+/// Where [CoverArtOptions::save_cover_file] puts the cover of `path`
+fn cover_file_of(path: &Path, metadata: &TrackMetadata) -> Option<PathBuf> {
+    metadata.album.as_ref()?;
+    path.parent().map(|dir| dir.join("cover.jpg"))
+}
+
+/// Fetches, embeds and saves the cover of the downloaded file at `path`
+/// as `options` say, fetching it through `client`. Nothing happens when
+/// `metadata` has no thumbnail.
+pub fn apply(client: &Client, path: &Path, metadata: &TrackMetadata, ffmpeg: &Path, options: &CoverArtOptions) -> Result<(), String> {
+    let Some(thumbnail_url) = &metadata.thumbnail_url else {
+        return Ok(());
+    };
+    let cover_file = cover_file_of(path, metadata)
+        .filter(|cover| options.save_cover_file && !cover.exists());
+    if !options.embed && cover_file.is_none() {
+        return Ok(());
+    }
+    let jpeg = fetch_cover(client, thumbnail_url, ffmpeg, options, path)?;
+    if let Some(cover) = cover_file {
+        std::fs::write(&cover, &jpeg).map_err(|err| format!("Cannot write {cover:?}: {err}"))?;
+    }
+    if options.embed {
+        embed_cover(path, jpeg)?;
+    }
+    Ok(())
+}
+
+/// [apply], logging failures: thumbnails live on other hosts than the
+/// audio and disappear on their own schedule
+pub fn apply_or_warn(client: &Client, path: &Path, metadata: &TrackMetadata, ffmpeg: &Path, options: &CoverArtOptions) {
+    if let Err(err) = apply(client, path, metadata, ffmpeg, options) {
+        log::warn!("Leaving {path:?} without cover art: {err}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::{fake_program::fake_program, test_server::TestServer};
+
+    use super::*;
+
+    #[test]
+    fn embeds_and_saves_cover() {
+        let jpeg = vec![0xFF, 0xD8, 0xFF, 0xE0, 1, 2, 3, 4, 5, 6, 7, 8];
+        let server = TestServer::routes(vec![("/art.png", jpeg.clone())]);
+        let dir = tempfile::tempdir().unwrap();
+        // copies the input and records the filter
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", r#"
+while [ "$#" -gt 0 ]; do
+    case "$1" in -i) input="$2"; shift;; -vf) echo "$2" > "$(dirname "$input")/filter";; esac
+    last="$1"; shift
+done
+cp "$input" "$last""#);
+        std::fs::create_dir(dir.path().join("album")).unwrap();
+        let track = dir.path().join("album/01 - Intro.mp3");
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x64];
+        frame.resize(417, 0);
+        std::fs::write(&track, frame.repeat(4)).unwrap();
+
+        let metadata = TrackMetadata {
+            album: Some("Album".to_string()),
+            thumbnail_url: Some(server.url("/art.png")),
+            ..Default::default()
+        };
+        let options = CoverArtOptions { save_cover_file: true, ..Default::default() };
+        apply(&Client::new(), &track, &metadata, &ffmpeg, &options).unwrap();
+
+        assert_eq!(jpeg, std::fs::read(dir.path().join("album/cover.jpg")).unwrap());
+        let tagged = lofty::read_from_path(&track, false).unwrap();
+        let cover = tagged.primary_tag().unwrap().get_picture_type(PictureType::CoverFront).unwrap();
+        assert_eq!(jpeg, cover.data());
+        assert_eq!(crop_filter(false), std::fs::read_to_string(dir.path().join("album/filter")).unwrap().trim());
+        // no scratch files left behind
+        assert_eq!(3, std::fs::read_dir(dir.path().join("album")).unwrap().count());
+    }
+}
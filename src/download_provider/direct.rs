@@ -0,0 +1,466 @@
+//! Plain HTTP(S) downloads of direct media URLs, shared by the providers
+//! whose search results already point at the media file.
+
+use std::{fs::{File, OpenOptions}, io::{Read, Write}, path::{Path, PathBuf}};
+
+use percent_encoding::percent_decode_str;
+use reqwest::{StatusCode, Url, blocking::Response, header};
+use serde::{Deserialize, Serialize};
+
+use crate::common::{self_setup::{SelfSetup, SetupConfig}, install::{PinnedRelease, installed_path, sha256_hex}, metadata::TrackMetadata};
+
+use super::{interface::{DownloadConfig, Downloaded, ProvideDownload}, template, tagging, loudness, archive::DownloadArchive, network::{NetworkOptions, USER_AGENT}, cover_art::{self, CoverArtOptions}, progress::{DownloadPhase, DownloadProgress, ProgressCallback, ProgressMeter}};
+
+/// Picks a local file name from the last path segment of a URL
+/// ```
+/// use cli_music_player::download_provider::direct::file_name_of;
+///
+/// assert_eq!("episode 1.mp3", file_name_of("https://cdn.example/feed/episode%201.mp3?ref=rss"));
+/// assert_eq!("download", file_name_of("https://cdn.example/"));
+/// assert_eq!("download", file_name_of("https://cdn.example/..%2F..%2Fetc"));
+/// ```
+pub fn file_name_of<AnyStr: AsRef<str>>(url: AnyStr) -> String {
+    Url::parse(url.as_ref()).ok()
+        .and_then(|url| url.path_segments()
+            .and_then(|mut segments| segments.next_back())
+            .map(|name| percent_decode_str(name).decode_utf8_lossy().to_string()))
+        .filter(|name| !name.is_empty() && name != "." && name != ".." && !name.contains(['/', '\\']))
+        .unwrap_or_else(|| "download".to_string())
+}
+
+/// Where the data of `url` collects while it is fetched into `dir`: named
+/// after the file, but told apart by a hash of the whole URL, so URLs
+/// ending in the same name do not resume each other's downloads
+/// ```
+/// use std::path::Path;
+/// use cli_music_player::download_provider::direct::part_path;
+///
+/// let first = part_path(Path::new("/music"), "https://cdn.example/show/1/audio.mp3");
+/// let second = part_path(Path::new("/music"), "https://cdn.example/show/2/audio.mp3");
+/// assert!(first.to_string_lossy().starts_with("/music/audio.mp3.") && first.to_string_lossy().ends_with(".part"));
+/// assert_ne!(first, second);
+/// ```
+pub fn part_path<P: AsRef<Path>>(dir: P, url: &str) -> PathBuf {
+    dir.as_ref().join(format!("{}.{}.part", file_name_of(url), &sha256_hex(url.as_bytes())[..16]))
+}
+
+/// What tells versions of a remote file apart: its ETag, else its
+/// Last-Modified date
+fn validator(resp: &Response) -> Option<String> {
+    [header::ETAG, header::LAST_MODIFIED].iter()
+        .find_map(|name| resp.headers().get(name)?.to_str().ok().map(str::to_string))
+}
+
+/// Parses a `Content-Range: bytes <start>-<end>/<total>` header into the
+/// start and, when known, the total size
+/// ```
+/// use cli_music_player::download_provider::direct::content_range;
+///
+/// assert_eq!(Some((100, Some(300))), content_range("bytes 100-299/300"));
+/// assert_eq!(Some((100, None)), content_range("bytes 100-299/*"));
+/// assert_eq!(None, content_range("bytes */300"));
+/// ```
+pub fn content_range(value: &str) -> Option<(u64, Option<u64>)> {
+    let (range, total) = value.trim().strip_prefix("bytes ")?.split_once('/')?;
+    let start = range.split_once('-')?.0.parse().ok()?;
+    Some((start, total.parse().ok()))
+}
+
+/// Downloads direct media URLs (podcast enclosures, archive files, radio
+/// recordings) with a plain HTTP client. Data goes to a [part_path] first,
+/// which a later attempt resumes with a `Range` request as long as the
+/// remote file did not change; the file only gets its real name once all
+/// of it arrived.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HttpDownload {
+    /// Default: "cli-music-player/<version>"
+    pub user_agent: String,
+    /// Whether to continue `.part` files left by earlier attempts
+    ///
+    /// Default: true
+    pub resume: bool,
+    /// Converts downloads when [DownloadConfig::format] asks for it. When
+    /// None, our own install in [HttpDownload::setup] is used if present,
+    /// else the one on PATH.
+    ///
+    /// Default: None
+    pub ffmpeg: Option<PathBuf>,
+    /// Tag downloads with the metadata of their search result
+    ///
+    /// Default: true
+    pub write_tags: bool,
+    /// Measure loudness and write ReplayGain tags, with ffmpeg
+    ///
+    /// Default: true
+    pub replay_gain: bool,
+    /// What to do with the thumbnail of the search result
+    ///
+    /// Default: [CoverArtOptions::default]
+    pub cover_art: CoverArtOptions,
+    /// Remembers downloaded URLs
+    ///
+    /// Default: [DownloadArchive::default]
+    pub archive: DownloadArchive,
+    /// Proxy and rate limits
+    ///
+    /// Default: [NetworkOptions::default]
+    pub network: NetworkOptions,
+    /// Where our own ffmpeg install is looked for
+    ///
+    /// Default: [SetupConfig::default]
+    pub setup: SetupConfig
+}
+
+impl Default for HttpDownload {
+    fn default() -> Self {
+        Self {
+            user_agent: USER_AGENT.to_string(),
+            resume: true,
+            ffmpeg: None,
+            write_tags: true,
+            replay_gain: true,
+            cover_art: CoverArtOptions::default(),
+            archive: DownloadArchive::default(),
+            network: NetworkOptions::default(),
+            setup: SetupConfig::default()
+        }
+    }
+}
+
+impl SelfSetup for HttpDownload {
+    fn setup(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+impl HttpDownload {
+    /// GETs `url` from byte `offset` on, or all of it should it no
+    /// longer match `if_range`, the [validator] of what we have
+    fn get(&self, url: &str, offset: u64, if_range: Option<&str>) -> Result<Response, String> {
+        let client = self.network.client(&self.user_agent)?;
+        let mut request = client.get(url);
+        if offset > 0 {
+            request = request.header(header::RANGE, format!("bytes={offset}-"));
+            if let Some(if_range) = if_range {
+                request = request.header(header::IF_RANGE, if_range);
+            }
+        }
+        request.send().map_err(|err| format!("Cannot fetch {url}: {err}"))
+    }
+
+    pub fn ffmpeg(&self) -> PathBuf {
+        self.ffmpeg.clone()
+            .or_else(|| Some(installed_path(&self.setup, &PinnedRelease::ffmpeg())).filter(|path| path.is_file()))
+            .unwrap_or_else(|| PathBuf::from("ffmpeg"))
+    }
+
+    /// [HttpDownload::fetch]es `url` into [DownloadConfig::local_path],
+    /// then converts and places it as `config` asks. `metadata` describes
+    /// this file when it differs from [DownloadConfig::metadata].
+    /// URLs already in [HttpDownload::archive] are skipped unless
+    /// [DownloadConfig::force] is set.
+    pub fn fetch_into(&self, url: &str, config: &DownloadConfig, metadata: Option<&TrackMetadata>, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+        self.archive.download_once("http", url, &config.local_path, config.force, on_progress,
+            |on_progress| self.fetch_unarchived(url, config, metadata, on_progress))
+    }
+
+    fn fetch_unarchived(&self, url: &str, config: &DownloadConfig, metadata: Option<&TrackMetadata>, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+        let format = &config.format;
+        let mut last = DownloadProgress::phase(DownloadPhase::Fetching);
+        let fetched = self.fetch(url, &config.local_path, &mut |progress| match progress.phase {
+            DownloadPhase::Finished => last = progress,
+            _ => on_progress(progress)
+        })?;
+        let extension = fetched.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        let path = if format.needs_conversion(extension) {
+            on_progress(DownloadProgress { phase: DownloadPhase::PostProcessing, ..last.clone() });
+            format.convert(&self.ffmpeg(), &fetched)?
+        } else {
+            fetched
+        };
+        let metadata = metadata.or(config.metadata.as_ref());
+        if let Some(metadata) = metadata.filter(|_| self.write_tags) {
+            on_progress(DownloadProgress { phase: DownloadPhase::Tagging, ..last.clone() });
+            tagging::tag_or_warn(&path, metadata);
+        }
+        if self.replay_gain {
+            loudness::tag_track_or_warn(&self.ffmpeg(), &path);
+        }
+        let path = template::place(&path, config, metadata)?;
+        if let Some(metadata) = metadata {
+            cover_art::apply_or_warn(&self.network.client(&self.user_agent)?, &path, metadata, &self.ffmpeg(), &self.cover_art);
+        }
+        on_progress(DownloadProgress { phase: DownloadPhase::Finished, ..last });
+        Ok(Downloaded::file(path))
+    }
+
+    /// Fetches `url` into `dir`, returning the path of the written file.
+    /// Existing files are never overwritten; the new one gets a free name
+    /// next to them.
+    pub fn fetch<AnyStr: AsRef<str>, P: AsRef<Path>>(&self, url: AnyStr, dir: P, on_progress: ProgressCallback) -> Result<PathBuf, String> {
+        let url = url.as_ref();
+        let wanted = dir.as_ref().join(file_name_of(url));
+        let part = part_path(&dir, url);
+        // the validator of the remote file the .part file holds the start of
+        let validator_path = part.with_extension("validator");
+        let existing = match std::fs::metadata(&part) {
+            Ok(meta) if self.resume => meta.len(),
+            _ => 0
+        };
+        let stored = std::fs::read_to_string(&validator_path).ok().filter(|stored| !stored.is_empty());
+        log::info!("Downloading {url} into {wanted:?}");
+        let mut resp = self.get(url, existing, stored.as_deref())?;
+
+        // (bytes we keep from the .part file, size of the whole file)
+        let (offset, total) = match resp.status() {
+            StatusCode::PARTIAL_CONTENT => {
+                let range = resp.headers().get(header::CONTENT_RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(content_range);
+                let unchanged = stored.is_none() || stored == validator(&resp);
+                match range {
+                    Some((start, total)) if start == existing && unchanged =>
+                        (existing, total.or_else(|| resp.content_length().map(|len| existing + len))),
+                    _ => {
+                        log::warn!("Cannot resume {part:?}: {url} changed or answered bytes {existing}- with {range:?}; starting over");
+                        resp = self.get(url, 0, None)?;
+                        (0, resp.content_length())
+                    }
+                }
+            },
+            StatusCode::RANGE_NOT_SATISFIABLE => {
+                // the .part file may already hold everything
+                let total = resp.headers().get(header::CONTENT_RANGE)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().strip_prefix("bytes */"))
+                    .and_then(|total| total.parse::<u64>().ok());
+                if total != Some(existing) {
+                    log::warn!("Cannot resume {part:?} ({existing} bytes, remote has {total:?}); starting over");
+                    resp = self.get(url, 0, None)?;
+                    (0, resp.content_length())
+                } else {
+                    let target = finish_part(&part, &validator_path, &wanted)?;
+                    on_progress(DownloadProgress { phase: DownloadPhase::Finished, ..ProgressMeter::new(total).update(existing) });
+                    return Ok(target);
+                }
+            },
+            // a plain 200 means the server ignored the range
+            _ => (0, resp.content_length())
+        };
+        let mut resp = resp.error_for_status().map_err(|err| format!("Cannot fetch {url}: {err}"))?;
+
+        let mut file = if offset > 0 {
+            log::info!("Resuming {part:?} from byte {offset}");
+            OpenOptions::new().append(true).open(&part)
+        } else {
+            std::fs::write(&validator_path, validator(&resp).unwrap_or_default())
+                .map_err(|err| format!("Cannot write {validator_path:?}: {err}"))?;
+            File::create(&part)
+        }.map_err(|err| format!("Cannot open {part:?}: {err}"))?;
+        let meter = ProgressMeter::resuming(total, offset);
+        let mut buf = vec![0u8; 64 * 1024];
+        let mut downloaded = offset;
+        let throttle = self.network.throttle();
+        on_progress(DownloadProgress { file: Some(part.clone()), ..meter.update(downloaded) });
+        loop {
+            // keep what we got so far in .part for the next attempt
+            let read = resp.read(&mut buf).map_err(|err| format!("Download of {url} interrupted at byte {downloaded}: {err}"))?;
+            if read == 0 {
+                break;
+            }
+            file.write_all(&buf[..read]).map_err(|err| format!("Cannot write {part:?}: {err}"))?;
+            downloaded += read as u64;
+            throttle.consume(read as u64);
+            on_progress(DownloadProgress { file: Some(part.clone()), ..meter.update(downloaded) });
+        }
+        file.sync_all().map_err(|err| format!("Cannot write {part:?}: {err}"))?;
+        if let Some(total) = total.filter(|total| *total != downloaded) {
+            return Err(format!("Download of {url} is incomplete: got {downloaded} of {total} bytes"));
+        }
+        let target = finish_part(&part, &validator_path, &wanted)?;
+        on_progress(DownloadProgress { phase: DownloadPhase::Finished, ..meter.update(downloaded) });
+        Ok(target)
+    }
+}
+
+/// Gives a complete `part` file the name it was downloaded for, or a free
+/// one next to it
+fn finish_part(part: &Path, validator_path: &Path, wanted: &Path) -> Result<PathBuf, String> {
+    let target = template::unique_path(wanted);
+    std::fs::rename(part, &target).map_err(|err| format!("Cannot move {part:?} to {target:?}: {err}"))?;
+    let _ = std::fs::remove_file(validator_path);
+    Ok(target)
+}
+
+impl ProvideDownload for HttpDownload {
+    fn download_with_progress(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+        self.fetch_into(&config.uri, &config, None, on_progress)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, atomic::{AtomicBool, Ordering}};
+
+    use crate::{common::test_server::{TestServer, TestResponse}, download_provider::format::{AudioCodec, AudioFormat}};
+
+    use super::*;
+
+    #[test]
+    fn reports_progress() {
+        let body = vec![7u8; 200 * 1024];
+        let server = TestServer::routes(vec![("/big.bin", body.clone())]);
+        let dir = tempfile::tempdir().unwrap();
+        let mut reports = vec![];
+        let path = HttpDownload::default().fetch(server.url("/big.bin"), dir.path(), &mut |p| reports.push(p)).unwrap();
+        assert_eq!(body, std::fs::read(path).unwrap());
+
+        let last = reports.last().unwrap();
+        assert_eq!(DownloadPhase::Finished, last.phase);
+        assert_eq!(body.len() as u64, last.downloaded_bytes);
+        assert_eq!(Some(body.len() as u64), last.total_bytes);
+        assert!(reports.len() > 2);
+        assert!(reports.windows(2).all(|w| w[0].downloaded_bytes <= w[1].downloaded_bytes));
+    }
+
+    /// Serves `body`, honouring `Range: bytes=<start>-`
+    fn ranged(body: Vec<u8>, req: &crate::common::test_server::TestRequest) -> TestResponse {
+        let start = req.headers.get("range")
+            .and_then(|range| range.strip_prefix("bytes=")?.strip_suffix('-')?.parse::<usize>().ok());
+        match start {
+            None => TestResponse::ok(body),
+            Some(start) if start >= body.len() => TestResponse { status: 416, ..TestResponse::ok("") }
+                .header("Content-Range", format!("bytes */{}", body.len())),
+            Some(start) => TestResponse { status: 206, ..TestResponse::ok(body[start..].to_vec()) }
+                .header("Content-Range", format!("bytes {start}-{}/{}", body.len() - 1, body.len()))
+        }
+    }
+
+    #[test]
+    fn goes_through_proxy_at_limited_rate() {
+        // a proxy gets the whole URL in the request line
+        let proxy = TestServer::serve(|req| match req.path.as_str() {
+            "http://music.example.invalid/track.mp3" => TestResponse::ok(vec![7u8; 30_000]),
+            _ => TestResponse::not_found()
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let network = NetworkOptions { proxy: Some(proxy.url("")), rate_limit: Some(100_000), global_rate_limit: None };
+        let http = HttpDownload { network, ..Default::default() };
+        let start = std::time::Instant::now();
+        let path = http.fetch("http://music.example.invalid/track.mp3", dir.path(), &mut |_| {}).unwrap();
+        assert_eq!(30_000, std::fs::metadata(path).unwrap().len());
+        assert!(start.elapsed() >= std::time::Duration::from_millis(250), "{:?}", start.elapsed());
+
+        let http = HttpDownload { network: NetworkOptions { proxy: Some("socks4://proxy:1080".to_string()), ..Default::default() }, ..Default::default() };
+        assert!(http.fetch("http://music.example.invalid/track.mp3", dir.path(), &mut |_| {}).is_err());
+    }
+
+    #[test]
+    fn resumes_part_files() {
+        let body = (0..100_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let served = body.clone();
+        let server = TestServer::serve(move |req| ranged(served.clone(), req));
+        let dir = tempfile::tempdir().unwrap();
+        let part = part_path(dir.path(), &server.url("/track.mp3"));
+        std::fs::write(&part, &body[..40_000]).unwrap();
+
+        let mut first = None;
+        let path = HttpDownload::default().fetch(server.url("/track.mp3"), dir.path(), &mut |p| { first.get_or_insert(p); }).unwrap();
+        assert_eq!(40_000, first.unwrap().downloaded_bytes);
+        assert_eq!(body, std::fs::read(&path).unwrap());
+        assert!(!part.exists());
+
+        // a complete .part only needs renaming
+        std::fs::rename(&path, &part).unwrap();
+        let http = HttpDownload { archive: DownloadArchive::new(dir.path().join("archive.json")), ..Default::default() };
+        let provider: crate::download_provider::DownloadProviders = http.into();
+        let config = DownloadConfig::new(server.url("/track.mp3"), dir.path());
+        assert_eq!(Ok(Downloaded::file(path.clone())), provider.download(config));
+        assert_eq!(body, std::fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn starts_over_when_the_remote_file_changed() {
+        let version = Arc::new(std::sync::Mutex::new(("\"v1\"", vec![1u8; 1000])));
+        let served = version.clone();
+        let server = TestServer::serve(move |req| {
+            let (etag, body) = served.lock().unwrap().clone();
+            match req.path.as_str() {
+                "/first/audio.mp3" => ranged(body, req).header("ETag", etag),
+                _ => TestResponse::ok("other show")
+            }
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let part = part_path(dir.path(), &server.url("/first/audio.mp3"));
+        let http = HttpDownload::default();
+        http.fetch(server.url("/first/audio.mp3"), dir.path(), &mut |_| {}).unwrap();
+        // pretend the first attempt got cut off
+        let path = dir.path().join("audio.mp3");
+        std::fs::write(&part, vec![1u8; 400]).unwrap();
+        std::fs::write(part.with_extension("validator"), "\"v1\"").unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        *version.lock().unwrap() = ("\"v2\"", vec![2u8; 1000]);
+        let mut first = None;
+        let path = http.fetch(server.url("/first/audio.mp3"), dir.path(), &mut |p| { first.get_or_insert(p); }).unwrap();
+        assert_eq!(0, first.unwrap().downloaded_bytes);
+        assert_eq!(vec![2u8; 1000], std::fs::read(&path).unwrap());
+        assert!(!part.with_extension("validator").exists());
+
+        // another URL with the same file name gets a name of its own
+        let other = http.fetch(server.url("/second/audio.mp3"), dir.path(), &mut |_| {}).unwrap();
+        assert_eq!(dir.path().join("audio (2).mp3"), other);
+        assert_eq!(vec![2u8; 1000], std::fs::read(&path).unwrap());
+    }
+
+    #[test]
+    fn converts_after_fetching() {
+        let server = TestServer::routes(vec![("/show.mp3", b"mp3 bytes".to_vec())]);
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = crate::common::fake_program::fake_program(dir.path(), "ffmpeg",
+            r#"for arg in "$@"; do last="$arg"; done; echo "$*" > "$last""#);
+        let archive = DownloadArchive::new(dir.path().join("archive.json"));
+        let http = HttpDownload { ffmpeg: Some(ffmpeg), archive, replay_gain: false, ..Default::default() };
+        let format = AudioFormat { bitrate_kbps: Some(96), ..AudioFormat::new(AudioCodec::Opus) };
+        let config = DownloadConfig { format, ..DownloadConfig::new(server.url("/show.mp3"), dir.path()) };
+        let mut phases = vec![];
+        let downloaded = http.download_with_progress(config, &mut |p| phases.push(p.phase)).unwrap();
+        let path = dir.path().join("show.opus");
+        assert_eq!(Downloaded::file(path.clone()), downloaded);
+        let args = std::fs::read_to_string(path).unwrap();
+        assert!(args.contains("-c:a libopus -b:a 96k"), "{args}");
+        assert!(!dir.path().join("show.mp3").exists());
+        assert_eq!(Some(&DownloadPhase::PostProcessing), phases.iter().rev().nth(1));
+        assert_eq!(Some(&DownloadPhase::Finished), phases.last());
+
+        // without one configured, our own install is found where setup put it
+        let setup = SetupConfig { exec_path: dir.path().to_path_buf(), ..Default::default() };
+        assert_eq!(dir.path().join("ffmpeg"), HttpDownload { setup, ..Default::default() }.ffmpeg());
+    }
+
+    #[test]
+    fn rejects_truncated_bodies() {
+        let truncate = Arc::new(AtomicBool::new(true));
+        let flag = truncate.clone();
+        let server = TestServer::serve(move |req| {
+            let body = vec![1u8; 1000];
+            if flag.load(Ordering::SeqCst) {
+                // promises more than it sends
+                TestResponse::ok(body[..600].to_vec()).header("Content-Length", "1000")
+            } else {
+                ranged(body, req)
+            }
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let http = HttpDownload::default();
+        let err = http.fetch(server.url("/short.ogg"), dir.path(), &mut |_| {}).unwrap_err();
+        assert!(err.contains("short.ogg"), "{err}");
+        assert!(!dir.path().join("short.ogg").exists());
+        assert_eq!(600, std::fs::metadata(part_path(dir.path(), &server.url("/short.ogg"))).unwrap().len());
+
+        truncate.store(false, Ordering::SeqCst);
+        let path = http.fetch(server.url("/short.ogg"), dir.path(), &mut |_| {}).unwrap();
+        assert_eq!(vec![1u8; 1000], std::fs::read(path).unwrap());
+    }
+}
@@ -0,0 +1,182 @@
+//! The audio format downloads should end up in, so files are consistent
+//! whichever source they came from.
+
+use std::{path::{Path, PathBuf}, process::Command};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum AudioCodec {
+    Opus,
+    /// AAC in an MP4 container
+    M4a,
+    Mp3,
+    Flac
+}
+
+impl AudioCodec {
+    /// File extension, which is also what yt-dlp's `--audio-format` calls it
+    pub fn extension(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "opus",
+            AudioCodec::M4a => "m4a",
+            AudioCodec::Mp3 => "mp3",
+            AudioCodec::Flac => "flac"
+        }
+    }
+
+    /// The ffmpeg encoder producing this codec
+    pub fn encoder(&self) -> &'static str {
+        match self {
+            AudioCodec::Opus => "libopus",
+            AudioCodec::M4a => "aac",
+            AudioCodec::Mp3 => "libmp3lame",
+            AudioCodec::Flac => "flac"
+        }
+    }
+
+    pub fn is_lossless(&self) -> bool {
+        matches!(self, AudioCodec::Flac)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct AudioFormat {
+    /// What to convert to. When None, the file is kept as the source
+    /// serves it.
+    ///
+    /// Default: None
+    pub codec: Option<AudioCodec>,
+    /// Target bitrate of lossy codecs, in kbit/s. When None, the encoder
+    /// picks its default quality.
+    ///
+    /// Default: None
+    pub bitrate_kbps: Option<u32>,
+    /// Keep the downloaded file next to the converted one
+    ///
+    /// Default: false
+    pub keep_original: bool
+}
+
+impl AudioFormat {
+    pub fn new(codec: AudioCodec) -> Self {
+        Self { codec: Some(codec), ..Default::default() }
+    }
+
+    /// Whether a file with this extension has to go through ffmpeg
+    /// ```
+    /// use cli_music_player::download_provider::format::{AudioCodec, AudioFormat};
+    ///
+    /// assert!(!AudioFormat::default().needs_conversion("webm"));
+    /// assert!(AudioFormat::new(AudioCodec::Opus).needs_conversion("webm"));
+    /// assert!(!AudioFormat::new(AudioCodec::Mp3).needs_conversion("MP3"));
+    /// let smaller = AudioFormat { bitrate_kbps: Some(96), ..AudioFormat::new(AudioCodec::Mp3) };
+    /// assert!(smaller.needs_conversion("mp3"));
+    /// ```
+    pub fn needs_conversion(&self, extension: &str) -> bool {
+        match self.codec {
+            None => false,
+            Some(codec) => !codec.extension().eq_ignore_ascii_case(extension)
+                || (self.bitrate_kbps.is_some() && !codec.is_lossless())
+        }
+    }
+
+    /// yt-dlp flags selecting and converting the audio
+    pub fn yt_dlp_args(&self) -> Vec<String> {
+        let Some(codec) = self.codec else {
+            return vec![];
+        };
+        let mut args = vec!["-f".to_string(), "bestaudio/best".to_string(),
+            "--extract-audio".to_string(), "--audio-format".to_string(), codec.extension().to_string()];
+        if let Some(bitrate) = self.bitrate_kbps.filter(|_| !codec.is_lossless()) {
+            args.extend(["--audio-quality".to_string(), format!("{bitrate}K")]);
+        }
+        if self.keep_original {
+            args.push("--keep-video".to_string());
+        }
+        args
+    }
+
+    /// Converts `input` with ffmpeg if needed, returning the path of the
+    /// file in the target format. Unless [AudioFormat::keep_original],
+    /// `input` is removed after a successful conversion.
+    pub fn convert(&self, ffmpeg: &Path, input: &Path) -> Result<PathBuf, String> {
+        let extension = input.extension().and_then(|ext| ext.to_str()).unwrap_or_default();
+        let codec = match self.codec {
+            Some(codec) if self.needs_conversion(extension) => codec,
+            _ => return Ok(input.to_path_buf())
+        };
+        let output = input.with_extension(codec.extension());
+        // re-encoding into the same extension must not overwrite the input while reading it
+        let staging = input.with_extension(format!("converting.{}", codec.extension()));
+        let mut command = Command::new(ffmpeg);
+        command.args(["-hide_banner", "-loglevel", "error", "-y", "-i"]).arg(input)
+            .args(["-vn", "-map_metadata", "0", "-c:a", codec.encoder()]);
+        if let Some(bitrate) = self.bitrate_kbps.filter(|_| !codec.is_lossless()) {
+            command.arg("-b:a").arg(format!("{bitrate}k"));
+        }
+        command.arg(&staging);
+        log::info!("Running {command:?}");
+        let result = command.output().map_err(|err| format!("Cannot run {ffmpeg:?}: {err}"))?;
+        if !result.status.success() {
+            let _ = std::fs::remove_file(&staging);
+            return Err(format!("ffmpeg could not convert {input:?} to {}: {}",
+                codec.extension(), String::from_utf8_lossy(&result.stderr).trim()));
+        }
+        if output == input {
+            if self.keep_original {
+                let original = input.with_extension(format!("original.{extension}"));
+                std::fs::rename(input, &original).map_err(|err| format!("Cannot move {input:?} to {original:?}: {err}"))?;
+            }
+        } else if !self.keep_original {
+            std::fs::remove_file(input).map_err(|err| format!("Cannot remove {input:?}: {err}"))?;
+        }
+        std::fs::rename(&staging, &output).map_err(|err| format!("Cannot move {staging:?} to {output:?}: {err}"))?;
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::fake_program::fake_program;
+
+    use super::*;
+
+    /// Copies the input to the last argument and records the arguments
+    const FAKE_FFMPEG: &str = r#"
+for arg in "$@"; do last="$arg"; done
+while [ "$#" -gt 0 ]; do
+    case "$1" in -i) input="$2"; shift;; esac
+    shift
+done
+case "$input" in *broken*) echo "Invalid data found" >&2; exit 1;; esac
+cp "$input" "$last"
+echo "$last" >> "$(dirname "$last")/ffmpeg.log"
+"#;
+
+    #[test]
+    fn converts_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", FAKE_FFMPEG);
+        let input = dir.path().join("song.webm");
+        std::fs::write(&input, "audio").unwrap();
+
+        assert_eq!(Ok(input.clone()), AudioFormat::default().convert(&ffmpeg, &input));
+        let output = AudioFormat::new(AudioCodec::Opus).convert(&ffmpeg, &input).unwrap();
+        assert_eq!(dir.path().join("song.opus"), output);
+        assert!(!input.exists());
+
+        let kept = AudioFormat { bitrate_kbps: Some(64), keep_original: true, ..AudioFormat::new(AudioCodec::Opus) };
+        assert_eq!(Ok(output.clone()), kept.convert(&ffmpeg, &output));
+        assert!(dir.path().join("song.original.opus").is_file());
+        assert!(!dir.path().join("song.converting.opus").exists());
+
+        let broken = dir.path().join("broken.webm");
+        std::fs::write(&broken, "noise").unwrap();
+        let err = AudioFormat::new(AudioCodec::Mp3).convert(&ffmpeg, &broken).unwrap_err();
+        assert!(err.contains("Invalid data found"), "{err}");
+        assert!(broken.exists());
+    }
+}
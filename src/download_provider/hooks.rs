@@ -0,0 +1,236 @@
+//! Runs user commands after downloads and batches, e.g. to sync into a
+//! NAS or regenerate playlists. Hooks hear about what was downloaded
+//! through `CMP_*` environment variables and, if they like, JSON on stdin.
+
+use std::{fmt::Display, io::{Read, Write}, path::PathBuf, process::{Command, Stdio}, thread::JoinHandle, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::common::metadata::TrackMetadata;
+use super::batch::{BatchOutcome, BatchReport};
+
+/// How often a running hook is checked on
+const POLL_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HookEvent {
+    /// After every successful download
+    Download,
+    /// After every batch, whatever became of its entries
+    Batch
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct HookCommand {
+    /// The program and its arguments. No shell is involved; use
+    /// `["sh", "-c", "..."]` for one.
+    ///
+    /// Default: []
+    pub command: Vec<String>,
+    /// Default: [HookEvent::Download]
+    pub on: HookEvent,
+    /// Write the [HookPayload] as JSON to the command's stdin, on top of
+    /// the environment variables
+    ///
+    /// Default: true
+    pub stdin_json: bool,
+    /// The command is killed, and counted as failed, after this long
+    ///
+    /// Default: 60
+    pub timeout_secs: u64
+}
+
+impl Default for HookCommand {
+    fn default() -> Self {
+        Self { command: vec![], on: HookEvent::Download, stdin_json: true, timeout_secs: 60 }
+    }
+}
+
+impl Display for HookCommand {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.command.join(" "))
+    }
+}
+
+/// What a hook is told about
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "event", rename_all = "lowercase")]
+pub enum HookPayload {
+    Download { uri: String, files: Vec<PathBuf>, metadata: Option<TrackMetadata> },
+    Batch { report: BatchReport }
+}
+
+/// `paths`, one per line
+fn lines(paths: &[PathBuf]) -> String {
+    paths.iter().map(|path| path.to_string_lossy()).collect::<Vec<_>>().join("\n")
+}
+
+impl HookPayload {
+    pub fn event(&self) -> HookEvent {
+        match self {
+            Self::Download { .. } => HookEvent::Download,
+            Self::Batch { .. } => HookEvent::Batch
+        }
+    }
+
+    /// The environment variables hooks get. `CMP_PATH` is the first file
+    /// downloaded, `CMP_PATHS` all of them, one per line.
+    /// ```
+    /// use std::path::PathBuf;
+    /// use cli_music_player::{common::metadata::TrackMetadata, download_provider::hooks::HookPayload};
+    ///
+    /// let payload = HookPayload::Download {
+    ///     uri: "https://youtu.be/abc".to_string(),
+    ///     files: vec![PathBuf::from("/music/Money.opus")],
+    ///     metadata: Some(TrackMetadata { title: Some("Money".to_string()), track_number: Some(6), ..Default::default() })
+    /// };
+    /// let env = payload.env();
+    /// assert!(env.contains(&("CMP_EVENT".to_string(), "download".to_string())));
+    /// assert!(env.contains(&("CMP_PATH".to_string(), "/music/Money.opus".to_string())));
+    /// assert!(env.contains(&("CMP_PATHS".to_string(), "/music/Money.opus".to_string())));
+    /// assert!(env.contains(&("CMP_TRACK_NUMBER".to_string(), "6".to_string())));
+    /// assert!(!env.iter().any(|(key, _)| key == "CMP_ARTIST"));
+    /// ```
+    pub fn env(&self) -> Vec<(String, String)> {
+        let mut env = vec![("CMP_EVENT", match self.event() {
+            HookEvent::Download => "download".to_string(),
+            HookEvent::Batch => "batch".to_string()
+        })];
+        match self {
+            Self::Download { uri, files, metadata } => {
+                env.push(("CMP_URI", uri.clone()));
+                env.push(("CMP_PATH", files.first().map(|path| path.to_string_lossy().to_string()).unwrap_or_default()));
+                env.push(("CMP_PATHS", lines(files)));
+                let metadata = metadata.clone().unwrap_or_default();
+                let fields = [
+                    ("CMP_TITLE", metadata.title),
+                    ("CMP_ARTIST", metadata.artist),
+                    ("CMP_ALBUM", metadata.album),
+                    ("CMP_TRACK_NUMBER", metadata.track_number.map(|number| number.to_string())),
+                    ("CMP_YEAR", metadata.year.map(|year| year.to_string()))
+                ];
+                env.extend(fields.into_iter().filter_map(|(key, value)| Some((key, value?))));
+            },
+            Self::Batch { report } => {
+                env.push(("CMP_SUCCEEDED", report.summary.succeeded.to_string()));
+                env.push(("CMP_SKIPPED", report.summary.skipped.to_string()));
+                env.push(("CMP_FAILED", report.summary.failed.to_string()));
+                env.push(("CMP_UNAVAILABLE", report.summary.unavailable.to_string()));
+                let paths = report.entries.iter()
+                    .flat_map(|entry| match &entry.outcome {
+                        BatchOutcome::Succeeded { files } => files.clone(),
+                        _ => vec![]
+                    })
+                    .collect::<Vec<_>>();
+                env.push(("CMP_PATHS", lines(&paths)));
+            }
+        }
+        env.into_iter().map(|(key, value)| (key.to_string(), value)).collect()
+    }
+}
+
+/// A hook that did not go well
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HookFailure {
+    pub command: String,
+    pub reason: String
+}
+
+fn read_all<R: Read + Send + 'static>(mut reader: R) -> JoinHandle<String> {
+    std::thread::spawn(move || {
+        let mut output = String::new();
+        let _ = reader.read_to_string(&mut output);
+        output
+    })
+}
+
+impl HookCommand {
+    /// Runs the command with `payload`, waiting at most
+    /// [HookCommand::timeout_secs] for it
+    pub fn run(&self, payload: &HookPayload) -> Result<(), String> {
+        let (program, args) = self.command.split_first().ok_or_else(|| "Hook has no command".to_string())?;
+        let mut command = Command::new(program);
+        command.args(args)
+            .envs(payload.env())
+            .stdin(if self.stdin_json { Stdio::piped() } else { Stdio::null() })
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+        log::info!("Running hook {command:?}");
+        let mut child = command.spawn().map_err(|err| format!("Cannot run {program:?}: {err}"))?;
+        if let Some(mut stdin) = child.stdin.take() {
+            let json = serde_json::to_vec(payload).expect("payloads serialize");
+            // hooks need not read it all, and may not read before we wait
+            std::thread::spawn(move || stdin.write_all(&json));
+        }
+        let stdout = read_all(child.stdout.take().expect("stdout is piped"));
+        let stderr = read_all(child.stderr.take().expect("stderr is piped"));
+        let deadline = Instant::now() + Duration::from_secs(self.timeout_secs);
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|err| format!("Cannot wait for {program:?}: {err}"))? {
+                break status;
+            }
+            if Instant::now() >= deadline {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(format!("Timed out after {}s", self.timeout_secs));
+            }
+            std::thread::sleep(POLL_INTERVAL);
+        };
+        let stdout = stdout.join().unwrap_or_default();
+        if !stdout.trim().is_empty() {
+            log::info!("Hook {self}: {}", stdout.trim());
+        }
+        let stderr = stderr.join().unwrap_or_default();
+        if !status.success() {
+            return Err(format!("Exited with {status}: {}", stderr.trim().lines().last().unwrap_or_default()));
+        }
+        Ok(())
+    }
+}
+
+/// Runs the `hooks` that are for `payload`'s event, one after the other,
+/// and reports the ones that failed
+pub fn run_hooks(hooks: &[HookCommand], payload: &HookPayload) -> Vec<HookFailure> {
+    hooks.iter()
+        .filter(|hook| hook.on == payload.event())
+        .filter_map(|hook| hook.run(payload).err().map(|reason| {
+            log::warn!("Hook {hook} failed: {reason}");
+            HookFailure { command: hook.to_string(), reason }
+        }))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn sh(script: &str) -> HookCommand {
+        HookCommand { command: vec!["sh".to_string(), "-c".to_string(), script.to_string()], ..Default::default() }
+    }
+
+    #[test]
+    fn runs_hooks_with_env_and_stdin() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let payload = HookPayload::Download { uri: "https://youtu.be/abc".to_string(), files: vec![dir.path().join("abc.m4a")], metadata: None };
+        let hooks = vec![
+            sh(&format!(r#"echo "$CMP_EVENT $CMP_URI" > {out:?}; cat >> {out:?}"#)),
+            HookCommand { on: HookEvent::Batch, ..sh("exit 1") },
+            sh("echo 'NAS unreachable' >&2; exit 3"),
+            HookCommand { timeout_secs: 0, ..sh("sleep 5") }
+        ];
+        let failures = run_hooks(&hooks, &payload);
+
+        let written = std::fs::read_to_string(&out).unwrap();
+        let (env, json) = written.split_once('\n').unwrap();
+        assert_eq!("download https://youtu.be/abc", env);
+        assert_eq!(payload, serde_json::from_str(json).unwrap());
+        assert_eq!(2, failures.len());
+        assert!(failures[0].reason.contains("NAS unreachable"), "{:?}", failures[0]);
+        assert!(failures[1].reason.contains("Timed out"), "{:?}", failures[1]);
+        assert_eq!(vec![HookFailure { command: String::new(), reason: "Hook has no command".to_string() }],
+            run_hooks(&[HookCommand::default()], &payload));
+    }
+}
@@ -1,49 +1,166 @@
-use std::{path::{PathBuf}, str::FromStr};
+use std::{fs::OpenOptions, io::ErrorKind, path::{Path, PathBuf}, str::FromStr, sync::atomic::{AtomicU64, Ordering}};
 
-use directories::ProjectDirs;
 use enum_dispatch::enum_dispatch;
-use serde::{Deserialize, Serialize};
+use reqwest::Url;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use serde_json::{Map, Value};
 
-use crate::common::{self_setup::SelfSetup, factory::Factory, config::project_dirs};
+use crate::common::{self_setup::SelfSetup, factory::{Factory, TryFactory, FactoryError, did_you_mean, forward_factory, default_factory, enum_dispatch_specialize}, config::project_dirs, metadata::TrackMetadata};
 
-use super::youtube_dl::YoutubeDL;
+use crate::search_provider::{podcast_rss::PodcastRss, internet_archive::InternetArchive};
 
-#[derive(Serialize, Deserialize)]
+use super::{format::AudioFormat, template, youtube_dl::YoutubeDL, direct::HttpDownload, progress::ProgressCallback};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct DownloadConfig {
     pub uri: String,
-    pub local_path: PathBuf
-}
-
-// TODO: Implement macros:
-// #[default_factory(local_path=".")]
-// struct DownloadConfigDefault;
-// #[forward_factory]
-// struct DownloadConfigForward;
-// #[enum_dispatch_specialize(Factory<DownloadConfig>)]
-// pub trait DownloadConfigFactory;
-
-// TODO: this should become a macro.
-// In retrospect, this seems like a good opportunity to inject validation
-// For example: put in a pattern to check the string is in valid format
-// or try to recommend the well-formed input.
-#[derive(Default)]
-pub struct DownloadConfigForward;
-impl DownloadConfigForward {
-    /// Creates an instance of [DownloadConfigForward]
+    pub local_path: PathBuf,
+    /// What the search provider knew about this media, if anything
+    #[serde(default)]
+    pub metadata: Option<TrackMetadata>,
+    /// What the downloaded file should be converted to
+    #[serde(default)]
+    pub format: AudioFormat,
+    /// Where inside [DownloadConfig::local_path] the file should land,
+    /// see [super::template]. When None, the provider picks the name.
+    #[serde(default)]
+    pub filename_template: Option<String>,
+    /// Download even if the download archive says we already have it
+    #[serde(default)]
+    pub force: bool
+}
+
+impl DownloadConfig {
+    /// Downloads `uri` into `local_path`, everything else left at its
+    /// default
     /// ```
-    /// use cli_music_player::download_provider::DownloadConfigForward;
-    /// let forward_factory = DownloadConfigForward::new(); 
+    /// use cli_music_player::download_provider::DownloadConfig;
+    ///
+    /// let config = DownloadConfig { force: true, ..DownloadConfig::new("https://cdn.example/a.mp3", "music") };
+    /// assert_eq!(None, config.filename_template);
     /// ```
-    pub fn new()->Self {DownloadConfigForward{}}
+    pub fn new<S: AsRef<str>, P: AsRef<Path>>(uri: S, local_path: P) -> Self {
+        DownloadConfig {
+            uri: uri.as_ref().to_string(),
+            local_path: local_path.as_ref().to_path_buf(),
+            metadata: None,
+            format: AudioFormat::default(),
+            filename_template: None,
+            force: false
+        }
+    }
 }
 
-impl Factory<DownloadConfig> for DownloadConfigForward {
-    /// Generates by forwarding the declaration of a json object
-    fn generate(&self, args: serde_json::Value) -> DownloadConfig {
-        serde_json::from_value(args).unwrap()
+/// The fields a [DownloadConfig] is declared with
+const DOWNLOAD_CONFIG_FIELDS: [&str; 6] = ["uri", "local_path", "metadata", "format", "filename_template", "force"];
+
+/// `args` as an object, rejecting fields other than `known`
+fn object<'a>(args: &'a Value, known: &[&str]) -> Result<&'a Map<String, Value>, FactoryError> {
+    let map = args.as_object().ok_or_else(|| FactoryError::NotAnObject { got: args.to_string() })?;
+    match map.keys().find(|key| !known.contains(&key.as_str())) {
+        Some(field) => Err(FactoryError::UnknownField {
+            field: field.clone(),
+            did_you_mean: did_you_mean(field, known.iter().copied()).map(str::to_string)
+        }),
+        None => Ok(map)
+    }
+}
+
+/// The field `name` of `map`, None when absent or null
+fn field<T: DeserializeOwned>(map: &Map<String, Value>, name: &str) -> Result<Option<T>, FactoryError> {
+    map.get(name)
+        .filter(|value| !value.is_null())
+        .map(|value| serde_json::from_value(value.clone())
+            .map_err(|err| FactoryError::InvalidField { field: name.to_string(), message: err.to_string() }))
+        .transpose()
+}
+
+fn required<T: DeserializeOwned>(map: &Map<String, Value>, name: &str) -> Result<T, FactoryError> {
+    field(map, name)?.ok_or_else(|| FactoryError::MissingField { field: name.to_string() })
+}
+
+fn required_uri(map: &Map<String, Value>) -> Result<String, FactoryError> {
+    let uri: String = required(map, "uri")?;
+    match uri.trim() {
+        "" => Err(FactoryError::InvalidField { field: "uri".to_string(), message: "must not be empty".to_string() }),
+        trimmed => Ok(trimmed.to_string())
     }
 }
 
+/// Makes sure downloads can be written into `dir`, creating it if needed
+pub fn check_writable(dir: &Path) -> Result<(), FactoryError> {
+    let unwritable = |message: String| FactoryError::Unwritable { path: dir.to_path_buf(), message };
+    if dir.exists() && !dir.is_dir() {
+        return Err(unwritable("not a directory".to_string()));
+    }
+    std::fs::create_dir_all(dir).map_err(|err| unwritable(err.to_string()))?;
+    // permissions alone do not tell, e.g. on read-only mounts. Probes are
+    // named uniquely so parallel checks never remove each other's.
+    static PROBES: AtomicU64 = AtomicU64::new(0);
+    loop {
+        let probe = dir.join(format!(".cli-music-player-write-test-{}-{}", std::process::id(), PROBES.fetch_add(1, Ordering::Relaxed)));
+        match OpenOptions::new().write(true).create_new(true).open(&probe) {
+            Ok(_) => return std::fs::remove_file(&probe).map_err(|err| unwritable(err.to_string())),
+            // left over by a process that had our id before
+            Err(err) if err.kind() == ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(unwritable(err.to_string()))
+        }
+    }
+}
+
+/// Accepts absolute http(s) URLs, which is what most providers download.
+/// URLs missing their scheme, or with a misspelled one, get a suggestion.
+/// ```
+/// use cli_music_player::{common::factory::FactoryError, download_provider::check_http_uri};
+///
+/// assert!(check_http_uri("https://cdn.example/a.mp3").is_ok());
+/// let suggestion = |uri| match check_http_uri(uri) {
+///     Err(FactoryError::InvalidUri { did_you_mean, .. }) => did_you_mean,
+///     other => panic!("{other:?}")
+/// };
+/// assert_eq!(Some("https://cdn.example/a.mp3".to_string()), suggestion("cdn.example/a.mp3"));
+/// assert_eq!(Some("https://cdn.example/a.mp3".to_string()), suggestion("htps://cdn.example/a.mp3"));
+/// assert_eq!(None, suggestion("ftp://cdn.example/a.mp3"));
+/// ```
+pub fn check_http_uri(uri: &str) -> Result<(), FactoryError> {
+    let invalid = |message: String, did_you_mean: Option<String>| FactoryError::InvalidUri { uri: uri.to_string(), message, did_you_mean };
+    match Url::parse(uri) {
+        Ok(url) if matches!(url.scheme(), "http" | "https") && url.host_str().is_some() => Ok(()),
+        Ok(url) => {
+            let suggestion = did_you_mean(url.scheme(), ["http", "https"])
+                .map(|scheme| format!("{scheme}{}", &uri[url.scheme().len()..]));
+            Err(invalid(format!("expected an http(s) URL, not {}:", url.scheme()), suggestion))
+        },
+        Err(_) => {
+            let suggestion = format!("https://{uri}");
+            let has_domain = Url::parse(&suggestion).ok()
+                .and_then(|url| url.host_str().map(|host| host.contains('.')))
+                .unwrap_or(false);
+            Err(invalid("expected an http(s) URL".to_string(), has_domain.then_some(suggestion)))
+        }
+    }
+}
+
+/// Generates a [DownloadConfig] from its own declaration
+/// ```
+/// use cli_music_player::download_provider::DownloadConfigForward;
+/// let forward_factory = DownloadConfigForward::new();
+/// ```
+#[forward_factory]
+pub struct DownloadConfigForward;
+
+/// Generates a [DownloadConfig] downloading into the working directory
+/// unless declared otherwise
+/// ```
+/// use std::path::PathBuf;
+/// use cli_music_player::{common::factory::Factory, download_provider::DownloadConfigDefault};
+///
+/// let config = DownloadConfigDefault::new().generate(serde_json::json!({ "uri": "https://cdn.example/a.mp3" }));
+/// assert_eq!(PathBuf::from("."), config.local_path);
+/// ```
+#[default_factory(local_path = ".")]
+pub struct DownloadConfigDefault;
+
 pub struct DownloadConfigFromURI {
     local_path: PathBuf
 }
@@ -53,14 +170,15 @@ impl DownloadConfigFromURI {
         DownloadConfigFromURI{local_path}
     }
     pub fn new(local_path: PathBuf)->Self {
-        local_path.is_dir().then_some(local_path).and_then(|path| Some(Self::from_dir(path)))
+        local_path.is_dir().then_some(local_path).map(Self::from_dir)
             .unwrap_or_default()
     }
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str<AnyStr: AsRef<str>>(local_path: AnyStr)->Result<Self, String> {
         // validate whether local_path is valid path
         let path_str = local_path.as_ref();
         let local = PathBuf::from_str(path_str);
-        local.and_then(|path| Ok(Self::new(path)))
+        local.map(Self::new)
             .map_err(|except| format!("Error doing PathBuf::from_str on {path_str:?}: {except:?}"))
     }
 }
@@ -78,35 +196,176 @@ impl Default for DownloadConfigFromURI {
     }
 }
 impl Factory<DownloadConfig> for DownloadConfigFromURI {
-    /// Generates a download config from a specified URI
-    /// 
+    /// Generates a download config from a specified URI, see
+    /// [TryFactory::try_generate] for what is accepted
     fn generate(&self, args: serde_json::Value) -> DownloadConfig {
-        let map = args.as_object().unwrap();
-        let uri = map.get("uri").and_then(|uri| uri.as_str());
-        DownloadConfig {
-            uri: uri.unwrap().to_string(),
-            local_path: self.local_path.clone()
+        self.try_generate(args).unwrap_or_else(|err| panic!("{err}"))
+    }
+}
+
+impl TryFactory<DownloadConfig> for DownloadConfigForward {
+    /// Checks the declaration field by field, and that
+    /// [DownloadConfig::local_path] is writable
+    fn try_generate(&self, args: Value) -> Result<DownloadConfig, FactoryError> {
+        let map = object(&args, &DOWNLOAD_CONFIG_FIELDS)?;
+        let config = DownloadConfig {
+            uri: required_uri(map)?,
+            local_path: required(map, "local_path")?,
+            metadata: field(map, "metadata")?,
+            format: field(map, "format")?.unwrap_or_default(),
+            filename_template: field(map, "filename_template")?,
+            force: field(map, "force")?.unwrap_or_default()
+        };
+        if let Some(filename_template) = &config.filename_template {
+            template::render(filename_template, &TrackMetadata::default(), "ext")
+                .map_err(|message| FactoryError::InvalidField { field: "filename_template".to_string(), message })?;
         }
+        check_writable(&config.local_path)?;
+        Ok(config)
+    }
+}
+
+impl TryFactory<DownloadConfig> for DownloadConfigFromURI {
+    /// Takes `{"uri": ...}` only
+    fn try_generate(&self, args: Value) -> Result<DownloadConfig, FactoryError> {
+        let map = object(&args, &["uri"])?;
+        let uri = required_uri(map)?;
+        check_writable(&self.local_path)?;
+        Ok(DownloadConfig::new(uri, &self.local_path))
     }
 }
 
 // NOTE: we need to specialize this; otherwise, enum_dispatch
 // thinks Factory<DownloadConfig> as a generic pass-through.
-pub trait DownloadConfigFactory: Factory<DownloadConfig> {}
-impl <T: Factory<DownloadConfig>>DownloadConfigFactory for T {}
+#[enum_dispatch_specialize(Factory<DownloadConfig>)]
+pub trait DownloadConfigFactory {}
 
 #[enum_dispatch(DownloadConfigFactory)]
 pub enum DownloadConfigFactoryEnum {
-    DownloadConfigForward
+    DownloadConfigForward,
+    DownloadConfigDefault
+}
+
+#[enum_dispatch_specialize(TryFactory<DownloadConfig>)]
+pub trait DownloadConfigTryFactory {
+    /// [TryFactory::try_generate], also checking that `provider` can make
+    /// sense of [DownloadConfig::uri]
+    fn try_generate_for<P: ProvideDownload>(&self, args: Value, provider: &P) -> Result<DownloadConfig, FactoryError> {
+        let config = self.try_generate(args)?;
+        provider.check_uri(&config.uri)?;
+        Ok(config)
+    }
+}
+
+/// What a download left behind
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Downloaded {
+    /// Every file written, in order: one for most downloads, one per
+    /// track for whole archive.org items and uploads split by chapters
+    pub files: Vec<PathBuf>,
+    /// Whether the download archive had all of it already, so nothing
+    /// was fetched
+    #[serde(default)]
+    pub skipped: bool
+}
+
+impl Downloaded {
+    pub fn file(path: PathBuf) -> Self {
+        Self { files: vec![path], skipped: false }
+    }
+
+    /// The first file, which is all there is for most downloads
+    pub fn path(&self) -> Option<&Path> {
+        self.files.first().map(PathBuf::as_path)
+    }
 }
 
 #[enum_dispatch]
 pub trait ProvideDownload where Self: SelfSetup {
-    /// Downloads based on the given config
-    fn download(&self, config: DownloadConfig) -> Result<(), String>;
+    /// Rejects URIs this provider cannot download, before anything is
+    /// queued. By default, any http(s) URL goes.
+    fn check_uri(&self, uri: &str) -> Result<(), FactoryError> {
+        check_http_uri(uri)
+    }
+    /// Downloads based on the given config, returning where the
+    /// downloaded files ended up
+    fn download(&self, config: DownloadConfig) -> Result<Downloaded, String> {
+        self.download_with_progress(config, &mut |_| {})
+    }
+    /// Same as [ProvideDownload::download], reporting progress along the way.
+    /// The last report of a successful download is [super::progress::DownloadPhase::Finished].
+    fn download_with_progress(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String>;
 }
 
+// providers are few and long-lived, so their size does not matter
+#[allow(clippy::large_enum_variant)]
 #[enum_dispatch(SelfSetup, ProvideDownload)]
 pub enum DownloadProviders {
-    YoutubeDL
-}
\ No newline at end of file
+    YoutubeDL,
+    HttpDownload,
+    PodcastRss,
+    InternetArchive
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use crate::download_provider::youtube_dl::YoutubeDL;
+
+    use super::*;
+
+    #[test]
+    fn rejects_bad_declarations_with_hints() {
+        let dir = tempfile::tempdir().unwrap();
+        let local_path = dir.path().join("music");
+        let factory = DownloadConfigForward::new();
+
+        assert_eq!(Err(FactoryError::UnknownField { field: "url".to_string(), did_you_mean: Some("uri".to_string()) }),
+            factory.try_generate(json!({ "url": "https://cdn.example/a.mp3", "local_path": local_path })));
+        assert_eq!(Err(FactoryError::MissingField { field: "uri".to_string() }),
+            factory.try_generate(json!({ "local_path": local_path })));
+        assert!(matches!(factory.try_generate(json!({ "uri": "https://cdn.example/a.mp3", "local_path": local_path, "force": "yes" })),
+            Err(FactoryError::InvalidField { field, .. }) if field == "force"));
+        assert!(matches!(factory.try_generate(json!({ "uri": "https://cdn.example/a.mp3", "local_path": local_path, "filename_template": "{genre}" })),
+            Err(FactoryError::InvalidField { field, .. }) if field == "filename_template"));
+
+        let config = factory.try_generate(json!({ "uri": " https://cdn.example/a.mp3 ", "local_path": local_path })).unwrap();
+        assert_eq!("https://cdn.example/a.mp3", config.uri);
+        assert!(local_path.is_dir());
+
+        let file = dir.path().join("file");
+        std::fs::write(&file, "").unwrap();
+        assert!(matches!(factory.try_generate(json!({ "uri": "https://cdn.example/a.mp3", "local_path": file })),
+            Err(FactoryError::Unwritable { .. })));
+    }
+
+    #[test]
+    #[should_panic(expected = "Missing field \"uri\"")]
+    fn uri_factory_panics_with_the_declaration_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let factory = DownloadConfigFromURI::new(dir.path().to_path_buf());
+        assert!(matches!(factory.try_generate(json!("https://cdn.example/a.mp3")), Err(FactoryError::NotAnObject { .. })));
+        assert_eq!("https://cdn.example/a.mp3", factory.generate(json!({ "uri": "https://cdn.example/a.mp3" })).uri);
+        factory.generate(json!({}));
+    }
+
+    #[test]
+    fn checks_uri_per_provider() {
+        let dir = tempfile::tempdir().unwrap();
+        let factory = DownloadConfigFromURI::new(dir.path().to_path_buf());
+        let hint = |uri: &str, provider: &DownloadProviders| match factory.try_generate_for(json!({ "uri": uri }), provider) {
+            Err(FactoryError::InvalidUri { did_you_mean, .. }) => did_you_mean,
+            other => panic!("{other:?}")
+        };
+        let youtube = DownloadProviders::from(YoutubeDL::default());
+        let archive = DownloadProviders::from(InternetArchive::new("https://archive.org"));
+
+        assert!(factory.try_generate_for(json!({ "uri": "https://youtu.be/dQw4w9WgXcQ" }), &youtube).is_ok());
+        assert_eq!(Some("https://www.youtube.com/watch?v=dQw4w9WgXcQ".to_string()), hint("dQw4w9WgXcQ", &youtube));
+        assert_eq!(None, hint("https://www.youtube.com/feed/trending", &youtube));
+        assert!(factory.try_generate_for(json!({ "uri": "ia:gd1977-05-08" }), &archive).is_ok());
+        assert_eq!(Some("ia:gd1977-05-08".to_string()), hint("gd1977-05-08", &archive));
+        assert_eq!(Some("https://cdn.example/a.mp3".to_string()), hint("cdn.example/a.mp3", &DownloadProviders::from(HttpDownload::default())));
+    }
+}
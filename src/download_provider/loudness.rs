@@ -0,0 +1,260 @@
+//! Measures how loud downloads are (EBU R128, through ffmpeg's `ebur128`
+//! filter) and writes ReplayGain tags, so players can level tracks that
+//! were uploaded at wildly different volumes.
+
+use std::{path::{Path, PathBuf}, process::Command, time::Duration};
+
+use lofty::{AudioFile, ItemKey, Tag, TagExt, TagType, id3::v2::{EncodedTextFrame, Frame, FrameFlags, FrameValue, ID3v2Tag, TextEncoding}};
+
+/// Loudness ReplayGain 2.0 levels tracks to, in LUFS
+pub const REFERENCE_LUFS: f64 = -18.0;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Loudness {
+    /// Integrated loudness, in LUFS
+    pub integrated_lufs: f64,
+    /// True peak, in dBFS
+    pub true_peak_dbfs: f64
+}
+
+impl Loudness {
+    /// What to add to reach [REFERENCE_LUFS], in dB
+    pub fn gain_db(&self) -> f64 {
+        REFERENCE_LUFS - self.integrated_lufs
+    }
+
+    /// The true peak as a linear sample value, 1.0 being full scale
+    pub fn peak(&self) -> f64 {
+        10f64.powf(self.true_peak_dbfs / 20.0)
+    }
+
+    /// The loudness of tracks played one after the other, `tracks` being
+    /// their loudness and duration: the duration-weighted mean energy, and
+    /// the highest peak
+    /// ```
+    /// use std::time::Duration;
+    /// use cli_music_player::download_provider::loudness::Loudness;
+    ///
+    /// let quiet = Loudness { integrated_lufs: -20.0, true_peak_dbfs: -3.0 };
+    /// let loud = Loudness { integrated_lufs: -10.0, true_peak_dbfs: 0.5 };
+    /// let album = Loudness::combined(&[(quiet, Duration::from_secs(60)), (loud, Duration::from_secs(60))]).unwrap();
+    /// // energy adds up: the loud half dominates
+    /// assert!((album.integrated_lufs - -12.6).abs() < 0.1, "{album:?}");
+    /// assert_eq!(0.5, album.true_peak_dbfs);
+    /// assert_eq!(None, Loudness::combined(&[]));
+    /// ```
+    pub fn combined(tracks: &[(Loudness, Duration)]) -> Option<Loudness> {
+        let total = tracks.iter().map(|(_, duration)| duration.as_secs_f64()).sum::<f64>();
+        // tracks of unknown length count the same
+        let weight = |duration: &Duration| if total > 0.0 { duration.as_secs_f64() / total } else { 1.0 / tracks.len() as f64 };
+        let energy = tracks.iter()
+            .map(|(loudness, duration)| weight(duration) * 10f64.powf(loudness.integrated_lufs / 10.0))
+            .sum::<f64>();
+        (!tracks.is_empty()).then(|| Loudness {
+            integrated_lufs: 10.0 * energy.log10(),
+            true_peak_dbfs: tracks.iter().map(|(loudness, _)| loudness.true_peak_dbfs).fold(f64::NEG_INFINITY, f64::max)
+        })
+    }
+}
+
+/// Reads the summary ffmpeg's `ebur128=peak=true` filter prints when done
+/// ```
+/// use cli_music_player::download_provider::loudness::parse_ebur128_summary;
+///
+/// let stderr = "[Parsed_ebur128_0 @ 0x5581] t: 3.1  TARGET:-23 LUFS    M: -14.0 S:-120.7     I: -14.1 LUFS       LRA:   0.0 LU  FTPK: -1.2 dBFS  TPK: -1.2 dBFS
+/// [Parsed_ebur128_0 @ 0x5581] Summary:
+///
+///   Integrated loudness:
+///     I:         -9.3 LUFS
+///     Threshold: -19.6 LUFS
+///
+///   Loudness range:
+///     LRA:         4.4 LU
+///
+///   True peak:
+///     Peak:        1.1 dBFS";
+/// let loudness = parse_ebur128_summary(stderr).unwrap();
+/// assert_eq!((-9.3, 1.1), (loudness.integrated_lufs, loudness.true_peak_dbfs));
+/// assert_eq!(-8.7, (loudness.gain_db() * 10.0).round() / 10.0);
+/// assert_eq!(None, parse_ebur128_summary("Invalid data found when processing input"));
+/// ```
+pub fn parse_ebur128_summary(stderr: &str) -> Option<Loudness> {
+    let summary = &stderr[stderr.rfind("Summary:")?..];
+    let value = |label: &str| summary.lines()
+        .map(str::trim)
+        .find_map(|line| line.strip_prefix(label))
+        .and_then(|rest| rest.split_whitespace().next())
+        .and_then(|value| value.parse::<f64>().ok());
+    let integrated_lufs = value("I:")?;
+    // silence has no loudness to speak of
+    integrated_lufs.is_finite().then_some(Loudness { integrated_lufs, true_peak_dbfs: value("Peak:").unwrap_or(0.0) })
+}
+
+/// Decodes the file at `path` with ffmpeg to measure its loudness
+pub fn analyze(ffmpeg: &Path, path: &Path) -> Result<Loudness, String> {
+    let mut command = Command::new(ffmpeg);
+    command.args(["-hide_banner", "-nostats", "-i"]).arg(path)
+        .args(["-map", "0:a:0", "-af", "ebur128=peak=true", "-f", "null", "-"]);
+    log::info!("Running {command:?}");
+    let output = command.output().map_err(|err| format!("Cannot run {ffmpeg:?}: {err}"))?;
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if !output.status.success() {
+        return Err(format!("ffmpeg could not measure the loudness of {path:?}: {}", stderr.lines().last().unwrap_or_default()));
+    }
+    parse_ebur128_summary(&stderr).ok_or_else(|| format!("ffmpeg reported no loudness for {path:?}"))
+}
+
+fn format_gain(loudness: &Loudness) -> String {
+    format!("{:.2} dB", loudness.gain_db())
+}
+
+fn format_peak(loudness: &Loudness) -> String {
+    format!("{:.6}", loudness.peak())
+}
+
+/// Writes gain and peak under `keys` (gain, peak) into the primary tag
+/// of the file at `path`
+fn write_gain(path: &Path, loudness: &Loudness, keys: (ItemKey, ItemKey)) -> Result<(), String> {
+    let mut tagged = lofty::read_from_path(path, false)
+        .map_err(|err| format!("Cannot read tags of {path:?}: {err}"))?;
+    let tag_type = tagged.primary_tag_type();
+    if tagged.tag(tag_type).is_none() {
+        tagged.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged.tag_mut(tag_type).expect("tag was just inserted");
+    if !(tag.insert_text(keys.0, format_gain(loudness)) && tag.insert_text(keys.1, format_peak(loudness))) {
+        return Err(format!("{tag_type:?} tags of {path:?} cannot hold ReplayGain"));
+    }
+    let saved = match tag_type {
+        TagType::ID3v2 => to_id3v2(tag).and_then(|tag| tag.save_to_path(path)),
+        _ => tag.save_to_path(path)
+    };
+    saved.map_err(|err| format!("Cannot write tags of {path:?}: {err}"))
+}
+
+/// Converts a tag holding ReplayGain to ID3v2. lofty reads the TXXX frames
+/// ReplayGain lives in, but cannot convert them back on its own.
+fn to_id3v2(tag: &Tag) -> lofty::Result<ID3v2Tag> {
+    let keys = [ItemKey::ReplayGainTrackGain, ItemKey::ReplayGainTrackPeak, ItemKey::ReplayGainAlbumGain, ItemKey::ReplayGainAlbumPeak];
+    let mut rest = tag.clone();
+    keys.iter().for_each(|key| rest.remove_key(key));
+    let mut id3v2 = ID3v2Tag::from(rest);
+    for key in &keys {
+        if let (Some(content), Some(description)) = (tag.get_string(key), key.map_key(TagType::ID3v2, true)) {
+            let value = FrameValue::UserText(EncodedTextFrame {
+                encoding: TextEncoding::UTF8,
+                description: description.to_string(),
+                content: content.to_string()
+            });
+            id3v2.insert(Frame::new("TXXX", value, FrameFlags::default())?);
+        }
+    }
+    Ok(id3v2)
+}
+
+/// Measures the file at `path` and writes its ReplayGain track gain and peak
+pub fn tag_track(ffmpeg: &Path, path: &Path) -> Result<Loudness, String> {
+    let loudness = analyze(ffmpeg, path)?;
+    write_gain(path, &loudness, (ItemKey::ReplayGainTrackGain, ItemKey::ReplayGainTrackPeak))?;
+    Ok(loudness)
+}
+
+/// [tag_track], logging failures: players without gain tags play the
+/// track at its own loudness, which is how it sounds anywhere else
+pub fn tag_track_or_warn(ffmpeg: &Path, path: &Path) {
+    if let Err(err) = tag_track(ffmpeg, path) {
+        log::warn!("Leaving {path:?} without ReplayGain: {err}");
+    }
+}
+
+/// The track loudness in the ReplayGain tags of the file at `path`, and
+/// its duration
+fn tagged_loudness(path: &Path) -> Option<(Loudness, Duration)> {
+    let tagged = lofty::read_from_path(path, false).ok()?;
+    let tag = tagged.primary_tag()?;
+    let number = |key: &ItemKey| tag.get_string(key)
+        .and_then(|value| value.trim().trim_end_matches("dB").trim().parse::<f64>().ok());
+    let gain = number(&ItemKey::ReplayGainTrackGain)?;
+    let peak = number(&ItemKey::ReplayGainTrackPeak).filter(|peak| *peak > 0.0).unwrap_or(1.0);
+    Some((Loudness { integrated_lufs: REFERENCE_LUFS - gain, true_peak_dbfs: 20.0 * peak.log10() }, tagged.properties().duration()))
+}
+
+/// Measures the album made of `album` and writes its ReplayGain gain and
+/// peak into the files in `update`; the other tracks are only read.
+/// Track gains already written are reused; tracks without are measured
+/// (and get their track gain too, if they are to be updated).
+pub fn tag_album(ffmpeg: &Path, album: &[PathBuf], update: &[PathBuf]) -> Result<Loudness, String> {
+    let tracks = album.iter()
+        .map(|path| match tagged_loudness(path) {
+            Some(track) => Ok(track),
+            None => {
+                let loudness = match update.contains(path) {
+                    true => tag_track(ffmpeg, path)?,
+                    false => analyze(ffmpeg, path)?
+                };
+                let duration = lofty::read_from_path(path, false).map(|tagged| tagged.properties().duration()).unwrap_or_default();
+                Ok((loudness, duration))
+            }
+        })
+        .collect::<Result<Vec<_>, String>>()?;
+    let loudness = Loudness::combined(&tracks).ok_or_else(|| "An album needs at least one track".to_string())?;
+    for path in update {
+        write_gain(path, &loudness, (ItemKey::ReplayGainAlbumGain, ItemKey::ReplayGainAlbumPeak))?;
+    }
+    Ok(loudness)
+}
+
+/// [tag_album], logging failures instead
+pub fn tag_album_or_warn(ffmpeg: &Path, album: &[PathBuf], update: &[PathBuf]) {
+    if let Err(err) = tag_album(ffmpeg, album, update) {
+        log::warn!("Leaving {} tracks without ReplayGain album gain: {err}", update.len());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::fake_program::fake_program;
+
+    use super::*;
+
+    /// Reports the loudness found in `<input>.lufs`
+    const FAKE_FFMPEG: &str = r#"
+while [ "$#" -gt 0 ]; do
+    case "$1" in -i) input="$2"; shift;; esac
+    shift
+done
+printf '[Parsed_ebur128_0 @ 0x1] Summary:\n\n  Integrated loudness:\n    I:  -%s LUFS\n\n  True peak:\n    Peak:  -1.0 dBFS\n' "$(cat "$input.lufs")" >&2
+"#;
+
+    #[test]
+    fn tags_track_and_album_gain() {
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", FAKE_FFMPEG);
+        let track = |name: &str, lufs: &str| {
+            let path = dir.path().join(name);
+            let mut frame = vec![0xFF, 0xFB, 0x90, 0x64];
+            frame.resize(417, 0);
+            std::fs::write(&path, frame.repeat(4)).unwrap();
+            std::fs::write(dir.path().join(format!("{name}.lufs")), lufs).unwrap();
+            path
+        };
+        let (quiet, loud) = (track("quiet.mp3", "20.0"), track("loud.mp3", "10.0"));
+
+        assert_eq!(2.0, tag_track(&ffmpeg, &quiet).unwrap().gain_db());
+        // the loud one is measured along the way
+        let both = [quiet.clone(), loud.clone()];
+        let album = tag_album(&ffmpeg, &both, &both).unwrap();
+        assert!((album.integrated_lufs - -12.6).abs() < 0.1, "{album:?}");
+
+        let gains = |path: &Path| {
+            let tagged = lofty::read_from_path(path, false).unwrap();
+            let tag = tagged.primary_tag().unwrap();
+            [ItemKey::ReplayGainTrackGain, ItemKey::ReplayGainAlbumGain, ItemKey::ReplayGainAlbumPeak].iter()
+                .map(|key| tag.get_string(key).unwrap_or_default().to_string())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(vec!["2.00 dB", "-5.40 dB", "0.891251"], gains(&quiet));
+        assert_eq!(vec!["-8.00 dB", "-5.40 dB", "0.891251"], gains(&loud));
+        assert!(tag_track(&ffmpeg, &dir.path().join("missing.mp3")).is_err());
+    }
+}
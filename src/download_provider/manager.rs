@@ -0,0 +1,582 @@
+//! Runs many downloads at once through a [ProvideDownload], with a bounded
+//! number of workers. The queue is persisted after every change, so a batch
+//! survives restarts: jobs that were running when we went away are queued
+//! again on the next start.
+
+use std::{path::{Path, PathBuf}, sync::{Arc, Condvar, Mutex, MutexGuard}, thread::JoinHandle, time::Duration};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::common::config::{load_json, project_dirs, save_json};
+use super::{interface::{DownloadConfig, DownloadProviders, Downloaded, ProvideDownload}, progress::DownloadProgress, cache::{CacheConfig, DownloadCache}, hooks::{self, HookCommand, HookFailure, HookPayload}};
+
+pub type JobId = u64;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum JobState {
+    /// Waiting for a worker, possibly until [DownloadJob::retry_at]
+    Queued,
+    Running,
+    /// Skipped by the workers until resumed
+    Paused,
+    /// Out of attempts; see [DownloadJob::last_error]
+    Failed,
+    /// See [DownloadJob::files]
+    Done,
+    Cancelled
+}
+
+impl JobState {
+    /// Whether the job still needs a worker
+    pub fn is_pending(&self) -> bool {
+        matches!(self, JobState::Queued | JobState::Running)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DownloadJob {
+    pub id: JobId,
+    pub config: DownloadConfig,
+    pub state: JobState,
+    /// Attempts made so far
+    #[serde(default)]
+    pub attempts: u32,
+    /// A failed attempt is retried no earlier than this
+    #[serde(default)]
+    pub retry_at: Option<DateTime<Utc>>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    /// What the download produced, once done
+    #[serde(default)]
+    pub files: Vec<PathBuf>,
+    /// The state asked for while the job was running (paused or
+    /// cancelled). Providers cannot be interrupted, so it is applied when
+    /// the running attempt returns.
+    #[serde(default)]
+    pub requested: Option<JobState>,
+    /// Download hooks that failed after the job was done
+    #[serde(default)]
+    pub hook_failures: Vec<HookFailure>,
+    /// Latest report of the running attempt; not persisted
+    #[serde(skip)]
+    pub progress: Option<DownloadProgress>,
+    pub added: DateTime<Utc>
+}
+
+/// What the queue file holds
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(default)]
+pub struct DownloadQueue {
+    pub next_id: JobId,
+    pub jobs: Vec<DownloadJob>
+}
+
+impl DownloadQueue {
+    fn job_mut(&mut self, id: JobId) -> Result<&mut DownloadJob, String> {
+        self.jobs.iter_mut().find(|job| job.id == id)
+            .ok_or_else(|| format!("No download job with id {id}"))
+    }
+
+    /// The first queued job whose backoff is over
+    fn next_ready(&mut self, now: DateTime<Utc>) -> Option<&mut DownloadJob> {
+        self.jobs.iter_mut()
+            .find(|job| job.state == JobState::Queued && job.retry_at.is_none_or(|at| at <= now))
+    }
+
+    fn next_retry(&self) -> Option<DateTime<Utc>> {
+        self.jobs.iter()
+            .filter(|job| job.state == JobState::Queued)
+            .filter_map(|job| job.retry_at)
+            .min()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct ManagerConfig {
+    /// How many downloads run at the same time
+    ///
+    /// Default: 3
+    pub workers: usize,
+    /// Attempts per job before it is marked [JobState::Failed]
+    ///
+    /// Default: 3
+    pub max_attempts: u32,
+    /// Wait before the first retry; doubles with every further attempt
+    ///
+    /// Default: 5
+    pub retry_backoff_secs: u64,
+    /// JSON file holding the [DownloadQueue]
+    ///
+    /// Default: `<data dir>/download_queue.json`
+    pub queue_path: PathBuf,
+    /// Quota enforced after every finished download
+    ///
+    /// Default: [CacheConfig::default]
+    pub cache: CacheConfig,
+    /// Run after every job done; batch hooks do not apply here
+    ///
+    /// Default: []
+    pub hooks: Vec<HookCommand>
+}
+
+impl Default for ManagerConfig {
+    fn default() -> Self {
+        Self {
+            workers: 3,
+            max_attempts: 3,
+            retry_backoff_secs: 5,
+            queue_path: project_dirs().data_dir().join("download_queue.json"),
+            cache: CacheConfig::default(),
+            hooks: vec![]
+        }
+    }
+}
+
+impl ManagerConfig {
+    /// Wait before retrying a job that has failed `attempts` times
+    /// ```
+    /// use std::time::Duration;
+    /// use cli_music_player::download_provider::manager::ManagerConfig;
+    ///
+    /// let config = ManagerConfig { retry_backoff_secs: 5, ..Default::default() };
+    /// assert_eq!(Duration::from_secs(5), config.backoff(1));
+    /// assert_eq!(Duration::from_secs(20), config.backoff(3));
+    /// ```
+    pub fn backoff(&self, attempts: u32) -> Duration {
+        Duration::from_secs(self.retry_backoff_secs.saturating_mul(1 << attempts.saturating_sub(1).min(16)))
+    }
+}
+
+struct Inner {
+    queue: DownloadQueue,
+    shutdown: bool
+}
+
+/// State shared between the manager and its workers
+struct Shared {
+    inner: Mutex<Inner>,
+    /// Signalled whenever the queue changes
+    changed: Condvar,
+    config: ManagerConfig
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        // a panicking worker must not take the whole queue down with it
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Persists the queue and wakes up whoever waits on it
+    fn commit(&self, inner: &Inner) -> Result<(), String> {
+        self.changed.notify_all();
+        save_queue(&self.config.queue_path, &inner.queue)
+    }
+}
+
+fn load_queue(path: &Path) -> Result<DownloadQueue, String> {
+    load_json(path, "download queue")
+}
+
+fn save_queue(path: &Path, queue: &DownloadQueue) -> Result<(), String> {
+    save_json(path, "download queue", queue)
+}
+
+/// Queues downloads and hands them to a pool of worker threads.
+/// Dropping the manager waits for running downloads to return.
+pub struct DownloadManager<P: ProvideDownload + Send + Sync + 'static = DownloadProviders> {
+    provider: Arc<P>,
+    shared: Arc<Shared>,
+    workers: Vec<JoinHandle<()>>
+}
+
+impl<P: ProvideDownload + Send + Sync + 'static> DownloadManager<P> {
+    /// Loads the persisted queue. Workers only pick up jobs after
+    /// [DownloadManager::start].
+    pub fn new(provider: P, config: ManagerConfig) -> Result<Self, String> {
+        let mut queue = load_queue(&config.queue_path)?;
+        for job in queue.jobs.iter_mut().filter(|job| job.state == JobState::Running) {
+            log::info!("Download job {} was interrupted; queueing it again", job.id);
+            job.state = match job.requested.take() {
+                Some(requested) => requested,
+                None => JobState::Queued
+            };
+        }
+        Ok(Self {
+            provider: Arc::new(provider),
+            shared: Arc::new(Shared { inner: Mutex::new(Inner { queue, shutdown: false }), changed: Condvar::new(), config }),
+            workers: vec![]
+        })
+    }
+
+    /// Spawns the workers, if not running already
+    pub fn start(&mut self) {
+        if !self.workers.is_empty() {
+            return;
+        }
+        self.shared.lock().shutdown = false;
+        self.workers = (0..self.shared.config.workers.max(1))
+            .map(|_| {
+                let (provider, shared) = (self.provider.clone(), self.shared.clone());
+                std::thread::spawn(move || work(provider.as_ref(), &shared))
+            })
+            .collect();
+    }
+
+    /// Lets running downloads return, then stops the workers. Jobs
+    /// stay queued for the next start.
+    pub fn shutdown(&mut self) {
+        self.shared.lock().shutdown = true;
+        self.shared.changed.notify_all();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+
+    pub fn enqueue(&self, config: DownloadConfig) -> Result<JobId, String> {
+        let mut inner = self.shared.lock();
+        let id = inner.queue.next_id;
+        inner.queue.next_id += 1;
+        inner.queue.jobs.push(DownloadJob {
+            id,
+            config,
+            state: JobState::Queued,
+            attempts: 0,
+            retry_at: None,
+            last_error: None,
+            files: vec![],
+            requested: None,
+            hook_failures: vec![],
+            progress: None,
+            added: Utc::now()
+        });
+        self.shared.commit(&inner)?;
+        Ok(id)
+    }
+
+    pub fn jobs(&self) -> Vec<DownloadJob> {
+        self.shared.lock().queue.jobs.clone()
+    }
+
+    pub fn job(&self, id: JobId) -> Option<DownloadJob> {
+        self.shared.lock().queue.jobs.iter().find(|job| job.id == id).cloned()
+    }
+
+    /// Moves a job to `state` right away, or once its running attempt returns
+    fn request(&self, id: JobId, state: JobState) -> Result<(), String> {
+        let mut inner = self.shared.lock();
+        let job = inner.queue.job_mut(id)?;
+        match job.state {
+            JobState::Done | JobState::Cancelled => return Err(format!("Download job {id} is already {:?}", job.state)),
+            JobState::Running => job.requested = Some(state),
+            _ => job.state = state
+        }
+        self.shared.commit(&inner)
+    }
+
+    /// Keeps the workers off the job until [DownloadManager::resume]
+    pub fn pause(&self, id: JobId) -> Result<(), String> {
+        self.request(id, JobState::Paused)
+    }
+
+    /// Stops the job for good. A running download is allowed to finish,
+    /// and the files it produced are removed.
+    pub fn cancel(&self, id: JobId) -> Result<(), String> {
+        self.request(id, JobState::Cancelled)
+    }
+
+    /// Queues a paused or failed job again. Failed jobs get a fresh set
+    /// of attempts.
+    pub fn resume(&self, id: JobId) -> Result<(), String> {
+        let mut inner = self.shared.lock();
+        let job = inner.queue.job_mut(id)?;
+        match job.state {
+            JobState::Paused => job.state = JobState::Queued,
+            JobState::Failed => {
+                job.state = JobState::Queued;
+                job.attempts = 0;
+                job.retry_at = None;
+            },
+            JobState::Running if job.requested == Some(JobState::Paused) => job.requested = None,
+            state => return Err(format!("Download job {id} is {state:?}, not paused or failed"))
+        }
+        self.shared.commit(&inner)
+    }
+
+    /// Forgets done and cancelled jobs, returning how many were removed
+    pub fn clear_finished(&self) -> Result<usize, String> {
+        let mut inner = self.shared.lock();
+        let before = inner.queue.jobs.len();
+        inner.queue.jobs.retain(|job| !matches!(job.state, JobState::Done | JobState::Cancelled));
+        let removed = before - inner.queue.jobs.len();
+        self.shared.commit(&inner).map(|_| removed)
+    }
+
+    /// Blocks until no job is queued or running. Paused jobs do not count.
+    /// Returns right away if the workers were never started.
+    pub fn wait_idle(&self) {
+        let mut inner = self.shared.lock();
+        while !self.workers.is_empty() && inner.queue.jobs.iter().any(|job| job.state.is_pending()) {
+            inner = self.shared.changed.wait(inner).unwrap_or_else(|poisoned| poisoned.into_inner());
+        }
+    }
+}
+
+impl<P: ProvideDownload + Send + Sync + 'static> Drop for DownloadManager<P> {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
+}
+
+/// The loop every worker thread runs
+fn work<P: ProvideDownload>(provider: &P, shared: &Shared) {
+    loop {
+        let (id, config) = {
+            let mut inner = shared.lock();
+            loop {
+                if inner.shutdown {
+                    return;
+                }
+                let now = Utc::now();
+                if let Some(job) = inner.queue.next_ready(now) {
+                    job.state = JobState::Running;
+                    job.attempts += 1;
+                    job.progress = None;
+                    let picked = (job.id, job.config.clone());
+                    if let Err(err) = shared.commit(&inner) {
+                        log::warn!("{err}");
+                    }
+                    break picked;
+                }
+                // nothing ready: sleep until the next retry is due or the queue changes
+                let wait = inner.queue.next_retry()
+                    .map(|at| (at - now).to_std().unwrap_or_default())
+                    .unwrap_or(Duration::from_secs(60));
+                inner = shared.changed.wait_timeout(inner, wait)
+                    .unwrap_or_else(|poisoned| poisoned.into_inner()).0;
+            }
+        };
+
+        log::info!("Download job {id}: fetching {}", config.uri);
+        let (uri, metadata) = (config.uri.clone(), config.metadata.clone());
+        let result = provider.download_with_progress(config, &mut |progress| {
+            if let Ok(job) = shared.lock().queue.job_mut(id) {
+                job.progress = Some(progress);
+            }
+        });
+        // still part of the attempt, so waiting for idle waits for hooks too.
+        // Like batches, only what was downloaded now is worth a hook.
+        let cancelled = || shared.lock().queue.job_mut(id).map_or(true, |job| job.requested == Some(JobState::Cancelled));
+        let hook_failures = match &result {
+            Ok(downloaded) if !downloaded.skipped && !cancelled() => hooks::run_hooks(&shared.config.hooks, &HookPayload::Download { uri, files: downloaded.files.clone(), metadata }),
+            _ => vec![]
+        };
+
+        let mut inner = shared.lock();
+        let Ok(job) = inner.queue.job_mut(id) else {
+            // forgotten while running
+            continue;
+        };
+        finish(job, result, &shared.config);
+        job.hook_failures = hook_failures;
+        let done = match job.state {
+            JobState::Done => job.files.clone(),
+            _ => vec![]
+        };
+        if let Err(err) = shared.commit(&inner) {
+            log::warn!("{err}");
+        }
+        drop(inner);
+        if !done.is_empty() {
+            let protect = done.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+            DownloadCache::new(shared.config.cache.clone()).evict_or_warn(&protect);
+        }
+    }
+}
+
+/// Applies the outcome of an attempt to its job
+fn finish(job: &mut DownloadJob, result: Result<Downloaded, String>, config: &ManagerConfig) {
+    let requested = job.requested.take();
+    match (result, requested) {
+        (Ok(downloaded), Some(JobState::Cancelled)) => {
+            // files the archive had already were there before the job
+            let produced = if downloaded.skipped { &[][..] } else { &downloaded.files[..] };
+            for path in produced {
+                if let Err(err) = std::fs::remove_file(path) {
+                    log::warn!("Cannot remove {path:?} of cancelled download job {}: {err}", job.id);
+                }
+            }
+            job.state = JobState::Cancelled;
+        },
+        (Ok(downloaded), _) => {
+            log::info!("Download job {} done: {:?}", job.id, downloaded.files);
+            job.state = JobState::Done;
+            job.files = downloaded.files;
+            job.last_error = None;
+            job.retry_at = None;
+        },
+        (Err(err), requested) => {
+            log::warn!("Download job {} failed (attempt {}): {err}", job.id, job.attempts);
+            job.last_error = Some(err);
+            job.state = match requested {
+                Some(state) => state,
+                None if job.attempts >= config.max_attempts => JobState::Failed,
+                None => {
+                    job.retry_at = chrono::Duration::from_std(config.backoff(job.attempts)).ok()
+                        .map(|backoff| Utc::now() + backoff);
+                    JobState::Queued
+                }
+            };
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::atomic::{AtomicUsize, Ordering}};
+
+    use crate::common::self_setup::SelfSetup;
+    use super::{*, super::progress::{DownloadPhase, ProgressCallback}};
+
+    /// Fails each uri as many times as asked, then writes `<uri>.m4a`.
+    /// One that is there already counts as skipped.
+    #[derive(Default)]
+    struct FlakyProvider {
+        failures: Mutex<HashMap<String, u32>>,
+        delay: Duration,
+        running: AtomicUsize,
+        max_running: AtomicUsize
+    }
+
+    impl SelfSetup for FlakyProvider {
+        fn setup(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    impl ProvideDownload for FlakyProvider {
+        fn download_with_progress(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+            let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+            self.max_running.fetch_max(running, Ordering::SeqCst);
+            std::thread::sleep(self.delay);
+            self.running.fetch_sub(1, Ordering::SeqCst);
+            if let Some(left) = self.failures.lock().unwrap().get_mut(&config.uri).filter(|left| **left > 0) {
+                *left -= 1;
+                return Err(format!("{} is flaky", config.uri));
+            }
+            let path = config.local_path.join(format!("{}.m4a", config.uri));
+            if path.is_file() {
+                return Ok(Downloaded { files: vec![path], skipped: true });
+            }
+            std::fs::write(&path, "audio").map_err(|err| err.to_string())?;
+            on_progress(DownloadProgress::phase(DownloadPhase::Finished));
+            Ok(Downloaded::file(path))
+        }
+    }
+
+    fn manager_config(dir: &Path, workers: usize) -> ManagerConfig {
+        ManagerConfig { workers, max_attempts: 3, retry_backoff_secs: 0, queue_path: dir.join("queue.json"), cache: CacheConfig::default(), hooks: vec![] }
+    }
+
+    fn download(dir: &Path, uri: &str) -> DownloadConfig {
+        DownloadConfig::new(uri, dir)
+    }
+
+    #[test]
+    fn bounded_workers_with_retries() {
+        let dir = tempfile::tempdir().unwrap();
+        let provider = FlakyProvider {
+            failures: Mutex::new(HashMap::from([("flaky".to_string(), 2), ("broken".to_string(), 99)])),
+            delay: Duration::from_millis(20),
+            ..Default::default()
+        };
+        let config = ManagerConfig {
+            hooks: vec![HookCommand { command: vec!["sh".to_string(), "-c".to_string(), r#"test "$CMP_URI" != b"#.to_string()], ..Default::default() }],
+            ..manager_config(dir.path(), 2)
+        };
+        let mut manager = DownloadManager::new(provider, config).unwrap();
+        let ids = ["a", "b", "c", "d", "flaky", "broken"].iter()
+            .map(|uri| manager.enqueue(download(dir.path(), uri)).unwrap())
+            .collect::<Vec<_>>();
+        manager.start();
+        manager.wait_idle();
+
+        assert!(manager.provider.max_running.load(Ordering::SeqCst) <= 2);
+        let jobs = manager.jobs();
+        assert_eq!(vec![JobState::Done; 5], jobs[..5].iter().map(|job| job.state).collect::<Vec<_>>());
+        assert_eq!(vec![dir.path().join("a.m4a")], jobs[0].files);
+        assert_eq!(Some(DownloadPhase::Finished), jobs[0].progress.as_ref().map(|p| p.phase));
+        assert_eq!((0, 1), (jobs[0].hook_failures.len(), jobs[1].hook_failures.len()));
+        assert_eq!(3, jobs[4].attempts);
+        assert_eq!(JobState::Failed, jobs[5].state);
+        assert_eq!(Some("broken is flaky".to_string()), jobs[5].last_error);
+
+        // a failed job gets a fresh set of attempts
+        manager.provider.failures.lock().unwrap().clear();
+        manager.resume(ids[5]).unwrap();
+        manager.wait_idle();
+        assert_eq!(JobState::Done, manager.job(ids[5]).unwrap().state);
+        assert_eq!(Err(format!("Download job {} is already Done", ids[0])), manager.cancel(ids[0]));
+
+        // nothing was downloaded, so there is nothing to run hooks for
+        let skipped = manager.enqueue(download(dir.path(), "b")).unwrap();
+        manager.wait_idle();
+        let job = manager.job(skipped).unwrap();
+        assert_eq!((JobState::Done, 0), (job.state, job.hook_failures.len()));
+        assert!(dir.path().join("b.m4a").is_file());
+    }
+
+    #[test]
+    fn pause_cancel_and_survive_restarts() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = manager_config(dir.path(), 1);
+        let manager = DownloadManager::new(FlakyProvider::default(), config.clone()).unwrap();
+        let paused = manager.enqueue(download(dir.path(), "paused")).unwrap();
+        let cancelled = manager.enqueue(download(dir.path(), "cancelled")).unwrap();
+        let interrupted = manager.enqueue(download(dir.path(), "interrupted")).unwrap();
+        manager.pause(paused).unwrap();
+        manager.cancel(cancelled).unwrap();
+        drop(manager);
+
+        // simulate dying in the middle of a download
+        let mut queue = load_queue(&config.queue_path).unwrap();
+        queue.jobs[2].state = JobState::Running;
+        save_queue(&config.queue_path, &queue).unwrap();
+
+        let mut manager = DownloadManager::new(FlakyProvider::default(), config).unwrap();
+        assert_eq!(JobState::Queued, manager.job(interrupted).unwrap().state);
+        manager.start();
+        manager.wait_idle();
+        let states = manager.jobs().iter().map(|job| job.state).collect::<Vec<_>>();
+        assert_eq!(vec![JobState::Paused, JobState::Cancelled, JobState::Done], states);
+        assert!(!dir.path().join("cancelled.m4a").exists());
+
+        manager.resume(paused).unwrap();
+        manager.wait_idle();
+        assert_eq!(JobState::Done, manager.job(paused).unwrap().state);
+        assert_eq!(Ok(3), manager.clear_finished());
+        assert!(manager.jobs().is_empty());
+        assert!(manager.pause(99).is_err());
+    }
+
+    #[test]
+    fn cancelling_a_running_job_removes_only_its_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let kept = dir.path().join("kept.m4a");
+        std::fs::write(&kept, "audio").unwrap();
+        let provider = FlakyProvider { delay: Duration::from_millis(200), ..Default::default() };
+        let mut manager = DownloadManager::new(provider, manager_config(dir.path(), 1)).unwrap();
+        let id = manager.enqueue(download(dir.path(), "running")).unwrap();
+        manager.start();
+        while manager.job(id).unwrap().state != JobState::Running {
+            std::thread::sleep(Duration::from_millis(5));
+        }
+        manager.cancel(id).unwrap();
+        manager.wait_idle();
+
+        assert_eq!(JobState::Cancelled, manager.job(id).unwrap().state);
+        assert!(!dir.path().join("running.m4a").exists());
+        assert!(kept.is_file());
+    }
+}
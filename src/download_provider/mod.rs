@@ -1,4 +1,20 @@
 pub mod interface;
 pub mod youtube_dl;
+pub mod direct;
+pub mod progress;
+pub mod manager;
+pub mod format;
+pub mod template;
+pub mod tagging;
+pub mod cover_art;
+pub mod archive;
+pub mod cache;
+pub mod stream;
+pub mod batch;
+pub mod loudness;
+pub mod chapters;
+pub mod segments;
+pub mod network;
+pub mod hooks;
 pub use interface::*;
 pub use youtube_dl::*;
@@ -0,0 +1,93 @@
+//! Progress reports emitted while a download runs, so frontends can draw
+//! progress bars.
+
+use std::{path::PathBuf, time::{Duration, Instant}};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DownloadPhase {
+    /// Bytes are coming in
+    Fetching,
+    /// Converting, remuxing or otherwise transforming the fetched file
+    PostProcessing,
+    /// Writing tags and cover art
+    Tagging,
+    /// The file is at its final path
+    Finished
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DownloadProgress {
+    pub phase: DownloadPhase,
+    pub downloaded_bytes: u64,
+    /// None when the server does not tell
+    pub total_bytes: Option<u64>,
+    /// Whether [DownloadProgress::total_bytes] is only a guess, as yt-dlp
+    /// makes for fragmented formats
+    #[serde(default)]
+    pub total_is_estimate: bool,
+    /// Bytes per second
+    pub speed: Option<f64>,
+    pub eta: Option<Duration>,
+    /// While fetching, the file the bytes go to (usually a `.part` file),
+    /// for reading along; see [super::stream]
+    #[serde(default)]
+    pub file: Option<PathBuf>
+}
+
+impl DownloadProgress {
+    pub fn phase(phase: DownloadPhase) -> Self {
+        Self { phase, downloaded_bytes: 0, total_bytes: None, total_is_estimate: false, speed: None, eta: None, file: None }
+    }
+
+    /// How far along the fetching is, between 0 and 1
+    /// ```
+    /// use cli_music_player::download_provider::progress::*;
+    ///
+    /// let progress = DownloadProgress { downloaded_bytes: 25, total_bytes: Some(100), ..DownloadProgress::phase(DownloadPhase::Fetching) };
+    /// assert_eq!(Some(0.25), progress.fraction());
+    /// assert_eq!(None, DownloadProgress::phase(DownloadPhase::Fetching).fraction());
+    /// ```
+    pub fn fraction(&self) -> Option<f64> {
+        self.total_bytes.filter(|total| *total > 0)
+            .map(|total| (self.downloaded_bytes as f64 / total as f64).min(1.0))
+    }
+}
+
+/// Where providers send their [DownloadProgress]
+pub type ProgressCallback<'a> = &'a mut dyn FnMut(DownloadProgress);
+
+/// Derives speed and ETA for downloads that only know their byte counts
+pub struct ProgressMeter {
+    started: Instant,
+    total_bytes: Option<u64>,
+    /// Bytes we already had before starting, e.g. when resuming
+    offset: u64
+}
+
+impl ProgressMeter {
+    pub fn new(total_bytes: Option<u64>) -> Self {
+        Self::resuming(total_bytes, 0)
+    }
+
+    pub fn resuming(total_bytes: Option<u64>, offset: u64) -> Self {
+        Self { started: Instant::now(), total_bytes, offset }
+    }
+
+    pub fn update(&self, downloaded_bytes: u64) -> DownloadProgress {
+        let elapsed = self.started.elapsed().as_secs_f64();
+        let fetched = downloaded_bytes.saturating_sub(self.offset) as f64;
+        let speed = (elapsed > 0.0 && fetched > 0.0).then(|| fetched / elapsed);
+        DownloadProgress {
+            phase: DownloadPhase::Fetching,
+            downloaded_bytes,
+            total_bytes: self.total_bytes,
+            total_is_estimate: false,
+            speed,
+            eta: self.total_bytes.zip(speed)
+                .map(|(total, speed)| Duration::from_secs_f64(total.saturating_sub(downloaded_bytes) as f64 / speed)),
+            file: None
+        }
+    }
+}
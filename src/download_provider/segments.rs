@@ -0,0 +1,356 @@
+//! Skips the parts of music videos that are not music: sponsor reads,
+//! intros, skits. Segments come from a local database file or a
+//! SponsorBlock-compatible API, and are either cut out of the download or
+//! written next to it for players to skip.
+
+use std::{collections::HashMap, path::{Path, PathBuf}, process::Command, time::Duration};
+
+use reqwest::{Url, blocking::Client};
+use serde::{Deserialize, Serialize};
+
+use super::chapters::Chapter;
+
+/// What a segment is, as SponsorBlock names it
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SegmentCategory {
+    Sponsor,
+    Selfpromo,
+    Interaction,
+    Intro,
+    Outro,
+    Preview,
+    /// Talk and skits in music videos
+    MusicOfftopic,
+    Filler
+}
+
+/// A stretch of a video, in the shape SponsorBlock serves it, so API
+/// responses can be saved into a segment database as they are
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub category: SegmentCategory,
+    /// Start and end, in seconds
+    pub segment: [f64; 2],
+    /// "skip" for segments to skip; other kinds ("mute", "poi" for
+    /// highlights, "full" for labels of the whole video) are left alone
+    #[serde(rename = "actionType", default, skip_serializing_if = "Option::is_none")]
+    pub action_type: Option<String>
+}
+
+impl Segment {
+    pub fn new(category: SegmentCategory, start: f64, end: f64) -> Self {
+        Self { category, segment: [start, end], action_type: None }
+    }
+
+    pub fn start(&self) -> Duration {
+        Duration::from_secs_f64(self.segment[0].max(0.0))
+    }
+
+    pub fn end(&self) -> Duration {
+        Duration::from_secs_f64(self.segment[1].max(0.0))
+    }
+
+    fn is_skip(&self) -> bool {
+        self.action_type.as_deref().is_none_or(|action| action == "skip") && self.segment[0] < self.segment[1]
+    }
+}
+
+/// Where segments come from
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum SegmentSource {
+    /// Nothing is skipped
+    #[default]
+    None,
+    /// A JSON file mapping video ids to their segments
+    File { path: PathBuf },
+    /// A SponsorBlock-compatible server, e.g. "https://sponsor.ajay.app"
+    Api { url: String }
+}
+
+/// What to do with the segments of a download
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SegmentAction {
+    /// Re-encode the download without them
+    Cut,
+    /// Keep the audio whole and write the segments to
+    /// `<file>.segments.json`, see [read_marks]
+    #[default]
+    Mark
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(default)]
+pub struct SegmentOptions {
+    /// Default: [SegmentSource::None]
+    pub source: SegmentSource,
+    /// Which segments to skip
+    ///
+    /// Default: every category but preview and filler
+    pub categories: Vec<SegmentCategory>,
+    /// Default: [SegmentAction::Mark]
+    pub action: SegmentAction
+}
+
+impl Default for SegmentOptions {
+    fn default() -> Self {
+        Self {
+            source: SegmentSource::None,
+            categories: vec![
+                SegmentCategory::Sponsor, SegmentCategory::Selfpromo, SegmentCategory::Interaction,
+                SegmentCategory::Intro, SegmentCategory::Outro, SegmentCategory::MusicOfftopic
+            ],
+            action: SegmentAction::Mark
+        }
+    }
+}
+
+impl SegmentOptions {
+    /// The segments of `video_id` in [SegmentOptions::categories], sorted
+    /// by start. APIs are asked through `client`.
+    pub fn segments(&self, client: &Client, video_id: &str) -> Result<Vec<Segment>, String> {
+        let mut segments = match &self.source {
+            SegmentSource::None => vec![],
+            SegmentSource::File { path } => {
+                let content = std::fs::read_to_string(path).map_err(|err| format!("Cannot read {path:?}: {err}"))?;
+                serde_json::from_str::<HashMap<String, Vec<Segment>>>(&content)
+                    .map_err(|err| format!("Cannot parse segment database {path:?}: {err}"))?
+                    .remove(video_id)
+                    .unwrap_or_default()
+            },
+            SegmentSource::Api { url } => self.fetch(client, url, video_id)?
+        };
+        segments.retain(|segment| segment.is_skip() && self.categories.contains(&segment.category));
+        segments.sort_by(|a, b| a.segment[0].total_cmp(&b.segment[0]));
+        Ok(segments)
+    }
+
+    fn fetch(&self, client: &Client, url: &str, video_id: &str) -> Result<Vec<Segment>, String> {
+        let categories = serde_json::to_string(&self.categories).expect("categories serialize");
+        let endpoint = Url::parse_with_params(&format!("{}/api/skipSegments", url.trim_end_matches('/')),
+                [("videoID", video_id), ("categories", &categories)])
+            .map_err(|err| format!("Invalid segment API URL {url:?}: {err}"))?;
+        let response = client.get(endpoint.clone()).send().map_err(|err| format!("Cannot fetch {endpoint}: {err}"))?;
+        // what SponsorBlock answers for videos nobody submitted segments for
+        if response.status() == reqwest::StatusCode::NOT_FOUND {
+            return Ok(vec![]);
+        }
+        let body = response.error_for_status()
+            .and_then(|response| response.text())
+            .map_err(|err| format!("Cannot fetch {endpoint}: {err}"))?;
+        serde_json::from_str(&body).map_err(|err| format!("{endpoint} returned unexpected segments: {err}"))
+    }
+
+    /// [SegmentOptions::segments], but a download without segments
+    /// skipped is still a download
+    pub fn segments_or_warn(&self, client: &Client, video_id: &str) -> Vec<Segment> {
+        self.segments(client, video_id).unwrap_or_else(|err| {
+            log::warn!("Not skipping anything in {video_id}: {err}");
+            vec![]
+        })
+    }
+}
+
+/// Overlapping and touching segments joined, in seconds
+fn spans(segments: &[Segment]) -> Vec<(f64, f64)> {
+    let mut sorted = segments.iter().map(|segment| (segment.segment[0], segment.segment[1])).collect::<Vec<_>>();
+    sorted.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let mut spans: Vec<(f64, f64)> = vec![];
+    for (start, end) in sorted {
+        match spans.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => spans.push((start, end))
+        }
+    }
+    spans
+}
+
+/// Where playback at `position` should jump to, if it is in a segment
+/// ```
+/// use std::time::Duration;
+/// use cli_music_player::download_provider::segments::{Segment, SegmentCategory, skip_to};
+///
+/// let segments = [Segment::new(SegmentCategory::Intro, 0.0, 10.0), Segment::new(SegmentCategory::Sponsor, 10.0, 25.5)];
+/// assert_eq!(Some(Duration::from_secs_f64(25.5)), skip_to(&segments, Duration::from_secs(3)));
+/// assert_eq!(None, skip_to(&segments, Duration::from_secs(30)));
+/// ```
+pub fn skip_to(segments: &[Segment], position: Duration) -> Option<Duration> {
+    let position = position.as_secs_f64();
+    spans(segments).into_iter()
+        .find(|(start, end)| (*start..*end).contains(&position))
+        .map(|(_, end)| Duration::from_secs_f64(end))
+}
+
+/// Where `time` ends up once `segments` are cut out
+/// ```
+/// use std::time::Duration;
+/// use cli_music_player::download_provider::segments::{Segment, SegmentCategory, time_after_cut};
+///
+/// let segments = [Segment::new(SegmentCategory::Intro, 0.0, 10.0), Segment::new(SegmentCategory::MusicOfftopic, 60.0, 90.0)];
+/// assert_eq!(Duration::from_secs(40), time_after_cut(&segments, Duration::from_secs(50)));
+/// // inside a cut, time stands still
+/// assert_eq!(Duration::from_secs(50), time_after_cut(&segments, Duration::from_secs(75)));
+/// assert_eq!(Duration::from_secs(60), time_after_cut(&segments, Duration::from_secs(100)));
+/// ```
+pub fn time_after_cut(segments: &[Segment], time: Duration) -> Duration {
+    let time = time.as_secs_f64();
+    let removed = spans(segments).into_iter()
+        .map(|(start, end)| (end.min(time) - start).max(0.0))
+        .sum::<f64>();
+    Duration::from_secs_f64((time - removed).max(0.0))
+}
+
+/// `chapters` moved to where they are once `segments` are cut out.
+/// Chapters cut out entirely are dropped.
+pub fn chapters_after_cut(segments: &[Segment], chapters: &[Chapter]) -> Vec<Chapter> {
+    chapters.iter()
+        .map(|chapter| Chapter {
+            title: chapter.title.clone(),
+            start: time_after_cut(segments, chapter.start),
+            end: chapter.end.map(|end| time_after_cut(segments, end))
+        })
+        .filter(|chapter| chapter.end.is_none_or(|end| end > chapter.start))
+        .collect()
+}
+
+/// The parts of `segments` between `start` and `end`, relative to
+/// `start`, for a track cut out of a longer recording
+pub fn segments_within(segments: &[Segment], start: Duration, end: Option<Duration>) -> Vec<Segment> {
+    let (start, end) = (start.as_secs_f64(), end.map_or(f64::INFINITY, |end| end.as_secs_f64()));
+    segments.iter()
+        .filter(|segment| segment.segment[0] < end && segment.segment[1] > start)
+        .map(|segment| Segment {
+            segment: [segment.segment[0].max(start) - start, segment.segment[1].min(end) - start],
+            ..segment.clone()
+        })
+        .collect()
+}
+
+/// Re-encodes the file at `path` without `segments`, keeping its tags
+pub fn cut(ffmpeg: &Path, path: &Path, segments: &[Segment]) -> Result<(), String> {
+    let spans = spans(segments);
+    if spans.is_empty() {
+        return Ok(());
+    }
+    let skipped = spans.iter()
+        .map(|(start, end)| format!("between(t,{start:.3},{end:.3})"))
+        .collect::<Vec<_>>()
+        .join("+");
+    let extension = path.extension().map(|ext| ext.to_string_lossy().to_string()).unwrap_or_default();
+    let staging = path.with_extension(format!("cutting.{extension}"));
+    let mut command = Command::new(ffmpeg);
+    command.args(["-hide_banner", "-loglevel", "error", "-y", "-i"]).arg(path)
+        .args(["-map", "0:a", "-map_metadata", "0", "-af"])
+        .arg(format!("aselect='not({skipped})',asetpts=N/SR/TB"))
+        .arg(&staging);
+    log::info!("Running {command:?}");
+    let result = command.output().map_err(|err| format!("Cannot run {ffmpeg:?}: {err}"))?;
+    if !result.status.success() {
+        let _ = std::fs::remove_file(&staging);
+        return Err(format!("ffmpeg could not cut segments out of {path:?}: {}", String::from_utf8_lossy(&result.stderr).trim()));
+    }
+    std::fs::rename(&staging, path).map_err(|err| format!("Cannot move {staging:?} to {path:?}: {err}"))
+}
+
+/// Where the segments of the file at `path` are marked
+pub fn marks_path(path: &Path) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(".segments.json");
+    path.with_file_name(name)
+}
+
+/// Writes `segments` next to the file at `path` for players to skip.
+/// When there are none, marks left by an earlier download of the file are
+/// removed instead.
+pub fn write_marks(path: &Path, segments: &[Segment]) -> Result<(), String> {
+    let marks = marks_path(path);
+    if segments.is_empty() {
+        return match std::fs::remove_file(&marks) {
+            Err(err) if err.kind() != std::io::ErrorKind::NotFound => Err(format!("Cannot remove {marks:?}: {err}")),
+            _ => Ok(())
+        };
+    }
+    let json = serde_json::to_string_pretty(segments).expect("segments serialize");
+    std::fs::write(&marks, json).map_err(|err| format!("Cannot write {marks:?}: {err}"))
+}
+
+/// [write_marks], logging failures instead
+pub fn write_marks_or_warn(path: &Path, segments: &[Segment]) {
+    if let Err(err) = write_marks(path, segments) {
+        log::warn!("Players may skip the wrong segments of {path:?}: {err}");
+    }
+}
+
+/// The segments marked for the file at `path`, if any
+pub fn read_marks(path: &Path) -> Vec<Segment> {
+    std::fs::read_to_string(marks_path(path)).ok()
+        .and_then(|content| serde_json::from_str(&content).ok())
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{common::{fake_program::fake_program, test_server::{TestServer, TestResponse}}, download_provider::network::{NetworkOptions, USER_AGENT}};
+
+    use super::*;
+
+    #[test]
+    fn loads_segments_from_api_and_file() {
+        let server = TestServer::serve(|req| match req.path.contains("videoID=abc") {
+            true => TestResponse::ok(r#"[
+                {"category": "music_offtopic", "segment": [200.0, 260.5], "UUID": "1", "actionType": "skip"},
+                {"category": "intro", "segment": [0.0, 12.0], "UUID": "2", "actionType": "skip"},
+                {"category": "filler", "segment": [100.0, 110.0], "UUID": "3", "actionType": "skip"},
+                {"category": "sponsor", "segment": [0.0, 0.0], "UUID": "4", "actionType": "full"}
+            ]"#),
+            false => TestResponse::not_found()
+        });
+        let options = SegmentOptions { source: SegmentSource::Api { url: server.url("/") }, ..Default::default() };
+        let client = Client::new();
+        let segments = options.segments(&client, "abc").unwrap();
+        assert_eq!(vec![Segment::new(SegmentCategory::Intro, 0.0, 12.0), Segment::new(SegmentCategory::MusicOfftopic, 200.0, 260.5)],
+            segments.into_iter().map(|segment| Segment { action_type: None, ..segment }).collect::<Vec<_>>());
+        assert_eq!(Ok(vec![]), options.segments(&client, "unknown"));
+        // through a proxy, which gets the whole URL
+        let proxied = NetworkOptions { proxy: Some(server.url("")), ..Default::default() }.client(USER_AGENT).unwrap();
+        let options = SegmentOptions { source: SegmentSource::Api { url: "http://segments.example.invalid".to_string() }, ..Default::default() };
+        assert_eq!(2, options.segments(&proxied, "abc").unwrap().len());
+
+        let dir = tempfile::tempdir().unwrap();
+        let database = dir.path().join("segments.json");
+        std::fs::write(&database, r#"{"abc": [{"category": "outro", "segment": [290.0, 300.0]}]}"#).unwrap();
+        let options = SegmentOptions { source: SegmentSource::File { path: database }, ..Default::default() };
+        assert_eq!(Ok(vec![Segment::new(SegmentCategory::Outro, 290.0, 300.0)]), options.segments(&client, "abc"));
+        assert!(SegmentOptions { source: SegmentSource::File { path: dir.path().join("missing.json") }, ..Default::default() }
+            .segments(&client, "abc").is_err());
+    }
+
+    #[test]
+    fn cuts_or_marks_segments() {
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", r#"for arg in "$@"; do last="$arg"; done; echo "$*" > "$last""#);
+        let path = dir.path().join("track.m4a");
+        std::fs::write(&path, "audio").unwrap();
+        let segments = [Segment::new(SegmentCategory::Intro, 0.0, 12.0), Segment::new(SegmentCategory::Sponsor, 10.0, 20.0)];
+
+        cut(&ffmpeg, &path, &segments).unwrap();
+        let args = std::fs::read_to_string(&path).unwrap();
+        assert!(args.contains("aselect='not(between(t,0.000,20.000))',asetpts=N/SR/TB"), "{args}");
+
+        write_marks(&path, &segments).unwrap();
+        assert_eq!(segments.to_vec(), read_marks(&path));
+        assert_eq!(dir.path().join("track.m4a.segments.json"), marks_path(&path));
+        write_marks(&path, &[]).unwrap();
+        assert!(!marks_path(&path).exists());
+        write_marks(&path, &[]).unwrap();
+
+        let chapters = [
+            Chapter { title: "Skit".to_string(), start: Duration::ZERO, end: Some(Duration::from_secs(20)) },
+            Chapter { title: "Song".to_string(), start: Duration::from_secs(20), end: None }
+        ];
+        assert_eq!(vec![Chapter { title: "Song".to_string(), start: Duration::ZERO, end: None }], chapters_after_cut(&segments, &chapters));
+        assert_eq!(vec![Segment::new(SegmentCategory::Sponsor, 0.0, 5.0)], segments_within(&segments[1..], Duration::from_secs(15), None));
+    }
+}
@@ -0,0 +1,322 @@
+//! Reads a download while it is still coming in, so playback can start
+//! seconds after picking a track. Providers report the file they write to
+//! in [DownloadProgress::file]; a [StreamReader] reads along and blocks
+//! at its end until more arrives or the download is over. With a
+//! [DownloadCache], whatever is streamed counts as played.
+
+use std::{fs::File, io::{self, Read, Seek, SeekFrom}, path::PathBuf, sync::{Arc, Condvar, Mutex, MutexGuard}, time::Duration};
+
+use super::{interface::{DownloadConfig, Downloaded, ProvideDownload}, progress::DownloadProgress, cache::DownloadCache};
+
+/// How long a reader waits for news before looking at the file again.
+/// Writers may buffer, so a progress report is not the only sign of data.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+#[derive(Default)]
+struct StreamState {
+    /// The growing file, once the provider said which it is
+    file: Option<PathBuf>,
+    total_bytes: Option<u64>,
+    result: Option<Result<Downloaded, String>>,
+    /// Whether anyone asked for a reader
+    read: bool
+}
+
+struct Shared {
+    state: Mutex<StreamState>,
+    /// Signalled on every progress report and when the download ends
+    changed: Condvar,
+    /// Told about the files that were streamed
+    cache: Option<DownloadCache>
+}
+
+impl Shared {
+    fn lock(&self) -> MutexGuard<'_, StreamState> {
+        self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn wait<'a>(&self, state: MutexGuard<'a, StreamState>) -> MutexGuard<'a, StreamState> {
+        self.changed.wait_timeout(state, POLL_INTERVAL)
+            .unwrap_or_else(|poisoned| poisoned.into_inner()).0
+    }
+
+    fn report(&self, progress: DownloadProgress) {
+        let mut state = self.lock();
+        if progress.file.is_some() {
+            state.file = progress.file;
+        }
+        // seeking from the end needs the real size
+        if !progress.total_is_estimate {
+            state.total_bytes = progress.total_bytes.or(state.total_bytes);
+        }
+        self.changed.notify_all();
+    }
+
+    fn finish(&self, result: Result<Downloaded, String>) {
+        let mut state = self.lock();
+        if let (Ok(downloaded), true) = (&result, state.read) {
+            self.played(downloaded);
+        }
+        state.result = Some(result);
+        self.changed.notify_all();
+    }
+
+    fn played(&self, downloaded: &Downloaded) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        for path in &downloaded.files {
+            if let Err(err) = cache.played(path) {
+                log::warn!("Cannot mark {path:?} as played: {err}");
+            }
+        }
+    }
+}
+
+/// A download running on a thread of its own, which any number of
+/// [StreamReader]s can read while it runs. Dropping it lets the download
+/// finish in the background.
+pub struct DownloadStream {
+    shared: Arc<Shared>
+}
+
+impl DownloadStream {
+    pub fn start<P: ProvideDownload + Send + Sync + 'static>(provider: Arc<P>, config: DownloadConfig) -> Self {
+        Self::spawn(provider, config, None)
+    }
+
+    /// [DownloadStream::start], marking the download as played in `cache`
+    /// once it is done, if anyone read it
+    pub fn start_cached<P: ProvideDownload + Send + Sync + 'static>(provider: Arc<P>, config: DownloadConfig, cache: DownloadCache) -> Self {
+        Self::spawn(provider, config, Some(cache))
+    }
+
+    fn spawn<P: ProvideDownload + Send + Sync + 'static>(provider: Arc<P>, config: DownloadConfig, cache: Option<DownloadCache>) -> Self {
+        let shared = Arc::new(Shared { state: Mutex::new(StreamState::default()), changed: Condvar::new(), cache });
+        let worker = shared.clone();
+        std::thread::spawn(move || {
+            let result = provider.download_with_progress(config, &mut |progress| worker.report(progress));
+            worker.finish(result);
+        });
+        Self { shared }
+    }
+
+    /// Blocks until there is something to read: the growing file, or the
+    /// finished one for providers that do not tell which file they write
+    /// (and downloads skipped thanks to the archive)
+    pub fn reader(&self) -> Result<StreamReader, String> {
+        let mut state = self.shared.lock();
+        if !state.read {
+            state.read = true;
+            if let Some(Ok(downloaded)) = &state.result {
+                self.shared.played(downloaded);
+            }
+        }
+        loop {
+            let (path, growing) = match (&state.result, &state.file) {
+                (Some(Err(err)), _) => return Err(err.clone()),
+                (Some(Ok(downloaded)), _) => match downloaded.path() {
+                    Some(path) => (path.to_path_buf(), false),
+                    None => return Err("The download produced no file".to_string())
+                },
+                (None, Some(file)) => (file.clone(), true),
+                (None, None) => {
+                    state = self.shared.wait(state);
+                    continue;
+                }
+            };
+            match File::open(&path) {
+                Ok(file) => return Ok(StreamReader { file, pos: 0, growing, shared: self.shared.clone() }),
+                // reported before the writer created it
+                Err(err) if growing && err.kind() == io::ErrorKind::NotFound => state = self.shared.wait(state),
+                Err(err) => return Err(format!("Cannot open {path:?}: {err}"))
+            }
+        }
+    }
+
+    /// Where the download ended up, once it is done
+    pub fn result(&self) -> Option<Result<Downloaded, String>> {
+        self.shared.lock().result.clone()
+    }
+
+    /// Blocks until the download is done
+    pub fn wait(&self) -> Result<Downloaded, String> {
+        let mut state = self.shared.lock();
+        loop {
+            if let Some(result) = &state.result {
+                return result.clone();
+            }
+            state = self.shared.wait(state);
+        }
+    }
+}
+
+/// Reads a file that may still be growing. At the end of what is there,
+/// reads block until more arrives; they only return 0 once the download
+/// succeeded, and fail if it failed.
+pub struct StreamReader {
+    file: File,
+    pos: u64,
+    /// Whether the download may still append to the file
+    growing: bool,
+    shared: Arc<Shared>
+}
+
+impl StreamReader {
+    /// Whether all of the file is there
+    pub fn is_complete(&self) -> bool {
+        !self.growing || matches!(self.shared.lock().result, Some(Ok(_)))
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.file.read(buf)?;
+            if read > 0 || !self.growing || buf.is_empty() {
+                self.pos += read as u64;
+                return Ok(read);
+            }
+            let state = self.shared.lock();
+            match &state.result {
+                Some(Err(err)) => return Err(io::Error::other(err.clone())),
+                // one more read picks up whatever came in last
+                Some(Ok(_)) => self.growing = false,
+                None => drop(self.shared.wait(state))
+            }
+        }
+    }
+}
+
+impl Seek for StreamReader {
+    /// Seeking from the end waits for the exact total size to be known,
+    /// which for providers that only estimate it means the end of the
+    /// download
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::End(offset) if self.growing => {
+                let mut state = self.shared.lock();
+                let total = loop {
+                    match (&state.result, state.total_bytes) {
+                        (Some(Err(err)), _) => return Err(io::Error::other(err.clone())),
+                        (Some(Ok(_)), _) => break self.file.metadata()?.len(),
+                        (None, Some(total)) => break total,
+                        (None, None) => state = self.shared.wait(state)
+                    }
+                };
+                SeekFrom::Start(total.checked_add_signed(offset)
+                    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before the start of the stream"))?)
+            },
+            pos => pos
+        };
+        self.pos = self.file.seek(pos)?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use crate::common::self_setup::SelfSetup;
+    use super::{*, super::{cache::CacheConfig, progress::{DownloadPhase, ProgressCallback, ProgressMeter}}};
+
+    /// Writes `chunks` one by one into `<local_path>/track.part`, taking
+    /// its time, then fails if asked to or renames it
+    struct SlowProvider {
+        chunks: Vec<&'static [u8]>,
+        fail: bool
+    }
+
+    impl SelfSetup for SlowProvider {
+        fn setup(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    impl ProvideDownload for SlowProvider {
+        fn download_with_progress(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+            let part = config.local_path.join("track.part");
+            let mut file = File::create(&part).map_err(|err| err.to_string())?;
+            let meter = ProgressMeter::new(Some(self.chunks.iter().map(|chunk| chunk.len() as u64).sum()));
+            let mut written = 0;
+            for chunk in &self.chunks {
+                std::thread::sleep(Duration::from_millis(50));
+                file.write_all(chunk).map_err(|err| err.to_string())?;
+                written += chunk.len() as u64;
+                on_progress(DownloadProgress { file: Some(part.clone()), ..meter.update(written) });
+            }
+            if self.fail {
+                return Err("connection reset".to_string());
+            }
+            let target = config.local_path.join("track.mp3");
+            std::fs::rename(&part, &target).map_err(|err| err.to_string())?;
+            on_progress(DownloadProgress::phase(DownloadPhase::Finished));
+            Ok(Downloaded::file(target))
+        }
+    }
+
+    fn config(dir: &tempfile::TempDir) -> DownloadConfig {
+        DownloadConfig::new("https://cdn.example/track.mp3", dir.path())
+    }
+
+    fn stream(dir: &tempfile::TempDir, fail: bool) -> DownloadStream {
+        let provider = SlowProvider { chunks: vec![b"ID3", b"frame1", b"frame2", b"frame3"], fail };
+        DownloadStream::start(Arc::new(provider), config(dir))
+    }
+
+    #[test]
+    fn reads_while_downloading() {
+        let dir = tempfile::tempdir().unwrap();
+        let stream = stream(&dir, false);
+        let mut reader = stream.reader().unwrap();
+        let mut first = [0; 3];
+        reader.read_exact(&mut first).unwrap();
+        assert_eq!(b"ID3", &first);
+        assert_eq!(None, stream.result());
+
+        assert_eq!(21, reader.seek(SeekFrom::End(0)).unwrap());
+        reader.seek(SeekFrom::Start(3)).unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!("frame1frame2frame3", rest);
+        assert!(reader.is_complete());
+        assert_eq!(Ok(Downloaded::file(dir.path().join("track.mp3"))), stream.wait());
+
+        // once done, readers get the final file
+        let mut all = String::new();
+        stream.reader().unwrap().read_to_string(&mut all).unwrap();
+        assert_eq!("ID3frame1frame2frame3", all);
+    }
+
+    #[test]
+    fn streamed_downloads_count_as_played() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = |dir: &tempfile::TempDir| DownloadCache::new(CacheConfig {
+            dir: dir.path().to_path_buf(),
+            quota_bytes: None,
+            state_path: dir.path().join("cache.json")
+        });
+        let provider = Arc::new(SlowProvider { chunks: vec![b"ID3", b"frame1"], fail: false });
+        let stream = DownloadStream::start_cached(provider.clone(), config(&dir), cache(&dir));
+        stream.reader().unwrap().read_to_end(&mut vec![]).unwrap();
+        stream.wait().unwrap();
+        let files = cache(&dir).files().unwrap();
+        assert_eq!((dir.path().join("track.mp3"), true), (files[0].path.clone(), files[0].played));
+
+        // nobody listened to this one
+        let other = tempfile::tempdir().unwrap();
+        DownloadStream::start_cached(provider, config(&other), cache(&other)).wait().unwrap();
+        assert!(!cache(&other).files().unwrap()[0].played);
+    }
+
+    #[test]
+    fn fails_readers_with_the_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let stream = stream(&dir, true);
+        let mut reader = stream.reader().unwrap();
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert!(err.to_string().contains("connection reset"), "{err}");
+        assert!(stream.wait().is_err());
+    }
+}
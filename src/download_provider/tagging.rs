@@ -0,0 +1,151 @@
+//! Writes [TrackMetadata] into the tags of downloaded files (ID3v2 for
+//! MP3, Vorbis comments for Opus/Ogg/FLAC, atoms for MP4), so other
+//! players can make sense of them.
+
+use std::{path::Path, time::Duration};
+
+use lofty::{Accessor, ItemKey, ItemValue, Tag, TagExt, TagItem};
+use serde_json::Value;
+
+use crate::common::metadata::TrackMetadata;
+
+/// Reads the metadata yt-dlp reports about a video, as printed by
+/// `--print "%()j"`. Music-specific fields win over the generic ones.
+/// ```
+/// use cli_music_player::download_provider::tagging::metadata_from_info_json;
+///
+/// let info = serde_json::json!({
+///     "title": "Pink Floyd - Money (Official Audio)",
+///     "track": "Money",
+///     "uploader": "Pink Floyd - Topic",
+///     "upload_date": "20160801",
+///     "webpage_url": "https://www.youtube.com/watch?v=abc"
+/// });
+/// let metadata = metadata_from_info_json(&info);
+/// assert_eq!(Some("Money".to_string()), metadata.title);
+/// assert_eq!(Some("Pink Floyd".to_string()), metadata.artist);
+/// assert_eq!(Some(2016), metadata.year);
+/// ```
+pub fn metadata_from_info_json(info: &Value) -> TrackMetadata {
+    let text = |keys: &[&str]| keys.iter()
+        .filter_map(|key| info.get(key).and_then(Value::as_str))
+        .map(str::trim)
+        .find(|value| !value.is_empty())
+        .map(str::to_string);
+    let number = |key: &str| info.get(key).and_then(Value::as_u64);
+    TrackMetadata {
+        title: text(&["track", "title"]),
+        // "<artist> - Topic" channels are auto-generated for labels' uploads
+        artist: text(&["artist", "creator", "uploader", "channel"])
+            .map(|artist| artist.strip_suffix(" - Topic").unwrap_or(&artist).to_string()),
+        album: text(&["album"]),
+        album_artist: text(&["album_artist"]),
+        track_number: number("track_number").map(|n| n as u32),
+        track_total: None,
+        year: number("release_year")
+            .map(|year| year as i32)
+            .or_else(|| text(&["release_date", "upload_date"]).and_then(|date| date.get(..4)?.parse().ok())),
+        duration: info.get("duration").and_then(Value::as_f64).filter(|secs| *secs >= 0.0).map(Duration::from_secs_f64),
+        source_url: text(&["webpage_url", "original_url"]),
+        thumbnail_url: text(&["thumbnail"])
+    }
+}
+
+/// Writes every known field of `metadata` into the primary tag of the
+/// file at `path`, creating the tag if needed. Fields we know nothing
+/// about are left as they are.
+pub fn write_tags(path: &Path, metadata: &TrackMetadata) -> Result<(), String> {
+    let mut tagged = lofty::read_from_path(path, false)
+        .map_err(|err| format!("Cannot read tags of {path:?}: {err}"))?;
+    let tag_type = tagged.primary_tag_type();
+    if tagged.tag(tag_type).is_none() {
+        tagged.insert_tag(Tag::new(tag_type));
+    }
+    let tag = tagged.tag_mut(tag_type).expect("tag was just inserted");
+    if let Some(title) = &metadata.title {
+        tag.set_title(title.clone());
+    }
+    if let Some(artist) = &metadata.artist {
+        tag.set_artist(artist.clone());
+    }
+    if let Some(album) = &metadata.album {
+        tag.set_album(album.clone());
+    }
+    if let Some(album_artist) = &metadata.album_artist {
+        tag.insert_text(ItemKey::AlbumArtist, album_artist.clone());
+    }
+    if let Some(track) = metadata.track_number {
+        tag.set_track(track);
+    }
+    if let Some(total) = metadata.track_total {
+        tag.set_track_total(total);
+    }
+    if let Some(year) = metadata.year {
+        tag.insert_text(ItemKey::RecordingDate, year.to_string());
+    }
+    if let Some(url) = &metadata.source_url {
+        // only ID3v2 has a field for it
+        if !tag.insert_item(TagItem::new(ItemKey::AudioSourceURL, ItemValue::Locator(url.clone()))) {
+            tag.set_comment(url.clone());
+        }
+    }
+    tag.save_to_path(path).map_err(|err| format!("Cannot write tags of {path:?}: {err}"))
+}
+
+/// [write_tags], logging failures. Containers lofty cannot write (WebM, ...)
+/// stay untagged, and the file name still says what the track is.
+pub fn tag_or_warn(path: &Path, metadata: &TrackMetadata) {
+    if let Err(err) = write_tags(path, metadata) {
+        log::warn!("Leaving {path:?} untagged: {err}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// A few silent MPEG-1 Layer III frames, enough for lofty to
+    /// recognize the file
+    fn silent_mp3() -> Vec<u8> {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x64];
+        frame.resize(417, 0);
+        frame.repeat(4)
+    }
+
+    #[test]
+    fn tags_mp3_with_id3v2() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("money.mp3");
+        std::fs::write(&path, silent_mp3()).unwrap();
+        let metadata = TrackMetadata {
+            title: Some("Money".to_string()),
+            artist: Some("Pink Floyd".to_string()),
+            album: Some("The Dark Side of the Moon".to_string()),
+            track_number: Some(6),
+            year: Some(1973),
+            source_url: Some("https://www.youtube.com/watch?v=abc".to_string()),
+            ..Default::default()
+        };
+        write_tags(&path, &metadata).unwrap();
+
+        let tagged = lofty::read_from_path(&path, false).unwrap();
+        let tag = tagged.primary_tag().unwrap();
+        assert_eq!(Some("Money"), tag.title());
+        assert_eq!(Some("Pink Floyd"), tag.artist());
+        assert_eq!(Some("The Dark Side of the Moon"), tag.album());
+        assert_eq!(Some(6), tag.track());
+        assert_eq!(Some(1973), tag.year());
+        assert_eq!(Some("https://www.youtube.com/watch?v=abc"), tag.get_locators(&ItemKey::AudioSourceURL).next());
+
+        // the audio is still there
+        assert!(std::fs::read(&path).unwrap().ends_with(&silent_mp3()));
+    }
+
+    #[test]
+    fn rejects_unknown_formats() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("notes.txt");
+        std::fs::write(&path, "not audio").unwrap();
+        assert!(write_tags(&path, &TrackMetadata::default()).is_err());
+    }
+}
@@ -0,0 +1,215 @@
+//! Filename templates such as `{artist}/{album}/{track:02} - {title}.{ext}`,
+//! filled from [TrackMetadata] to decide where a download lands inside
+//! [DownloadConfig::local_path].
+//!
+//! Placeholders: `title`, `artist`, `album`, `album_artist`, `track`,
+//! `track_total`, `year` and `ext`. Numbers take an optional zero-padded
+//! width (`{track:02}`). Missing text fields become "Unknown <Field>" and
+//! missing numbers become 0.
+
+use std::path::{Component, Path, PathBuf};
+
+use crate::common::metadata::TrackMetadata;
+
+use super::interface::DownloadConfig;
+
+/// Longest file or directory name we produce, in bytes. Most filesystems
+/// stop at 255; the rest leaves room for `.part` and collision suffixes.
+pub const MAX_COMPONENT_BYTES: usize = 240;
+
+/// Makes a metadata value safe to use as (part of) a single path
+/// component: separators and characters Windows rejects become `_`, and
+/// leading dots (hidden files, `..`) and trailing dots and spaces go.
+/// ```
+/// use cli_music_player::download_provider::template::sanitize;
+///
+/// assert_eq!("AC_DC", sanitize("AC/DC"));
+/// assert_eq!("What_ Why_", sanitize("What? Why?"));
+/// assert_eq!("_etc_passwd", sanitize("../etc/passwd"));
+/// assert_eq!("_", sanitize(" .. "));
+/// ```
+pub fn sanitize<AnyStr: AsRef<str>>(value: AnyStr) -> String {
+    let replaced = value.as_ref().chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+            c if c.is_control() => '_',
+            c => c
+        })
+        .collect::<String>();
+    let trimmed = replaced.trim().trim_start_matches('.').trim_end_matches(['.', ' ']);
+    if trimmed.is_empty() { "_".to_string() } else { trimmed.to_string() }
+}
+
+/// Cuts `name` down to [MAX_COMPONENT_BYTES] on a character boundary,
+/// keeping `extension` (without its dot) intact if `name` ends with it
+fn truncate(name: &str, extension: Option<&str>) -> String {
+    let suffix = extension.map(|ext| format!(".{ext}")).filter(|suffix| name.ends_with(suffix.as_str())).unwrap_or_default();
+    let stem = &name[..name.len() - suffix.len()];
+    if stem.len() + suffix.len() <= MAX_COMPONENT_BYTES {
+        return name.to_string();
+    }
+    let mut end = MAX_COMPONENT_BYTES.saturating_sub(suffix.len());
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}{suffix}", stem[..end].trim_end())
+}
+
+fn text(value: &Option<String>, fallback: &str) -> String {
+    sanitize(value.as_deref().filter(|value| !value.trim().is_empty()).unwrap_or(fallback))
+}
+
+fn number<N: ToString>(value: Option<N>, width: usize) -> String {
+    format!("{:0>width$}", value.map(|n| n.to_string()).unwrap_or_else(|| "0".to_string()))
+}
+
+/// Fills `template` in, returning a path relative to the download dir
+/// ```
+/// use std::path::PathBuf;
+/// use cli_music_player::{common::metadata::TrackMetadata, download_provider::template::render};
+///
+/// let metadata = TrackMetadata {
+///     title: Some("Money".to_string()),
+///     artist: Some("Pink Floyd".to_string()),
+///     album: Some("The Dark Side of the Moon".to_string()),
+///     track_number: Some(6),
+///     ..Default::default()
+/// };
+/// let path = render("{artist}/{album}/{track:02} - {title}.{ext}", &metadata, "opus");
+/// assert_eq!(Ok(PathBuf::from("Pink Floyd/The Dark Side of the Moon/06 - Money.opus")), path);
+/// assert_eq!(Ok(PathBuf::from("Unknown Artist - Unknown Title (0).m4a")),
+///     render("{artist} - {title} ({year}).{ext}", &TrackMetadata::default(), "m4a"));
+/// assert!(render("{genre}.{ext}", &metadata, "opus").is_err());
+/// assert!(render("../{title}", &metadata, "opus").is_err());
+/// ```
+pub fn render<AnyStr: AsRef<str>>(template: AnyStr, metadata: &TrackMetadata, ext: &str) -> Result<PathBuf, String> {
+    let template = template.as_ref();
+    let mut rendered = String::new();
+    let mut rest = template;
+    while let Some(open) = rest.find(['{', '}']) {
+        if rest[open..].starts_with('}') {
+            return Err(format!("Unmatched '}}' in filename template {template:?}"));
+        }
+        rendered.push_str(&rest[..open]);
+        let close = rest[open..].find('}')
+            .ok_or_else(|| format!("Unclosed '{{' in filename template {template:?}"))? + open;
+        let placeholder = &rest[open + 1..close];
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, width)) => (name, width.parse::<usize>()
+                .map_err(|_| format!("Bad width {width:?} for {{{name}}} in filename template {template:?}"))?),
+            None => (placeholder, 0)
+        };
+        rendered.push_str(&match name {
+            "title" => text(&metadata.title, "Unknown Title"),
+            "artist" => text(&metadata.artist, "Unknown Artist"),
+            "album" => text(&metadata.album, "Unknown Album"),
+            "album_artist" => text(&metadata.album_artist.clone().or_else(|| metadata.artist.clone()), "Unknown Artist"),
+            "track" => number(metadata.track_number, width),
+            "track_total" => number(metadata.track_total, width),
+            "year" => number(metadata.year, width),
+            "ext" => sanitize(ext),
+            _ => return Err(format!("Unknown placeholder {{{name}}} in filename template {template:?}; \
+                known are title, artist, album, album_artist, track, track_total, year and ext"))
+        });
+        rest = &rest[close + 1..];
+    }
+    rendered.push_str(rest);
+
+    let path = Path::new(&rendered);
+    if !path.components().all(|component| matches!(component, Component::Normal(_))) {
+        return Err(format!("Filename template {template:?} must stay inside the download dir, got {rendered:?}"));
+    }
+    let components = path.components().collect::<Vec<_>>();
+    Ok(components.iter().enumerate()
+        .map(|(idx, component)| {
+            let name = component.as_os_str().to_string_lossy();
+            truncate(&name, (idx + 1 == components.len()).then_some(ext))
+        })
+        .collect())
+}
+
+/// `path` itself if nothing is there, else the first free
+/// `<stem> (<n>).<ext>` next to it
+pub fn unique_path(path: &Path) -> PathBuf {
+    if !path.exists() {
+        return path.to_path_buf();
+    }
+    let stem = path.file_stem().map(|stem| stem.to_string_lossy().to_string()).unwrap_or_default();
+    let ext = path.extension().map(|ext| format!(".{}", ext.to_string_lossy())).unwrap_or_default();
+    (2..).map(|n| path.with_file_name(format!("{stem} ({n}){ext}")))
+        .find(|candidate| !candidate.exists())
+        .expect("some suffix is free")
+}
+
+/// Moves a finished download to where [DownloadConfig::filename_template]
+/// says, creating directories as needed. Without a template, or when the
+/// file already is there, `downloaded` is returned untouched.
+/// `metadata` falls back to [DownloadConfig::metadata], and the title to
+/// the downloaded file's name.
+pub fn place(downloaded: &Path, config: &DownloadConfig, metadata: Option<&TrackMetadata>) -> Result<PathBuf, String> {
+    let Some(template) = &config.filename_template else {
+        return Ok(downloaded.to_path_buf());
+    };
+    let mut metadata = metadata.or(config.metadata.as_ref()).cloned().unwrap_or_default();
+    if metadata.title.is_none() {
+        metadata.title = downloaded.file_stem().map(|stem| stem.to_string_lossy().to_string());
+    }
+    let ext = downloaded.extension().map(|ext| ext.to_string_lossy().to_string()).unwrap_or_default();
+    let wanted = config.local_path.join(render(template, &metadata, &ext)?);
+    if wanted == downloaded {
+        return Ok(wanted);
+    }
+    let target = unique_path(&wanted);
+    if let Some(dir) = target.parent().filter(|dir| !dir.exists()) {
+        std::fs::create_dir_all(dir).map_err(|err| format!("Cannot std::fs::create_dir_all({dir:?}): {err:?}"))?;
+    }
+    std::fs::rename(downloaded, &target).map_err(|err| format!("Cannot move {downloaded:?} to {target:?}: {err}"))?;
+    Ok(target)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn places_with_limits_and_collisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DownloadConfig {
+            metadata: Some(TrackMetadata {
+                title: Some("Ω".repeat(200)),
+                artist: Some("AC/DC".to_string()),
+                ..Default::default()
+            }),
+            filename_template: Some("{artist}/{title}.{ext}".to_string()),
+            ..DownloadConfig::new("https://example.invalid/a", dir.path())
+        };
+        let first = dir.path().join("first.mp3");
+        std::fs::write(&first, "1").unwrap();
+        let placed = place(&first, &config, None).unwrap();
+        let name = placed.file_name().unwrap().to_string_lossy().to_string();
+        assert_eq!(dir.path().join("AC_DC"), placed.parent().unwrap());
+        assert!(name.len() <= MAX_COMPONENT_BYTES && name.ends_with("ΩΩ.mp3"), "{name}");
+
+        let second = dir.path().join("second.mp3");
+        std::fs::write(&second, "2").unwrap();
+        let collided = place(&second, &config, None).unwrap();
+        assert!(collided.to_string_lossy().ends_with("Ω (2).mp3"), "{collided:?}");
+        assert_eq!("2", std::fs::read_to_string(collided).unwrap());
+
+        // metadata of the file itself wins, and the title falls back to the file name
+        let third = dir.path().join("Live at Pompeii.mp3");
+        std::fs::write(&third, "3").unwrap();
+        let own = TrackMetadata { artist: Some("Pink Floyd".to_string()), ..Default::default() };
+        assert_eq!(Ok(dir.path().join("Pink Floyd/Live at Pompeii.mp3")), place(&third, &config, Some(&own)));
+    }
+
+    #[test]
+    fn truncates_without_adding_an_extension() {
+        let metadata = TrackMetadata { title: Some("a".repeat(300)), ..Default::default() };
+        assert_eq!(Ok(PathBuf::from("a".repeat(MAX_COMPONENT_BYTES))), render("{title}", &metadata, "mp3"));
+        let name = render("{title}.{ext} (live)", &metadata, "mp3").unwrap().to_string_lossy().to_string();
+        assert_eq!(MAX_COMPONENT_BYTES, name.len());
+        assert!(!name.contains(".mp3"), "{name}");
+        assert_eq!(Ok(PathBuf::from(format!("{}.mp3", "a".repeat(MAX_COMPONENT_BYTES - 4)))), render("{title}.{ext}", &metadata, "mp3"));
+    }
+}
@@ -1,17 +1,825 @@
-use crate::common::self_setup::SelfSetup;
+//! Implementation of a download provider that drives yt-dlp (or a fork
+//! accepting the same flags) as a child process.
 
-use super::interface::{ProvideDownload, DownloadConfig};
+use std::{fmt::Display, io::{BufRead, BufReader, Read}, path::{Path, PathBuf}, process::{Command, Output, Stdio}, sync::mpsc, time::Duration};
 
-pub struct YoutubeDL;
+use serde::{Deserialize, Serialize};
+
+use crate::common::{self_setup::{SelfSetup, SetupConfig}, install::{self, PinnedRelease, installed_path}, factory::FactoryError};
+
+use super::{interface::{ProvideDownload, DownloadConfig, Downloaded, check_http_uri}, batch::BatchItem, template, tagging, loudness, chapters::{self, SplitPlan}, segments::{self, SegmentAction, SegmentOptions}, archive::DownloadArchive, network::{NetworkOptions, USER_AGENT}, cover_art::{self, CoverArtOptions}, progress::{DownloadPhase, DownloadProgress, ProgressCallback}};
+
+/// Marks the lines our `--progress-template`s produce
+const PROGRESS_MARK: &str = "[cmp-progress]";
+const POSTPROCESS_MARK: &str = "[cmp-postprocess]";
+
+/// Parses one line of yt-dlp output produced by our progress templates.
+/// yt-dlp prints "NA" for values it does not know.
+/// ```
+/// use std::{path::PathBuf, time::Duration};
+/// use cli_music_player::download_provider::{youtube_dl::parse_progress_line, progress::DownloadPhase};
+///
+/// let progress = parse_progress_line("[cmp-progress] downloading 1024 4096 NA 512.5 6").unwrap();
+/// assert_eq!(DownloadPhase::Fetching, progress.phase);
+/// assert_eq!((1024, Some(4096)), (progress.downloaded_bytes, progress.total_bytes));
+/// assert_eq!((Some(512.5), Some(Duration::from_secs(6))), (progress.speed, progress.eta));
+///
+/// let estimated = parse_progress_line("[cmp-progress] downloading 10 NA 4000.0 NA NA NA").unwrap();
+/// assert_eq!(Some(4000), estimated.total_bytes);
+/// assert_eq!(None, estimated.file);
+///
+/// let named = parse_progress_line("[cmp-progress] downloading 10 20 NA NA NA /music/Money [abc].webm.part").unwrap();
+/// assert_eq!(Some(PathBuf::from("/music/Money [abc].webm.part")), named.file);
+///
+/// let tagging = parse_progress_line("[cmp-postprocess] FFmpegMetadata started").unwrap();
+/// assert_eq!(DownloadPhase::Tagging, tagging.phase);
+/// assert_eq!(None, parse_progress_line("[download] Destination: x.m4a"));
+/// ```
+pub fn parse_progress_line(line: &str) -> Option<DownloadProgress> {
+    let number = |field: Option<&str>| field.and_then(|value| value.parse::<f64>().ok()).filter(|value| value.is_finite() && *value >= 0.0);
+    if let Some(rest) = line.trim().strip_prefix(PROGRESS_MARK) {
+        // the file name comes last, as it may contain spaces
+        let mut fields = rest.trim_start().splitn(7, ' ');
+        let _status = fields.next()?;
+        let downloaded = number(fields.next());
+        let total = number(fields.next());
+        let estimate = number(fields.next());
+        return Some(DownloadProgress {
+            phase: DownloadPhase::Fetching,
+            downloaded_bytes: downloaded.unwrap_or(0.0) as u64,
+            total_bytes: total.or(estimate).map(|total| total as u64),
+            total_is_estimate: total.is_none() && estimate.is_some(),
+            speed: number(fields.next()),
+            eta: number(fields.next()).map(Duration::from_secs_f64),
+            file: fields.next().map(str::trim).filter(|file| !file.is_empty() && *file != "NA").map(PathBuf::from)
+        });
+    }
+    let postprocessor = line.trim().strip_prefix(POSTPROCESS_MARK)?.split_whitespace().next()?;
+    Some(DownloadProgress::phase(match postprocessor {
+        pp if pp.contains("Metadata") || pp.contains("EmbedThumbnail") => DownloadPhase::Tagging,
+        _ => DownloadPhase::PostProcessing
+    }))
+}
+
+/// Why a yt-dlp invocation failed, derived from its exit code and stderr.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum YoutubeDLError {
+    /// The executable could not be started at all
+    NotInstalled { executable: PathBuf, reason: String },
+    /// Exit code 1: the extractor or the download itself failed.
+    /// `message` is the last `ERROR:` line yt-dlp printed.
+    DownloadFailed { uri: String, message: String },
+    /// Exit code 2: yt-dlp rejected the options we passed
+    InvalidOptions { message: String },
+    /// Exit code 100: yt-dlp updated itself and must be restarted
+    RestartRequired,
+    /// Exit code 101: stopped by `--max-downloads` or similar limits
+    Cancelled,
+    /// Terminated by a signal, e.g. killed by the user
+    Killed,
+    /// Any other exit code
+    Other { code: i32, message: String },
+    /// yt-dlp succeeded but did not tell us where the file went
+    MissingOutput { uri: String, stdout: String }
+}
+
+impl Display for YoutubeDLError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::NotInstalled { executable, reason } =>
+                write!(f, "Cannot run {executable:?} ({reason}); is yt-dlp installed? Try running setup"),
+            Self::DownloadFailed { uri, message } => write!(f, "yt-dlp failed to download {uri}: {message}"),
+            Self::InvalidOptions { message } => write!(f, "yt-dlp rejected its options: {message}"),
+            Self::RestartRequired => write!(f, "yt-dlp updated itself and needs to be run again"),
+            Self::Cancelled => write!(f, "yt-dlp cancelled the download due to a download limit"),
+            Self::Killed => write!(f, "yt-dlp was terminated by a signal"),
+            Self::Other { code, message } => write!(f, "yt-dlp exited with code {code}: {message}"),
+            Self::MissingOutput { uri, stdout } =>
+                write!(f, "yt-dlp downloaded {uri} but reported no existing file (stdout: {stdout:?})")
+        }
+    }
+}
+
+impl From<YoutubeDLError> for String {
+    fn from(err: YoutubeDLError) -> Self {
+        err.to_string()
+    }
+}
+
+impl YoutubeDLError {
+    /// The most useful line of stderr: the last `ERROR:` line if any,
+    /// otherwise the last non-empty line
+    fn summarize(stderr: &str) -> String {
+        let mut lines = stderr.lines().rev().map(str::trim).filter(|line| !line.is_empty());
+        lines.clone().find(|line| line.starts_with("ERROR:"))
+            .or_else(|| lines.next())
+            .map(|line| line.trim_start_matches("ERROR:").trim().to_string())
+            .unwrap_or_default()
+    }
+
+    pub fn from_output(uri: &str, output: &Output) -> Self {
+        let message = Self::summarize(&String::from_utf8_lossy(&output.stderr));
+        match output.status.code() {
+            Some(1) => Self::DownloadFailed { uri: uri.to_string(), message },
+            Some(2) => Self::InvalidOptions { message },
+            Some(100) => Self::RestartRequired,
+            Some(101) => Self::Cancelled,
+            Some(code) => Self::Other { code, message },
+            None => Self::Killed
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct YoutubeDL {
+    /// The yt-dlp program to run. When None, our own install in
+    /// [SetupConfig::exec_path] is preferred over `yt-dlp` on PATH.
+    ///
+    /// Default: None
+    pub executable: Option<PathBuf>,
+    /// The ffmpeg yt-dlp post-processes with. When None, our own install
+    /// is used if there is one; otherwise yt-dlp looks on PATH.
+    ///
+    /// Default: None
+    pub ffmpeg: Option<PathBuf>,
+    /// yt-dlp output template, relative to [DownloadConfig::local_path].
+    /// See yt-dlp's "OUTPUT TEMPLATE" documentation.
+    ///
+    /// Default: "%(title)s [%(id)s].%(ext)s"
+    pub output_template: String,
+    /// Passed verbatim before the URI, for options this crate does not
+    /// expose yet.
+    ///
+    /// Default: []
+    pub extra_args: Vec<String>,
+    /// Tag downloads with what the search result and yt-dlp know about them
+    ///
+    /// Default: true
+    pub write_tags: bool,
+    /// Measure loudness and write ReplayGain tags, with ffmpeg
+    ///
+    /// Default: true
+    pub replay_gain: bool,
+    /// Split videos into one file per chapter, or per timestamp listed in
+    /// the description, as full-album uploads have them. See
+    /// [YoutubeDL::split_plan] to preview the split points.
+    ///
+    /// Default: false
+    pub split_chapters: bool,
+    /// Non-music parts of videos to cut out or mark for skipping
+    ///
+    /// Default: [SegmentOptions::default]
+    pub segments: SegmentOptions,
+    /// Proxy and rate limits
+    ///
+    /// Default: [NetworkOptions::default]
+    pub network: NetworkOptions,
+    /// What to do with the video's thumbnail
+    ///
+    /// Default: [CoverArtOptions::default]
+    pub cover_art: CoverArtOptions,
+    /// Remembers downloaded videos
+    ///
+    /// Default: [DownloadArchive::default]
+    pub archive: DownloadArchive,
+    /// Where [SelfSetup::setup] installs whatever is missing
+    ///
+    /// Default: [SetupConfig::default]
+    pub setup: SetupConfig,
+    /// Installed when no yt-dlp is found
+    ///
+    /// Default: [PinnedRelease::yt_dlp]
+    pub yt_dlp_release: PinnedRelease,
+    /// Installed when no ffmpeg is found
+    ///
+    /// Default: [PinnedRelease::ffmpeg]
+    pub ffmpeg_release: PinnedRelease
+}
+
+impl Default for YoutubeDL {
+    fn default() -> Self {
+        Self {
+            executable: None,
+            ffmpeg: None,
+            output_template: "%(title)s [%(id)s].%(ext)s".to_string(),
+            extra_args: vec![],
+            write_tags: true,
+            replay_gain: true,
+            split_chapters: false,
+            segments: SegmentOptions::default(),
+            network: NetworkOptions::default(),
+            cover_art: CoverArtOptions::default(),
+            archive: DownloadArchive::default(),
+            setup: SetupConfig::default(),
+            yt_dlp_release: PinnedRelease::yt_dlp(),
+            ffmpeg_release: PinnedRelease::ffmpeg()
+        }
+    }
+}
+
+impl YoutubeDL {
+    pub fn new<P: Into<PathBuf>>(executable: P) -> Self {
+        Self { executable: Some(executable.into()), ..Default::default() }
+    }
+
+    /// The video id in a YouTube URL, which is what the download archive
+    /// knows videos by. Other URIs are their own id.
+    /// ```
+    /// use cli_music_player::download_provider::YoutubeDL;
+    ///
+    /// assert_eq!("abc", YoutubeDL::video_id("https://www.youtube.com/watch?v=abc&list=PL1"));
+    /// assert_eq!("abc", YoutubeDL::video_id("https://music.youtube.com/watch?v=abc"));
+    /// assert_eq!("abc", YoutubeDL::video_id("https://youtu.be/abc?t=42"));
+    /// assert_eq!("abc", YoutubeDL::video_id("https://www.youtube.com/shorts/abc"));
+    /// assert_eq!("https://soundcloud.com/a/b", YoutubeDL::video_id("https://soundcloud.com/a/b"));
+    /// ```
+    pub fn video_id<AnyStr: AsRef<str>>(uri: AnyStr) -> String {
+        let uri = uri.as_ref();
+        let id = reqwest::Url::parse(uri).ok().and_then(|url| {
+            let host = url.host_str()?.trim_start_matches("www.").to_string();
+            match host.as_str() {
+                "youtu.be" => url.path_segments()?.next().map(str::to_string),
+                "youtube.com" | "music.youtube.com" | "m.youtube.com" => url.query_pairs()
+                    .find(|(key, _)| key == "v")
+                    .map(|(_, id)| id.to_string())
+                    .or_else(|| {
+                        let mut segments = url.path_segments()?;
+                        matches!(segments.next(), Some("shorts" | "embed" | "live")).then(|| segments.next().map(str::to_string)).flatten()
+                    }),
+                _ => None
+            }
+        });
+        id.filter(|id| !id.is_empty()).unwrap_or_else(|| uri.to_string())
+    }
+
+    /// The yt-dlp we are going to run
+    pub fn executable(&self) -> PathBuf {
+        self.executable.clone().unwrap_or_else(|| {
+            let installed = installed_path(&self.setup, &self.yt_dlp_release);
+            if installed.is_file() { installed } else { PathBuf::from(&self.yt_dlp_release.name) }
+        })
+    }
+
+    /// The ffmpeg to point yt-dlp at, if not the one on PATH
+    pub fn ffmpeg(&self) -> Option<PathBuf> {
+        self.ffmpeg.clone()
+            .or_else(|| Some(installed_path(&self.setup, &self.ffmpeg_release)).filter(|path| path.is_file()))
+    }
+
+    fn command(&self) -> Command {
+        let mut command = Command::new(self.executable());
+        if let Some(ffmpeg) = self.ffmpeg() {
+            command.arg("--ffmpeg-location").arg(ffmpeg);
+        }
+        command.args(self.network.yt_dlp_args());
+        command
+    }
+
+    /// Runs yt-dlp, `target` being what it works on for error reporting
+    fn run(&self, target: &str, command: &mut Command) -> Result<Output, YoutubeDLError> {
+        log::info!("Running {command:?}");
+        let output = command.output().map_err(|err| YoutubeDLError::NotInstalled {
+            executable: self.executable(),
+            reason: err.to_string()
+        })?;
+        log::debug!("yt-dlp stderr: {}", String::from_utf8_lossy(&output.stderr));
+        if output.status.success() {
+            Ok(output)
+        } else {
+            Err(YoutubeDLError::from_output(target, &output))
+        }
+    }
+
+    /// Like [YoutubeDL::run], but hands every line of stdout and stderr to
+    /// `on_line` as it comes. Lines for which `on_line` returns true are
+    /// consumed and left out of the returned output.
+    fn run_streaming(&self, target: &str, command: &mut Command, on_line: &mut dyn FnMut(&str) -> bool) -> Result<Output, YoutubeDLError> {
+        log::info!("Running {command:?}");
+        let mut child = command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()
+            .map_err(|err| YoutubeDLError::NotInstalled { executable: self.executable(), reason: err.to_string() })?;
+        let (tx, rx) = mpsc::channel::<(bool, String)>();
+        let forward = |pipe: Box<dyn Read + Send>, is_stderr: bool, tx: mpsc::Sender<(bool, String)>| std::thread::spawn(move || {
+            for line in BufReader::new(pipe).lines().map_while(Result::ok) {
+                if tx.send((is_stderr, line)).is_err() {
+                    break;
+                }
+            }
+        });
+        let readers = [
+            forward(Box::new(child.stdout.take().expect("piped stdout")), false, tx.clone()),
+            forward(Box::new(child.stderr.take().expect("piped stderr")), true, tx)
+        ];
+        let (mut stdout, mut stderr) = (String::new(), String::new());
+        for (is_stderr, line) in rx {
+            if !on_line(&line) {
+                let buf = if is_stderr { &mut stderr } else { &mut stdout };
+                buf.push_str(&line);
+                buf.push('\n');
+            }
+        }
+        readers.into_iter().for_each(|reader| { let _ = reader.join(); });
+        let status = child.wait()
+            .map_err(|err| YoutubeDLError::Other { code: -1, message: format!("Cannot wait for yt-dlp: {err}") })?;
+        log::debug!("yt-dlp stderr: {stderr}");
+        let output = Output { status, stdout: stdout.into_bytes(), stderr: stderr.into_bytes() };
+        if output.status.success() {
+            Ok(output)
+        } else {
+            Err(YoutubeDLError::from_output(target, &output))
+        }
+    }
+
+    /// Returns the version string yt-dlp reports
+    pub fn version(&self) -> Result<String, YoutubeDLError> {
+        self.run("--version", Command::new(self.executable()).arg("--version"))
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+    }
+
+    /// Lists the entries of a playlist (or channel, or album) without
+    /// downloading anything. A single video comes back as the only entry.
+    pub fn playlist<AnyStr: AsRef<str>>(&self, uri: AnyStr) -> Result<Vec<BatchItem>, String> {
+        let uri = uri.as_ref();
+        let mut command = self.command();
+        command.args(["--flat-playlist", "-J", "--"]).arg(uri);
+        let output = self.run(uri, &mut command)?;
+        let info = serde_json::from_slice::<serde_json::Value>(&output.stdout)
+            .map_err(|err| format!("yt-dlp listed {uri} as bad JSON: {err}"))?;
+        Ok(playlist_entries(&info, uri))
+    }
+
+    /// The tracks a download with [YoutubeDL::split_chapters] would split
+    /// `uri` into, without downloading anything. Display it for a preview.
+    pub fn split_plan<AnyStr: AsRef<str>>(&self, uri: AnyStr) -> Result<SplitPlan, String> {
+        let uri = uri.as_ref();
+        let mut command = self.command();
+        command.args(["--no-playlist", "-J", "--"]).arg(uri);
+        let output = self.run(uri, &mut command)?;
+        let info = serde_json::from_slice::<serde_json::Value>(&output.stdout)
+            .map_err(|err| format!("yt-dlp described {uri} as bad JSON: {err}"))?;
+        Ok(SplitPlan { chapters: chapters::chapters_from_info_json(&info) })
+    }
+}
+
+/// The entries of what `yt-dlp --flat-playlist -J` prints. Private and
+/// deleted videos stay in playlists under placeholder titles; they are
+/// marked unavailable. Entries with neither URL nor id are kept as
+/// [BatchItem::broken], so they are not lost without a word.
+/// ```
+/// use cli_music_player::download_provider::youtube_dl::playlist_entries;
+///
+/// let info = serde_json::json!({"_type": "playlist", "entries": [
+///     {"id": "abc", "url": "https://www.youtube.com/watch?v=abc", "title": "Money"},
+///     {"id": "def", "url": "https://www.youtube.com/watch?v=def", "title": "[Private video]"},
+///     {"id": "ghi", "title": "Time", "availability": "needs_auth"},
+///     {"title": "Us and Them"}
+/// ]});
+/// let entries = playlist_entries(&info, "https://www.youtube.com/playlist?list=PL1");
+/// assert_eq!(4, entries.len());
+/// assert_eq!(None, entries[0].unavailable);
+/// assert!(entries[1].unavailable.is_some() && entries[2].unavailable.is_some());
+/// assert_eq!("https://www.youtube.com/watch?v=ghi", entries[2].uri);
+/// assert_eq!(Some("Playlist entry 4 has neither URL nor id".to_string()), entries[3].broken);
+///
+/// let video = serde_json::json!({"id": "abc", "title": "Money", "webpage_url": "https://www.youtube.com/watch?v=abc"});
+/// assert_eq!("https://www.youtube.com/watch?v=abc", playlist_entries(&video, "https://youtu.be/abc")[0].uri);
+/// ```
+pub fn playlist_entries(info: &serde_json::Value, uri: &str) -> Vec<BatchItem> {
+    let text = |entry: &serde_json::Value, key: &str| entry.get(key).and_then(serde_json::Value::as_str).map(str::to_string);
+    let Some(entries) = info.get("entries").and_then(serde_json::Value::as_array) else {
+        return vec![BatchItem { title: text(info, "title"), ..BatchItem::new(text(info, "webpage_url").unwrap_or_else(|| uri.to_string())) }];
+    };
+    entries.iter()
+        .enumerate()
+        .map(|(idx, entry)| {
+            let title = text(entry, "title");
+            let Some(entry_uri) = text(entry, "url")
+                .or_else(|| text(entry, "id").map(|id| format!("https://www.youtube.com/watch?v={id}"))) else {
+                return BatchItem { title, broken: Some(format!("Playlist entry {} has neither URL nor id", idx + 1)), ..BatchItem::new(uri) };
+            };
+            let unavailable = match (title.as_deref(), text(entry, "availability").as_deref()) {
+                (Some(placeholder @ ("[Private video]" | "[Deleted video]")), _) => Some(format!("{placeholder} in playlist")),
+                (_, Some(availability @ ("private" | "needs_auth" | "subscriber_only" | "premium_only"))) => Some(format!("Video is {availability}")),
+                _ => None
+            };
+            BatchItem { uri: entry_uri, title, unavailable, broken: None }
+        })
+        .collect()
+}
 
 impl SelfSetup for YoutubeDL {
+    /// Makes sure both yt-dlp and ffmpeg can be run, installing the pinned
+    /// releases into [SetupConfig::exec_path] when they cannot be found.
     fn setup(&self) -> Result<(), String> {
-        todo!()
+        self.setup.create_dirs()?;
+        let client = self.network.client(USER_AGENT)?;
+        let yt_dlp = install::ensure_installed(&client, &self.setup, &self.yt_dlp_release, self.executable.as_deref())?;
+        let ffmpeg = install::ensure_installed(&client, &self.setup, &self.ffmpeg_release, self.ffmpeg.as_deref())?;
+        log::info!("Using {yt_dlp:?} and {ffmpeg:?}");
+        Ok(())
+    }
+}
+
+impl YoutubeDL {
+    fn download_unarchived(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+        // yt-dlp limits its rate itself; the throttle counts it among the running
+        // downloads and what it fetches against the global limit
+        let throttle = self.network.throttle();
+        let mut command = self.command();
+        command
+            .arg("--no-playlist")
+            // --print implies --simulate and --quiet; we want the download, its progress and the final path
+            .arg("--no-simulate")
+            .args(["--print", "after_move:filepath", "--print", "after_move:%()j"])
+            .args(["--progress", "--newline"])
+            .arg("--progress-template").arg(format!("download:{PROGRESS_MARK} %(progress.status)s %(progress.downloaded_bytes)s \
+                %(progress.total_bytes)s %(progress.total_bytes_estimate)s %(progress.speed)s %(progress.eta)s %(progress.tmpfilename)s"))
+            .arg("--progress-template").arg(format!("postprocess:{POSTPROCESS_MARK} %(progress.postprocessor)s %(progress.status)s"))
+            .args(config.format.yt_dlp_args())
+            .args(throttle.rate().into_iter().flat_map(|rate| ["--limit-rate".to_string(), rate.to_string()]))
+            .arg("-P").arg(&config.local_path)
+            .arg("-o").arg(&self.output_template)
+            .args(&self.extra_args)
+            .arg("--")
+            .arg(&config.uri);
+        let mut last = DownloadProgress::phase(DownloadPhase::Fetching);
+        let output = self.run_streaming(&config.uri, &mut command, &mut |line| match parse_progress_line(line) {
+            Some(progress) => {
+                // post-processing reports carry no byte counts; keep the last known ones
+                last = match progress.phase {
+                    DownloadPhase::Fetching => {
+                        // counts start over with every file yt-dlp fetches (e.g. video, then audio)
+                        let fetched = match progress.file == last.file {
+                            true => progress.downloaded_bytes.saturating_sub(last.downloaded_bytes),
+                            false => progress.downloaded_bytes
+                        };
+                        throttle.record(fetched);
+                        progress
+                    },
+                    phase => DownloadProgress { phase, ..last.clone() }
+                };
+                on_progress(last.clone());
+                true
+            },
+            None => false
+        })?;
+        drop(throttle);
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        let path = stdout.lines().rev()
+            .filter(|line| !line.starts_with('{'))
+            .map(|line| PathBuf::from(line.trim()))
+            .find(|path| path.is_file())
+            .ok_or_else(|| YoutubeDLError::MissingOutput { uri: config.uri.clone(), stdout: stdout.to_string() })?;
+        let info = stdout.lines()
+            .filter(|line| line.starts_with('{'))
+            .find_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+            .unwrap_or_default();
+        // what the search result says wins over what the uploader typed in
+        let metadata = config.metadata.clone().unwrap_or_default().or(tagging::metadata_from_info_json(&info));
+        let mut plan = SplitPlan { chapters: if self.split_chapters { chapters::chapters_from_info_json(&info) } else { vec![] } };
+        if self.write_tags {
+            on_progress(DownloadProgress { phase: DownloadPhase::Tagging, ..last.clone() });
+            tagging::tag_or_warn(&path, &metadata);
+        }
+        let ffmpeg = self.ffmpeg().unwrap_or_else(|| PathBuf::from("ffmpeg"));
+        let path = template::place(&path, &config, Some(&metadata))?;
+        // extras are not worth failing a download yt-dlp managed over, nor worth going around the proxy for
+        let client = self.network.client(USER_AGENT)
+            .map_err(|err| log::warn!("Fetching neither segments nor cover art for {path:?}: {err}"))
+            .ok();
+        let apply_cover = |track: &Path| if let Some(client) = &client {
+            cover_art::apply_or_warn(client, track, &metadata, &ffmpeg, &self.cover_art);
+        };
+        // segment databases know videos by their YouTube id
+        let video_id = Self::video_id(&config.uri);
+        let mut skipped = match &client {
+            Some(client) if video_id != config.uri => self.segments.segments_or_warn(client, &video_id),
+            _ => vec![]
+        };
+        if self.segments.action == SegmentAction::Cut && !skipped.is_empty() {
+            on_progress(DownloadProgress { phase: DownloadPhase::PostProcessing, ..last.clone() });
+            match segments::cut(&ffmpeg, &path, &skipped) {
+                Ok(()) => {
+                    plan.chapters = segments::chapters_after_cut(&skipped, &plan.chapters);
+                    skipped.clear();
+                },
+                Err(err) => log::warn!("Marking segments of {path:?} instead: {err}")
+            }
+        }
+        if plan.chapters.is_empty() {
+            if self.replay_gain {
+                loudness::tag_track_or_warn(&ffmpeg, &path);
+            }
+            segments::write_marks_or_warn(&path, &skipped);
+            apply_cover(&path);
+            on_progress(DownloadProgress { phase: DownloadPhase::Finished, ..last });
+            return Ok(Downloaded::file(path));
+        }
+        on_progress(DownloadProgress { phase: DownloadPhase::PostProcessing, ..last.clone() });
+        // the whole upload is still worth having when it cannot be split
+        let files = match chapters::split(&ffmpeg, &path, &plan, &metadata, &config) {
+            Ok(tracks) => {
+                if self.replay_gain {
+                    loudness::tag_album_or_warn(&ffmpeg, &tracks, &tracks);
+                }
+                for (track, chapter) in tracks.iter().zip(&plan.chapters) {
+                    segments::write_marks_or_warn(track, &segments::segments_within(&skipped, chapter.start, chapter.end));
+                    apply_cover(track);
+                }
+                tracks
+            },
+            Err(err) => {
+                log::warn!("Keeping {path:?} whole: {err}");
+                if self.replay_gain {
+                    loudness::tag_track_or_warn(&ffmpeg, &path);
+                }
+                segments::write_marks_or_warn(&path, &skipped);
+                apply_cover(&path);
+                vec![path]
+            }
+        };
+        on_progress(DownloadProgress { phase: DownloadPhase::Finished, ..last });
+        Ok(Downloaded { files, skipped: false })
     }
 }
 
 impl ProvideDownload for YoutubeDL {
-    fn download(&self, config: DownloadConfig) -> Result<(),String> {
-        todo!()
+    /// yt-dlp knows plenty of sites, so any http(s) URL goes, except
+    /// YouTube pages without a video on them. Bare video ids get the
+    /// watch URL suggested.
+    fn check_uri(&self, uri: &str) -> Result<(), FactoryError> {
+        let invalid = |message: &str, did_you_mean: Option<String>| FactoryError::InvalidUri { uri: uri.to_string(), message: message.to_string(), did_you_mean };
+        if uri.len() == 11 && uri.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+            return Err(invalid("expected a URL, not a video id", Some(format!("https://www.youtube.com/watch?v={uri}"))));
+        }
+        check_http_uri(uri)?;
+        let url = reqwest::Url::parse(uri).map_err(|err| invalid(&err.to_string(), None))?;
+        let youtube = matches!(url.host_str().map(|host| host.trim_start_matches("www.")),
+            Some("youtube.com" | "music.youtube.com" | "m.youtube.com" | "youtu.be"));
+        if youtube && Self::video_id(uri) == uri {
+            return Err(invalid("this YouTube page has no video id", None));
+        }
+        Ok(())
+    }
+
+    /// Videos already in [YoutubeDL::archive] are skipped unless
+    /// [DownloadConfig::force] is set.
+    fn download_with_progress(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+        let (video_id, local_path, force) = (Self::video_id(&config.uri), config.local_path.clone(), config.force);
+        self.archive.download_once("youtube_dl", &video_id, &local_path, force, on_progress,
+            |on_progress| self.download_unarchived(config, on_progress))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{io::{Read, Seek, SeekFrom}, sync::Arc};
+
+    use crate::{common::{fake_program::fake_program, metadata::TrackMetadata}, download_provider::{DownloadProviders, format::{AudioCodec, AudioFormat}, stream::DownloadStream}};
+
+    use super::*;
+
+    /// Mimics yt-dlp: writes "<id>.<audio format, m4a by default>" into the
+    /// `-P` directory and prints its path, unless the id asks for a failure.
+    const FAKE_YT_DLP: &str = r#"
+if [ "$1" = "--version" ]; then echo "2022.07.18"; exit 0; fi
+while [ $# -gt 0 ]; do
+    case "$1" in
+        -P) home="$2"; shift ;;
+        --audio-format) ext="$2"; shift ;;
+        --audio-quality) quality="$2"; shift ;;
+        --proxy) network="$network proxy=$2"; shift ;;
+        --limit-rate) network="$network rate=$2"; shift ;;
+        --) uri="$2" ;;
+    esac
+    shift
+done
+case "$uri" in
+    *stream*)
+        part="$home/${uri##*=}.m4a.part"
+        for chunk in ID3 frame1 frame2; do
+            printf %s "$chunk" >> "$part"
+            echo "[cmp-progress] downloading $(wc -c < "$part") NA 4096.0 NA NA $part" >&2
+            sleep 0.2
+        done
+        mv "$part" "$home/${uri##*=}.m4a"
+        echo "$home/${uri##*=}.m4a"
+        exit 0 ;;
+    *private*) echo "WARNING: something odd" >&2; echo "ERROR: [youtube] private: Private video. Sign in if you've been granted access" >&2; exit 1 ;;
+    *badopt*) echo "yt-dlp: error: no such option: --bogus" >&2; exit 2 ;;
+    *silent*) exit 0 ;;
+esac
+echo "[cmp-progress] downloading 0 NA 2048.0 NA NA" >&2
+echo "[cmp-progress] downloading 1024 2048 NA 1024.0 1" >&2
+echo "[cmp-progress] finished 2048 2048 NA NA NA" >&2
+echo "[cmp-postprocess] ExtractAudio started" >&2
+echo "[cmp-postprocess] FFmpegMetadata started" >&2
+file="$home/${uri##*=}.${ext:-m4a}"
+echo "audio $quality" > "$file"
+if [ -n "$network" ]; then echo "$network" > "$file.network"; fi
+echo "$file"
+case "$uri" in *album*) description=', "description": "0:00 Intro\n2:00 Outro"' ;; esac
+printf '%s\n' "{\"title\": \"Fake ${uri##*=}\", \"uploader\": \"Fake Artist - Topic\", \"upload_date\": \"20220718\"$description}"
+"#;
+
+    fn fake_yt_dlp() -> (tempfile::TempDir, YoutubeDL) {
+        let dir = tempfile::tempdir().unwrap();
+        let program = fake_program(dir.path(), "yt-dlp", FAKE_YT_DLP);
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", "echo 'ffmpeg version 5.1'");
+        let archive = DownloadArchive::new(dir.path().join("archive.json"));
+        (dir, YoutubeDL { ffmpeg: Some(ffmpeg), archive, ..YoutubeDL::new(program) })
+    }
+
+    fn config(dir: &tempfile::TempDir, uri: &str) -> DownloadConfig {
+        DownloadConfig::new(uri, dir.path())
+    }
+
+    #[test]
+    fn downloads_and_returns_path() {
+        let (dir, ytdl) = fake_yt_dlp();
+        assert_eq!(Ok(()), ytdl.setup());
+        let provider: DownloadProviders = ytdl.into();
+        let path = dir.path().join("abc.m4a");
+        assert_eq!(Ok(Downloaded::file(path.clone())), provider.download(config(&dir, "https://www.youtube.com/watch?v=abc")));
+        assert_eq!("audio \n", std::fs::read_to_string(path).unwrap());
+    }
+
+    #[test]
+    fn splits_albums_by_description_timestamps() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", r#"for arg in "$@"; do last="$arg"; done; echo "$*" > "$last""#);
+        let ytdl = YoutubeDL { ffmpeg: Some(ffmpeg), split_chapters: true, replay_gain: false, ..ytdl };
+        let tracks = ytdl.download(config(&dir, "https://www.youtube.com/watch?v=album")).unwrap().files;
+        assert_eq!(vec![dir.path().join("01 - Intro.m4a"), dir.path().join("02 - Outro.m4a")], tracks);
+        assert!(tracks.iter().all(|track| track.is_file()));
+        assert!(!dir.path().join("album.m4a").exists());
+
+        // videos without a tracklist stay whole
+        let downloaded = ytdl.download(config(&dir, "https://www.youtube.com/watch?v=abc")).unwrap();
+        assert_eq!(Downloaded::file(dir.path().join("abc.m4a")), downloaded);
+    }
+
+    #[test]
+    fn streams_the_growing_part_file() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let ytdl = YoutubeDL { write_tags: false, replay_gain: false, ..ytdl };
+        let stream = DownloadStream::start(Arc::new(ytdl), config(&dir, "https://www.youtube.com/watch?v=stream"));
+        let mut reader = stream.reader().unwrap();
+        let mut first = [0; 3];
+        reader.read_exact(&mut first).unwrap();
+        assert_eq!(b"ID3", &first);
+        assert_eq!(None, stream.result());
+
+        // yt-dlp only guessed the total, so the end is where the download ends
+        assert_eq!(15, reader.seek(SeekFrom::End(0)).unwrap());
+        assert_eq!(Some(Ok(Downloaded::file(dir.path().join("stream.m4a")))), stream.result());
+        reader.seek(SeekFrom::Start(3)).unwrap();
+        let mut rest = String::new();
+        reader.read_to_string(&mut rest).unwrap();
+        assert_eq!("frame1frame2", rest);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn marks_or_cuts_segments() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let database = dir.path().join("segments.json");
+        std::fs::write(&database, r#"{"abc": [{"category": "intro", "segment": [0.0, 8.5]}]}"#).unwrap();
+        let segments = SegmentOptions { source: segments::SegmentSource::File { path: database }, ..Default::default() };
+        let ytdl = YoutubeDL { segments, replay_gain: false, ..ytdl };
+        let path = dir.path().join("abc.m4a");
+        ytdl.download(config(&dir, "https://www.youtube.com/watch?v=abc")).unwrap();
+        assert_eq!(8.5, segments::read_marks(&path)[0].segment[1]);
+
+        let ffmpeg = fake_program(dir.path(), "ffmpeg", r#"for arg in "$@"; do last="$arg"; done; echo "$*" > "$last""#);
+        let segments = SegmentOptions { action: SegmentAction::Cut, ..ytdl.segments.clone() };
+        let ytdl = YoutubeDL { ffmpeg: Some(ffmpeg), segments, ..ytdl };
+        ytdl.download(DownloadConfig { force: true, ..config(&dir, "https://www.youtube.com/watch?v=abc") }).unwrap();
+        assert!(std::fs::read_to_string(&path).unwrap().contains("between(t,0.000,8.500)"));
+        assert!(segments::read_marks(&path).is_empty());
+    }
+
+    #[test]
+    fn passes_proxy_and_rate_limit() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let network = NetworkOptions { proxy: Some("socks5://proxy.office:1080".to_string()), rate_limit: Some(250_000), global_rate_limit: None };
+        let downloaded = YoutubeDL { network, replay_gain: false, ..ytdl }.download(config(&dir, "https://www.youtube.com/watch?v=abc")).unwrap();
+        let network = std::fs::read_to_string(dir.path().join("abc.m4a.network")).unwrap();
+        assert_eq!(" proxy=socks5://proxy.office:1080 rate=250000", network.trim_end());
+        assert_eq!(Downloaded::file(dir.path().join("abc.m4a")), downloaded);
+    }
+
+    #[test]
+    fn names_files_from_info_json() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let config = DownloadConfig {
+            filename_template: Some("{artist}/{title} ({year}).{ext}".to_string()),
+            metadata: Some(TrackMetadata { title: Some("Searched Title".to_string()), ..Default::default() }),
+            ..config(&dir, "https://www.youtube.com/watch?v=abc")
+        };
+        let downloaded = ytdl.download(config).unwrap();
+        assert_eq!(Downloaded::file(dir.path().join("Fake Artist/Searched Title (2022).m4a")), downloaded);
+    }
+
+    #[test]
+    fn skips_archived_videos() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let downloaded = ytdl.download(config(&dir, "https://www.youtube.com/watch?v=abc")).unwrap();
+        let path = downloaded.path().unwrap();
+        std::fs::write(path, "kept").unwrap();
+        // same video, different URL
+        let skipped = Downloaded { skipped: true, ..downloaded.clone() };
+        assert_eq!(Ok(skipped.clone()), ytdl.download(config(&dir, "https://youtu.be/abc")));
+        assert_eq!(Ok(skipped), ytdl.download(config(&dir, "https://music.youtube.com/watch?v=abc")));
+        assert_eq!("kept", std::fs::read_to_string(path).unwrap());
+
+        let forced = DownloadConfig { force: true, ..config(&dir, "https://music.youtube.com/watch?v=abc") };
+        ytdl.download(forced).unwrap();
+        assert_eq!("audio \n", std::fs::read_to_string(path).unwrap());
+        assert_eq!(1, ytdl.archive.entries().unwrap().len());
+    }
+
+    #[test]
+    fn converts_to_requested_format() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let format = AudioFormat { bitrate_kbps: Some(160), ..AudioFormat::new(AudioCodec::Opus) };
+        let path = dir.path().join("abc.opus");
+        let downloaded = ytdl.download(DownloadConfig { format, ..config(&dir, "https://www.youtube.com/watch?v=abc") }).unwrap();
+        assert_eq!(Downloaded::file(path.clone()), downloaded);
+        assert_eq!("audio 160K\n", std::fs::read_to_string(path).unwrap());
+    }
+
+    #[test]
+    fn reports_progress() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let mut reports = vec![];
+        ytdl.download_with_progress(config(&dir, "https://www.youtube.com/watch?v=abc"), &mut |p| reports.push(p)).unwrap();
+        let phases = reports.iter().map(|p| p.phase).collect::<Vec<_>>();
+        use DownloadPhase::*;
+        assert_eq!(vec![Fetching, Fetching, Fetching, PostProcessing, Tagging, Tagging, Finished], phases);
+        assert_eq!(Some(2048), reports[0].total_bytes);
+        assert_eq!(Some(Duration::from_secs(1)), reports[1].eta);
+        assert_eq!(Some(1024.0), reports[1].speed);
+        let last = reports.last().unwrap();
+        assert_eq!((2048, Some(2048)), (last.downloaded_bytes, last.total_bytes));
+    }
+
+    #[test]
+    fn maps_exit_codes() {
+        let (dir, ytdl) = fake_yt_dlp();
+        let err = ytdl.download(config(&dir, "https://www.youtube.com/watch?v=private")).unwrap_err();
+        assert_eq!(YoutubeDLError::DownloadFailed {
+            uri: "https://www.youtube.com/watch?v=private".to_string(),
+            message: "[youtube] private: Private video. Sign in if you've been granted access".to_string()
+        }.to_string(), err);
+
+        let err = ytdl.download(config(&dir, "https://www.youtube.com/watch?v=badopt")).unwrap_err();
+        assert!(err.starts_with("yt-dlp rejected its options: yt-dlp: error: no such option"), "{err}");
+
+        let err = ytdl.download(config(&dir, "https://www.youtube.com/watch?v=silent")).unwrap_err();
+        assert!(err.contains("reported no existing file"), "{err}");
+    }
+
+    #[test]
+    fn missing_executable() {
+        let dir = tempfile::tempdir().unwrap();
+        let ytdl = YoutubeDL::new(dir.path().join("no-such-yt-dlp"));
+        assert!(matches!(ytdl.version(), Err(YoutubeDLError::NotInstalled { .. })));
+        assert!(ytdl.download(config(&dir, "https://www.youtube.com/watch?v=abc")).unwrap_err().contains("Try running setup"));
+    }
+
+    #[test]
+    fn setup_installs_into_exec_path() {
+        let (dir, _) = fake_yt_dlp();
+        let setup = SetupConfig {
+            include_path: dir.path().join("root/include"),
+            lib_path: dir.path().join("root/lib"),
+            exec_path: dir.path().join("root/bin"),
+            data_path: dir.path().join("root/data")
+        };
+        let release = |name: &str, version: &str| {
+            let artifact = dir.path().join(name);
+            PinnedRelease {
+                name: format!("cmp-test-{name}"),
+                version: version.to_string(),
+                url: format!("https://example.invalid/{name}"),
+                sha256: Some(install::sha256_hex(&std::fs::read(&artifact).unwrap())),
+                checksums_url: None,
+                archive_member: None,
+                local_archive: Some(artifact),
+                ..PinnedRelease::yt_dlp()
+            }
+        };
+        let ytdl = YoutubeDL {
+            setup: setup.clone(),
+            yt_dlp_release: release("yt-dlp", "2022.07.18"),
+            ffmpeg_release: release("ffmpeg", "ffmpeg version"),
+            ..Default::default()
+        };
+        assert_eq!(ytdl.setup(), Ok(()));
+        assert_eq!(setup.exec_path.join("cmp-test-yt-dlp"), ytdl.executable());
+        assert_eq!(Some(setup.exec_path.join("cmp-test-ffmpeg")), ytdl.ffmpeg());
+        assert_eq!(Ok("2022.07.18".to_string()), ytdl.version());
+        // a second setup finds the installed programs
+        assert_eq!(ytdl.setup(), Ok(()));
+    }
+}
@@ -1,5 +1,7 @@
 #[macro_use]
 extern crate simple_error;
+// lets macros of cli-music-player-macros name our items from inside, too
+extern crate self as cli_music_player;
 pub mod common;
 pub mod download_provider;
 pub mod search_provider;
\ No newline at end of file
@@ -1,9 +1,11 @@
+use std::{path::Path, time::Duration};
+
 use enum_dispatch::enum_dispatch;
 use serde::{Serialize, Deserialize};
 
-use crate::common::self_setup::SelfSetup;
+use crate::{common::{self_setup::SelfSetup, metadata::TrackMetadata}, download_provider::DownloadConfig};
 
-use super::youtube_scraper::YoutubeScraper;
+use super::{youtube_scraper::YoutubeScraper, youtube_music::YoutubeMusic, podcast_rss::PodcastRss, youtube_feed::YoutubeFeed, radio_browser::RadioBrowser, internet_archive::InternetArchive};
 
 #[derive(Serialize, Deserialize)]
 pub struct SearchQuery {
@@ -21,7 +23,100 @@ pub trait ProvideSearch: SelfSetup {
 
 #[enum_dispatch(SelfSetup, ProvideSearch)]
 pub enum SearchProviders {
-    YoutubeScraper
+    YoutubeScraper,
+    YoutubeMusic,
+    PodcastRss,
+    YoutubeFeed,
+    RadioBrowser,
+    InternetArchive
+}
+
+/// A single entry of an [Album], in album order
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AlbumTrack {
+    /// 1-based position in the album
+    pub track_number: u32,
+    pub title: String,
+    /// Track-level artists; empty means "same as the album artist"
+    pub artists: Vec<String>,
+    pub duration: Option<Duration>,
+    /// None if the track is unavailable (region-locked, removed, ...)
+    pub url: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Album {
+    pub id: String,
+    pub title: String,
+    pub artist: Option<String>,
+    pub year: Option<i32>,
+    pub thumbnail_url: Option<String>,
+    /// Sorted by [AlbumTrack::track_number]
+    pub tracks: Vec<AlbumTrack>
+}
+
+impl Album {
+    /// Tags for one of this album's tracks
+    pub fn metadata(&self, track: &AlbumTrack) -> TrackMetadata {
+        TrackMetadata {
+            title: Some(track.title.clone()),
+            artist: (!track.artists.is_empty()).then(|| track.artists.join(", "))
+                .or_else(|| self.artist.clone()),
+            album: Some(self.title.clone()),
+            album_artist: self.artist.clone(),
+            track_number: Some(track.track_number),
+            track_total: Some(self.tracks.len() as u32),
+            year: self.year,
+            duration: track.duration,
+            source_url: track.url.clone(),
+            thumbnail_url: self.thumbnail_url.clone(),
+        }
+    }
+    /// One [DownloadConfig] per available track, in album order
+    pub fn download_configs<P: AsRef<Path>>(&self, local_path: P) -> Vec<DownloadConfig> {
+        self.tracks.iter()
+            .filter_map(|track| track.url.as_ref().map(|url| DownloadConfig {
+                metadata: Some(self.metadata(track)),
+                ..DownloadConfig::new(url, &local_path)
+            }))
+            .collect()
+    }
+}
+
+/// An album as listed on an artist's page, without its tracks
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct AlbumSummary {
+    /// Id accepted by [ProvideAlbum::album]
+    pub id: String,
+    pub title: String,
+    pub year: Option<i32>,
+    pub thumbnail_url: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Discography {
+    pub artist_id: String,
+    pub name: String,
+    pub albums: Vec<AlbumSummary>,
+    pub singles: Vec<AlbumSummary>
+}
+
+/// Providers that know about albums and artists, not just loose tracks.
+///
+/// Not every [SearchProviders] variant can do this, so this trait is
+/// implemented on the concrete providers only.
+pub trait ProvideAlbum: ProvideSearch {
+    /// Expands an album into its ordered track list
+    fn album(&self, album_id: &str) -> Result<Album, String>;
+    /// Lists the albums and singles released by an artist
+    fn discography(&self, artist_id: &str) -> Result<Discography, String>;
+    /// Expands every album and single of an artist
+    fn discography_albums(&self, artist_id: &str) -> Result<Vec<Album>, String> {
+        let discography = self.discography(artist_id)?;
+        discography.albums.iter().chain(discography.singles.iter())
+            .map(|summary| self.album(&summary.id))
+            .collect()
+    }
 }
 
 
@@ -0,0 +1,462 @@
+//! Implementation of a search and download provider over the Internet
+//! Archive's advanced search and metadata APIs. An archive "item" is a
+//! whole concert or album; what we hand out are its individual audio files.
+
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use percent_encoding::percent_decode_str;
+use reqwest::Url;
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::{
+    common::{self_setup::SelfSetup, factory::FactoryError, metadata::{TrackMetadata, parse_clock_duration}},
+    download_provider::{DownloadConfig, Downloaded, ProvideDownload, direct::HttpDownload, loudness, template, progress::{ProgressCallback, DownloadProgress, DownloadPhase}}
+};
+use super::interface::{ProvideSearch, SearchQuery};
+
+/// An archive item as returned by the advanced search
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveItem {
+    pub identifier: String,
+    pub title: String,
+    pub creator: Option<String>,
+    pub date: Option<String>
+}
+
+/// One audio file of an [ArchiveItem]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ArchiveFile {
+    pub identifier: String,
+    /// Path of the file inside the item
+    pub name: String,
+    /// The archive's format name, e.g. "VBR MP3" or "Flac"
+    pub format: String,
+    pub size: Option<u64>,
+    pub duration: Option<Duration>,
+    pub md5: Option<String>,
+    pub url: String,
+    pub metadata: TrackMetadata
+}
+
+impl ArchiveFile {
+    pub fn download_config<P: AsRef<Path>>(&self, local_path: P) -> DownloadConfig {
+        DownloadConfig { metadata: Some(self.metadata.clone()), ..DownloadConfig::new(&self.url, local_path) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct InternetArchive {
+    /// Root of archive.org
+    ///
+    /// Default: "https://archive.org"
+    pub api_url: String,
+    /// Maximum number of items per search
+    ///
+    /// Default: 20
+    pub rows: u32,
+    /// Audio formats we know, most preferred first. Files in other formats
+    /// (images, torrents, spectrograms, ...) are ignored.
+    ///
+    /// Default: ["Flac", "VBR MP3", "Ogg Vorbis", "128Kbps MP3", "64Kbps MP3", "MP3", "WAVE"]
+    pub formats: Vec<String>,
+    /// How files are fetched
+    pub http: HttpDownload
+}
+
+impl Default for InternetArchive {
+    fn default() -> Self {
+        Self {
+            api_url: "https://archive.org".to_string(),
+            rows: 20,
+            formats: ["Flac", "VBR MP3", "Ogg Vorbis", "128Kbps MP3", "64Kbps MP3", "MP3", "WAVE"]
+                .into_iter().map(str::to_string).collect(),
+            http: HttpDownload::default()
+        }
+    }
+}
+
+impl SelfSetup for InternetArchive {
+    fn setup(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Archive metadata fields are either a string or a list of strings
+fn string_of(value: Option<&Value>) -> Option<String> {
+    match value? {
+        Value::String(s) => Some(s.clone()),
+        Value::Array(values) => values.first().and_then(Value::as_str).map(str::to_string),
+        Value::Number(n) => Some(n.to_string()),
+        _ => None
+    }.filter(|s| !s.trim().is_empty())
+}
+
+fn year_of(date: &str) -> Option<i32> {
+    date.get(..4).and_then(|year| year.parse().ok())
+}
+
+impl InternetArchive {
+    pub fn new<AnyStr: AsRef<str>>(api_url: AnyStr) -> Self {
+        Self { api_url: api_url.as_ref().to_string(), ..Default::default() }
+    }
+
+    fn get_json(&self, url: Url) -> Result<Value, String> {
+        log::info!("GET {url}");
+        self.http.network.client(&self.http.user_agent)?
+            .get(url.clone()).send()
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| format!("Request to {url} failed: {err}"))
+            .and_then(|resp| serde_json::from_reader(resp).map_err(|err| format!("Bad JSON from {url}: {err}")))
+    }
+
+    fn url<AnyStr: AsRef<str>>(&self, path: AnyStr) -> Result<Url, String> {
+        Url::parse(&format!("{}{}", self.api_url, path.as_ref())).map_err(|err| format!("Bad archive URL: {err}"))
+    }
+
+    /// The download URL of a file inside an item
+    pub fn file_url(&self, identifier: &str, name: &str) -> Result<Url, String> {
+        let mut url = self.url("/download")?;
+        url.path_segments_mut()
+            .map_err(|_| format!("{} cannot be a base URL", self.api_url))?
+            .push(identifier)
+            .extend(name.split('/'));
+        Ok(url)
+    }
+
+    /// Finds audio items matching all keywords
+    pub fn search_items(&self, keywords: &[String]) -> Result<Vec<ArchiveItem>, String> {
+        let mut url = self.url("/advancedsearch.php")?;
+        url.query_pairs_mut()
+            .append_pair("q", &format!("({}) AND mediatype:(audio)", keywords.join(" AND ")))
+            .append_pair("fl[]", "identifier")
+            .append_pair("fl[]", "title")
+            .append_pair("fl[]", "creator")
+            .append_pair("fl[]", "date")
+            .append_pair("rows", &self.rows.to_string())
+            .append_pair("output", "json");
+        let response = self.get_json(url)?;
+        Ok(response.pointer("/response/docs").and_then(Value::as_array).into_iter().flatten()
+            .filter_map(|doc| Some(ArchiveItem {
+                identifier: string_of(doc.get("identifier"))?,
+                title: string_of(doc.get("title")).unwrap_or_default(),
+                creator: string_of(doc.get("creator")),
+                date: string_of(doc.get("date"))
+            }))
+            .collect())
+    }
+
+    /// Parses the metadata API response of an item into its audio files,
+    /// in the order the archive lists them.
+    pub fn parse_files(&self, identifier: &str, response: &Value) -> Result<Vec<ArchiveFile>, String> {
+        let meta = response.get("metadata")
+            .ok_or_else(|| format!("Archive item {identifier} does not exist"))?;
+        let album = string_of(meta.get("title"));
+        let creator = string_of(meta.get("creator"));
+        let year = string_of(meta.get("date")).as_deref().and_then(year_of);
+        let files = response.get("files").and_then(Value::as_array).cloned().unwrap_or_default();
+        files.iter()
+            .filter_map(|file| {
+                let format = string_of(file.get("format"))?;
+                self.formats.contains(&format).then_some((file, format))
+            })
+            .map(|(file, format)| {
+                let name = string_of(file.get("name")).unwrap_or_default();
+                let url = self.file_url(identifier, &name)?;
+                // lengths come as seconds ("245.32") or as a clock ("04:05")
+                let duration = string_of(file.get("length")).and_then(|length| length.parse::<f64>().ok()
+                    .map(Duration::from_secs_f64)
+                    .or_else(|| parse_clock_duration(&length)));
+                Ok(ArchiveFile {
+                    identifier: identifier.to_string(),
+                    format,
+                    size: string_of(file.get("size")).and_then(|size| size.parse().ok()),
+                    md5: string_of(file.get("md5")),
+                    metadata: TrackMetadata {
+                        title: string_of(file.get("title")).or_else(|| Some(name.clone())),
+                        artist: string_of(file.get("creator")).or_else(|| creator.clone()),
+                        album: string_of(file.get("album")).or_else(|| album.clone()),
+                        album_artist: creator.clone(),
+                        // "3" or "3/12"
+                        track_number: string_of(file.get("track"))
+                            .and_then(|track| track.split('/').next().and_then(|n| n.trim().parse().ok())),
+                        year,
+                        duration,
+                        source_url: Some(url.to_string()),
+                        ..Default::default()
+                    },
+                    duration,
+                    url: url.to_string(),
+                    name
+                })
+            })
+            .collect()
+    }
+
+    /// Lists every audio file of an item, in every format available
+    pub fn files(&self, identifier: &str) -> Result<Vec<ArchiveFile>, String> {
+        let url = self.url("/metadata")?;
+        let mut url = url;
+        url.path_segments_mut().map_err(|_| format!("{} cannot be a base URL", self.api_url))?.push(identifier);
+        self.get_json(url).and_then(|response| self.parse_files(identifier, &response))
+    }
+
+    /// Keeps only the files in the most preferred format the item has,
+    /// so each track appears once.
+    pub fn preferred_files(&self, files: Vec<ArchiveFile>) -> Vec<ArchiveFile> {
+        let best = self.formats.iter().find(|format| files.iter().any(|file| &file.format == *format)).cloned();
+        files.into_iter().filter(|file| Some(&file.format) == best.as_ref()).collect()
+    }
+
+    /// Splits an archive URI into (identifier, file name). Accepts
+    /// `<api_url>/download/<id>[/<file>]`, `<api_url>/details/<id>[/<file>]` and
+    /// `ia:<id>[/<file>]`.
+    pub fn parse_uri<'a>(&self, uri: &'a str) -> Option<(&'a str, Option<&'a str>)> {
+        let rest = uri.strip_prefix("ia:")
+            .or_else(|| uri.strip_prefix(&format!("{}/download/", self.api_url)))
+            .or_else(|| uri.strip_prefix(&format!("{}/details/", self.api_url)))?;
+        let rest = rest.trim_matches('/');
+        match rest.split_once('/') {
+            Some((identifier, file)) if !file.is_empty() => Some((identifier, Some(file))),
+            _ => Some((rest, None)).filter(|(identifier, _)| !identifier.is_empty())
+        }
+    }
+}
+
+impl ProvideSearch for InternetArchive {
+    /// Searches audio items and returns the URLs of their files, one
+    /// format per item
+    fn search(&self, query: SearchQuery) -> Result<Vec<String>, String> {
+        let mut urls = vec![];
+        for item in self.search_items(&query.keywords)? {
+            // one item with broken metadata should not hide the others
+            let files = match self.files(&item.identifier) {
+                Ok(files) => files,
+                Err(err) => {
+                    log::warn!("Cannot list the files of {}: {err}", item.identifier);
+                    continue;
+                }
+            };
+            urls.extend(self.preferred_files(files).into_iter().map(|file| file.url));
+        }
+        Ok(urls)
+    }
+}
+
+impl ProvideDownload for InternetArchive {
+    fn check_uri(&self, uri: &str) -> Result<(), FactoryError> {
+        if self.parse_uri(uri).is_some() {
+            return Ok(());
+        }
+        // most likely a bare identifier
+        let did_you_mean = (!uri.contains([':', '/'])).then(|| format!("ia:{uri}"));
+        Err(FactoryError::InvalidUri {
+            uri: uri.to_string(),
+            message: format!("expected ia:<identifier>[/<file>] or a URL under {}", self.api_url),
+            did_you_mean
+        })
+    }
+
+    /// Downloads a single file, or every preferred audio file when the URI
+    /// names a whole item. Files in folders of the item land in the same
+    /// folders under [DownloadConfig::local_path].
+    fn download_with_progress(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+        // (url, what we know about that file, if more than the config does, folder it goes into)
+        let files = match self.parse_uri(&config.uri) {
+            Some((identifier, None)) => self.preferred_files(self.files(identifier)?).into_iter()
+                .map(|file| (file.url, Some(file.metadata), folder_of(&file.name)))
+                .collect(),
+            Some((_, Some(_))) if config.uri.starts_with(&format!("{}/download/", self.api_url)) => vec![(config.uri.clone(), None, PathBuf::new())],
+            Some((identifier, Some(file))) => {
+                // /details/ URLs come percent-encoded, ia: URIs as typed
+                let file = match config.uri.starts_with("ia:") {
+                    true => file.to_string(),
+                    false => percent_decode_str(file).decode_utf8_lossy().to_string()
+                };
+                vec![(self.file_url(identifier, &file)?.to_string(), None, PathBuf::new())]
+            },
+            None => return Err(format!("{:?} is not an Internet Archive URI", config.uri))
+        };
+        if files.is_empty() {
+            return Err(format!("Archive item {:?} has no audio files", config.uri));
+        }
+        let fetch = |url: &str, metadata: Option<&TrackMetadata>, folder: &Path, on_progress: ProgressCallback| {
+            let config = DownloadConfig { local_path: config.local_path.join(folder), ..config.clone() };
+            std::fs::create_dir_all(&config.local_path)
+                .map_err(|err| format!("Cannot std::fs::create_dir_all({:?}): {err:?}", config.local_path))?;
+            self.http.fetch_into(url, &config, metadata, on_progress)
+        };
+        match files.as_slice() {
+            [(url, metadata, folder)] => fetch(url, metadata.as_ref(), folder, on_progress),
+            files => {
+                let (mut paths, mut fetched) = (vec![], vec![]);
+                for (url, metadata, folder) in files {
+                    let downloaded = fetch(url, metadata.as_ref(), folder, &mut |progress| if progress.phase != DownloadPhase::Finished {
+                        on_progress(progress)
+                    })?;
+                    if !downloaded.skipped {
+                        fetched.extend(downloaded.files.iter().cloned());
+                    }
+                    paths.extend(downloaded.files);
+                }
+                // the files of an item are one album; those we had already got their album gain back then
+                if self.http.replay_gain && !fetched.is_empty() {
+                    loudness::tag_album_or_warn(&self.http.ffmpeg(), &paths, &fetched);
+                    if let Err(err) = self.http.archive.refresh(&fetched) {
+                        log::warn!("{err}");
+                    }
+                }
+                on_progress(DownloadProgress::phase(DownloadPhase::Finished));
+                Ok(Downloaded { skipped: fetched.is_empty(), files: paths })
+            }
+        }
+    }
+}
+
+/// The folders a file sits in inside its item, made safe to create
+/// locally. Items may hold equally named files on different discs.
+/// ```
+/// use std::path::PathBuf;
+/// use cli_music_player::search_provider::internet_archive::folder_of;
+///
+/// assert_eq!(PathBuf::from("Disc 2"), folder_of("Disc 2/01 Intro.flac"));
+/// assert_eq!(PathBuf::from("_/etc"), folder_of("../etc/passwd"));
+/// assert_eq!(PathBuf::new(), folder_of("01 Intro.flac"));
+/// ```
+pub fn folder_of(name: &str) -> PathBuf {
+    let mut parts = name.split('/').filter(|part| !part.is_empty()).collect::<Vec<_>>();
+    parts.pop();
+    parts.into_iter().map(template::sanitize).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use lofty::ItemKey;
+
+    use crate::{common::{fake_program::fake_program, test_server::{TestServer, TestResponse}}, download_provider::archive::DownloadArchive};
+
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/tests/fixtures/internet_archive/{name}", env!("CARGO_MANIFEST_DIR"))).unwrap()
+    }
+
+    fn mock_archive() -> (TestServer, InternetArchive) {
+        let server = TestServer::serve(|req| match req.path.split('?').next().unwrap_or_default() {
+            "/advancedsearch.php" => TestResponse::ok(fixture("search.json")),
+            "/metadata/gd1977-05-08" => TestResponse::ok(fixture("metadata.json")),
+            "/download/gd1977-05-08/gd77-05-08d1t01.flac" => TestResponse::ok("flac one"),
+            "/download/gd1977-05-08/gd77-05-08d1t02.flac" => TestResponse::ok("flac two"),
+            "/download/gd1977-05-08/gd77-05-08d1t01.mp3" => TestResponse::ok("mp3 one"),
+            "/download/gd1977-05-08/gd77-05-08d1t02.mp3" => TestResponse::ok("mp3 two"),
+            "/download/gd1977-05-08/Disc%202/encore%20track.mp3" => TestResponse::ok("encore"),
+            _ => TestResponse::not_found()
+        });
+        let archive = InternetArchive::new(server.url(""));
+        (server, archive)
+    }
+
+    #[test]
+    fn files_with_formats() {
+        let (_server, archive) = mock_archive();
+        let files = archive.files("gd1977-05-08").unwrap();
+        // the jpg and the torrent are not audio
+        assert_eq!(5, files.len());
+        assert_eq!(vec!["Flac", "VBR MP3", "Flac", "VBR MP3", "VBR MP3"],
+            files.iter().map(|file| file.format.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some(Duration::from_secs_f64(245.5)), files[0].duration);
+        assert_eq!(Some(Duration::from_secs(392)), files[2].duration);
+        assert_eq!(Some(2), files[2].metadata.track_number);
+        assert_eq!(Some(1977), files[0].metadata.year);
+        assert_eq!(Some("Grateful Dead".to_string()), files[0].metadata.artist);
+        assert!(files[4].url.ends_with("/download/gd1977-05-08/Disc%202/encore%20track.mp3"), "{}", files[4].url);
+
+        let preferred = archive.preferred_files(files);
+        assert_eq!(2, preferred.len());
+        assert!(preferred.iter().all(|file| file.format == "Flac"));
+    }
+
+    #[test]
+    fn search_skips_items_without_metadata() {
+        let server = TestServer::serve(|req| match req.path.split('?').next().unwrap_or_default() {
+            "/advancedsearch.php" => TestResponse::ok(serde_json::json!({ "response": { "docs": [
+                { "identifier": "taken-down" },
+                { "identifier": "gd1977-05-08" }
+            ] } }).to_string()),
+            "/metadata/gd1977-05-08" => TestResponse::ok(fixture("metadata.json")),
+            _ => TestResponse::not_found()
+        });
+        let urls = InternetArchive::new(server.url("")).search(SearchQuery { keywords: vec!["dead".to_string()] }).unwrap();
+        assert_eq!(2, urls.len());
+    }
+
+    #[test]
+    fn search_then_download() {
+        let (_server, mut archive) = mock_archive();
+        let urls = archive.search(SearchQuery { keywords: vec!["grateful".to_string(), "dead".to_string()] }).unwrap();
+        assert_eq!(2, urls.len());
+
+        let dir = tempfile::tempdir().unwrap();
+        archive.http.archive = DownloadArchive::new(dir.path().join("archive.json"));
+        let config = |uri: String| DownloadConfig::new(uri, dir.path());
+        let downloaded = archive.download(config("ia:gd1977-05-08".to_string())).unwrap();
+        assert_eq!(vec![dir.path().join("gd77-05-08d1t01.flac"), dir.path().join("gd77-05-08d1t02.flac")], downloaded.files);
+        assert_eq!("flac one", std::fs::read_to_string(dir.path().join("gd77-05-08d1t01.flac")).unwrap());
+        assert_eq!("flac two", std::fs::read_to_string(dir.path().join("gd77-05-08d1t02.flac")).unwrap());
+        // downloading the item again skips the files we have
+        std::fs::write(dir.path().join("gd77-05-08d1t01.flac"), "kept").unwrap();
+        archive.download(config("ia:gd1977-05-08".to_string())).unwrap();
+        assert_eq!("kept", std::fs::read_to_string(dir.path().join("gd77-05-08d1t01.flac")).unwrap());
+
+        archive.download(config(archive.file_url("gd1977-05-08", "Disc 2/encore track.mp3").unwrap().to_string())).unwrap();
+        assert_eq!("encore", std::fs::read_to_string(dir.path().join("encore track.mp3")).unwrap());
+        // a details page stands for the file, not its HTML
+        let details = format!("{}/details/gd1977-05-08/Disc%202/encore%20track.mp3", archive.api_url);
+        let downloaded = archive.download(DownloadConfig { force: true, ..config(details) }).unwrap();
+        assert_eq!("encore", std::fs::read_to_string(downloaded.path().unwrap()).unwrap());
+
+        // files in folders of the item keep them
+        let mp3s = InternetArchive { formats: vec!["VBR MP3".to_string()], ..archive.clone() };
+        let downloaded = mp3s.download(DownloadConfig { force: true, ..config("ia:gd1977-05-08".to_string()) }).unwrap();
+        assert_eq!(3, downloaded.files.len());
+        assert_eq!(dir.path().join("Disc 2/encore track.mp3"), downloaded.files[2]);
+
+        assert!(archive.download(config("https://example.com/not-archive".to_string())).is_err());
+    }
+
+    #[test]
+    fn album_gain_keeps_the_archive_in_sync() {
+        let mut frame = vec![0xFF, 0xFB, 0x90, 0x64];
+        frame.resize(417, 0);
+        let mp3 = frame.repeat(4);
+        let server = TestServer::serve(move |req| match req.path.as_str() {
+            "/metadata/gd1977-05-08" => TestResponse::ok(fixture("metadata.json")),
+            path if path.ends_with(".mp3") => TestResponse::ok(mp3.clone()),
+            _ => TestResponse::not_found()
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let ffmpeg = fake_program(dir.path(), "ffmpeg",
+            r"printf '[Parsed_ebur128_0 @ 0x1] Summary:\n\n  Integrated loudness:\n    I:  -14.0 LUFS\n\n  True peak:\n    Peak:  -1.0 dBFS\n' >&2");
+        let http = HttpDownload { ffmpeg: Some(ffmpeg), archive: DownloadArchive::new(dir.path().join("archive.json")), ..Default::default() };
+        let archive = InternetArchive { formats: vec!["VBR MP3".to_string()], http, ..InternetArchive::new(server.url("")) };
+        let config = DownloadConfig::new("ia:gd1977-05-08", dir.path().join("music"));
+        let album_gain = |path: &Path| lofty::read_from_path(path, false).ok()
+            .and_then(|tagged| tagged.primary_tag()?.get_string(&ItemKey::ReplayGainAlbumGain).map(str::to_string));
+
+        let files = archive.download(config.clone()).unwrap().files;
+        assert_eq!(3, files.len());
+        assert_eq!(Some("-4.00 dB".to_string()), album_gain(&files[1]));
+        assert!(archive.http.archive.verify().unwrap().is_empty());
+
+        // a second run only tags what it fetched
+        std::fs::write(&files[0], "edited").unwrap();
+        std::fs::remove_file(&files[1]).unwrap();
+        let again = archive.download(config).unwrap();
+        assert_eq!((files.clone(), false), (again.files, again.skipped));
+        assert_eq!("edited", std::fs::read_to_string(&files[0]).unwrap());
+        assert_eq!(Some("-4.00 dB".to_string()), album_gain(&files[1]));
+        let modified = archive.http.archive.verify().unwrap();
+        assert_eq!(vec![files[0].clone()], modified.iter().flat_map(|entry| entry.downloaded().files).collect::<Vec<_>>());
+    }
+}
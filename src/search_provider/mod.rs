@@ -1,2 +1,7 @@
 pub mod interface;
 pub mod youtube_scraper;
+pub mod youtube_music;
+pub mod podcast_rss;
+pub mod youtube_feed;
+pub mod radio_browser;
+pub mod internet_archive;
@@ -0,0 +1,235 @@
+//! Implementation of a search provider over subscribed podcast feeds.
+//! Searching only looks at episodes of feeds we are subscribed to; there is
+//! no global podcast directory involved.
+
+use std::{path::{Path, PathBuf}, time::Duration};
+
+use chrono::{DateTime, Utc, Datelike};
+use serde::{Serialize, Deserialize};
+
+use crate::{
+    common::{self_setup::SelfSetup, config::{load_json, project_dirs, save_json}, feed::Feed, metadata::TrackMetadata},
+    download_provider::{DownloadConfig, Downloaded, ProvideDownload, direct::HttpDownload, progress::ProgressCallback}
+};
+use super::interface::{ProvideSearch, SearchQuery};
+
+/// A feed we follow
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Subscription {
+    /// Where the RSS/Atom document lives
+    pub url: String,
+    pub title: String
+}
+
+/// An entry of a podcast feed that carries a media file
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Episode {
+    pub id: String,
+    pub podcast: String,
+    pub title: String,
+    pub enclosure_url: String,
+    pub mime_type: Option<String>,
+    pub length: Option<u64>,
+    pub duration: Option<Duration>,
+    pub published: Option<DateTime<Utc>>,
+    pub author: Option<String>,
+    pub thumbnail_url: Option<String>
+}
+
+impl Episode {
+    pub fn metadata(&self) -> TrackMetadata {
+        TrackMetadata {
+            title: Some(self.title.clone()),
+            artist: self.author.clone().or_else(|| Some(self.podcast.clone())),
+            album: Some(self.podcast.clone()),
+            year: self.published.map(|date| date.year()),
+            duration: self.duration,
+            source_url: Some(self.enclosure_url.clone()),
+            thumbnail_url: self.thumbnail_url.clone(),
+            ..Default::default()
+        }
+    }
+    pub fn download_config<P: AsRef<Path>>(&self, local_path: P) -> DownloadConfig {
+        DownloadConfig { metadata: Some(self.metadata()), ..DownloadConfig::new(&self.enclosure_url, local_path) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct PodcastRss {
+    /// JSON file holding our [Subscription]s
+    ///
+    /// Default: `<config dir>/podcasts.json`
+    pub subscriptions_path: PathBuf,
+    /// How episodes are fetched
+    pub http: HttpDownload
+}
+
+impl Default for PodcastRss {
+    fn default() -> Self {
+        Self { subscriptions_path: project_dirs().config_dir().join("podcasts.json"), http: HttpDownload::default() }
+    }
+}
+
+impl SelfSetup for PodcastRss {
+    fn setup(&self) -> Result<(), String> {
+        match self.subscriptions_path.parent() {
+            Some(dir) if !dir.exists() => std::fs::create_dir_all(dir)
+                .map_err(|err| format!("Cannot std::fs::create_dir_all({dir:?}): {err:?}")),
+            _ => Ok(())
+        }
+    }
+}
+
+impl PodcastRss {
+    pub fn new<P: AsRef<Path>>(subscriptions_path: P) -> Self {
+        Self { subscriptions_path: subscriptions_path.as_ref().to_path_buf(), ..Default::default() }
+    }
+
+    pub fn subscriptions(&self) -> Result<Vec<Subscription>, String> {
+        load_json(&self.subscriptions_path, "subscriptions")
+    }
+
+    fn save(&self, subscriptions: &[Subscription]) -> Result<(), String> {
+        save_json(&self.subscriptions_path, "subscriptions", subscriptions)
+    }
+
+    /// Follows a feed. The feed is fetched once to make sure it parses and
+    /// to learn its title. Subscribing twice is a no-op.
+    pub fn subscribe<AnyStr: AsRef<str>>(&self, feed_url: AnyStr) -> Result<Subscription, String> {
+        let feed_url = feed_url.as_ref();
+        let mut subscriptions = self.subscriptions()?;
+        if let Some(existing) = subscriptions.iter().find(|sub| sub.url == feed_url) {
+            return Ok(existing.clone());
+        }
+        let feed = Feed::fetch(&self.http.network.client(&self.http.user_agent)?, feed_url)?;
+        let subscription = Subscription { url: feed_url.to_string(), title: feed.title };
+        subscriptions.push(subscription.clone());
+        self.save(&subscriptions)?;
+        Ok(subscription)
+    }
+
+    /// Returns whether we were subscribed to `feed_url`
+    pub fn unsubscribe<AnyStr: AsRef<str>>(&self, feed_url: AnyStr) -> Result<bool, String> {
+        let mut subscriptions = self.subscriptions()?;
+        let before = subscriptions.len();
+        subscriptions.retain(|sub| sub.url != feed_url.as_ref());
+        if subscriptions.len() == before {
+            return Ok(false);
+        }
+        self.save(&subscriptions).map(|_| true)
+    }
+
+    /// Lists the episodes of a feed, newest first. Entries without an
+    /// enclosure (announcements, blog posts) are left out.
+    pub fn episodes<AnyStr: AsRef<str>>(&self, feed_url: AnyStr) -> Result<Vec<Episode>, String> {
+        let feed = Feed::fetch(&self.http.network.client(&self.http.user_agent)?, feed_url)?;
+        let mut episodes = feed.entries.into_iter()
+            .filter_map(|entry| entry.enclosure.map(|enclosure| Episode {
+                id: entry.id,
+                podcast: feed.title.clone(),
+                title: entry.title,
+                enclosure_url: enclosure.url,
+                mime_type: enclosure.mime_type,
+                length: enclosure.length,
+                duration: entry.duration,
+                published: entry.published,
+                author: entry.author.or_else(|| feed.author.clone()),
+                thumbnail_url: entry.thumbnail_url
+            }))
+            .collect::<Vec<_>>();
+        episodes.sort_by_key(|episode| std::cmp::Reverse(episode.published));
+        Ok(episodes)
+    }
+}
+
+impl ProvideSearch for PodcastRss {
+    /// Matches keywords against episode and podcast titles of every
+    /// subscribed feed, returning enclosure URLs.
+    fn search(&self, query: SearchQuery) -> Result<Vec<String>, String> {
+        let keywords = query.keywords.iter().map(|kw| kw.to_lowercase()).collect::<Vec<_>>();
+        let mut urls = vec![];
+        for subscription in self.subscriptions()? {
+            // one dead feed should not hide the others
+            let episodes = match self.episodes(&subscription.url) {
+                Ok(episodes) => episodes,
+                Err(err) => {
+                    log::warn!("Cannot search podcast {}: {err}", subscription.title);
+                    continue;
+                }
+            };
+            for episode in episodes {
+                let haystack = format!("{} {}", episode.podcast, episode.title).to_lowercase();
+                if keywords.iter().all(|kw| haystack.contains(kw)) {
+                    urls.push(episode.enclosure_url);
+                }
+            }
+        }
+        Ok(urls)
+    }
+}
+
+impl ProvideDownload for PodcastRss {
+    fn download_with_progress(&self, config: DownloadConfig, on_progress: ProgressCallback) -> Result<Downloaded, String> {
+        self.http.download_with_progress(config, on_progress)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{common::test_server::TestServer, download_provider::archive::DownloadArchive, search_provider::interface::SearchProviders};
+
+    use super::*;
+
+    fn fixture_server() -> TestServer {
+        let feed = std::fs::read(format!("{}/tests/fixtures/podcast/feed.xml", env!("CARGO_MANIFEST_DIR"))).unwrap();
+        TestServer::routes(vec![
+            ("/feed.xml", feed),
+            ("/media/ep2.mp3", b"second episode audio".to_vec()),
+        ])
+    }
+
+    #[test]
+    fn episodes_from_fixture() {
+        let podcasts = PodcastRss::new("/nonexistent/podcasts.json");
+        let feed = format!("{}/tests/fixtures/podcast/feed.xml", env!("CARGO_MANIFEST_DIR"));
+        let episodes = podcasts.episodes(&feed).unwrap();
+        // the announcement has no enclosure
+        assert_eq!(2, episodes.len());
+        assert_eq!("Episode 2: Bass Lines", episodes[0].title);
+        assert_eq!("https://cdn.example/media/ep2.mp3", episodes[0].enclosure_url);
+        assert_eq!(Some(Duration::from_secs(3723)), episodes[0].duration);
+        assert_eq!(Some(Duration::from_secs(1500)), episodes[1].duration);
+        assert_eq!(Some(2022), episodes[0].published.map(|date| date.year()));
+        assert_eq!(Some("Fixture Hosts".to_string()), episodes[1].metadata().artist);
+    }
+
+    #[test]
+    fn subscribe_search_download() {
+        let server = fixture_server();
+        let dir = tempfile::tempdir().unwrap();
+        let mut podcasts = PodcastRss::new(dir.path().join("nested/podcasts.json"));
+        podcasts.http.archive = DownloadArchive::new(dir.path().join("archive.json"));
+        let sub = podcasts.subscribe(server.url("/feed.xml")).unwrap();
+        assert_eq!("Fixture FM", sub.title);
+        // subscribing twice keeps a single entry
+        podcasts.subscribe(server.url("/feed.xml")).unwrap();
+        assert_eq!(vec![sub.clone()], podcasts.subscriptions().unwrap());
+
+        let gone = Subscription { url: server.url("/gone.xml"), title: "Gone FM".to_string() };
+        podcasts.save(&[gone.clone(), sub.clone()]).unwrap();
+        let provider: SearchProviders = podcasts.clone().into();
+        let found = provider.search(SearchQuery { keywords: vec!["BASS".to_string()] }).unwrap();
+        assert_eq!(vec!["https://cdn.example/media/ep2.mp3".to_string()], found);
+        assert!(podcasts.unsubscribe(&gone.url).unwrap());
+
+        let config = DownloadConfig::new(server.url("/media/ep2.mp3"), dir.path());
+        let path = dir.path().join("ep2.mp3");
+        assert_eq!(Downloaded::file(path.clone()), podcasts.download(config).unwrap());
+        assert_eq!("second episode audio", std::fs::read_to_string(path).unwrap());
+
+        assert!(podcasts.unsubscribe(&sub.url).unwrap());
+        assert!(!podcasts.unsubscribe(&sub.url).unwrap());
+        assert!(podcasts.subscriptions().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,181 @@
+//! Implementation of a search provider over a radio-browser style station
+//! directory. Results are stream URLs, which never finish "downloading";
+//! see [crate::common::icy] for reading what a station is currently playing.
+
+use serde::{Serialize, Deserialize};
+use serde_json::Value;
+
+use crate::{common::{self_setup::SelfSetup, icy}, download_provider::network::{NetworkOptions, USER_AGENT}};
+use super::interface::{ProvideSearch, SearchQuery};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Station {
+    pub id: String,
+    pub name: String,
+    /// The playable stream, with playlists (.pls, .m3u) already resolved
+    /// by the directory where possible
+    pub stream_url: String,
+    pub homepage: Option<String>,
+    pub favicon: Option<String>,
+    pub tags: Vec<String>,
+    /// ISO 3166-1 alpha-2
+    pub country_code: Option<String>,
+    pub codec: Option<String>,
+    /// kbps; None when the directory does not know
+    pub bitrate: Option<u32>
+}
+
+/// Filters for [RadioBrowser::stations]; unset fields match everything
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct StationQuery {
+    /// Substring of the station name
+    pub name: Option<String>,
+    /// Exact tag such as "jazz"
+    pub tag: Option<String>,
+    /// ISO 3166-1 alpha-2 code such as "DE"
+    pub country_code: Option<String>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct RadioBrowser {
+    /// Root of the directory API
+    ///
+    /// Default: "https://de1.api.radio-browser.info"
+    pub api_url: String,
+    /// Maximum number of stations per query
+    ///
+    /// Default: 50
+    pub limit: u32,
+    /// Proxy for the directory and the stations
+    ///
+    /// Default: [NetworkOptions::default]
+    pub network: NetworkOptions
+}
+
+impl Default for RadioBrowser {
+    fn default() -> Self {
+        Self { api_url: "https://de1.api.radio-browser.info".to_string(), limit: 50, network: NetworkOptions::default() }
+    }
+}
+
+impl SelfSetup for RadioBrowser {
+    fn setup(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+fn non_empty(value: &Value, key: &str) -> Option<String> {
+    value.get(key).and_then(Value::as_str)
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(str::to_string)
+}
+
+impl RadioBrowser {
+    pub fn new<AnyStr: AsRef<str>>(api_url: AnyStr) -> Self {
+        Self { api_url: api_url.as_ref().to_string(), ..Default::default() }
+    }
+
+    /// Converts one station object of the directory's JSON. Stations without
+    /// any stream URL are dropped.
+    pub fn parse_station(value: &Value) -> Option<Station> {
+        Some(Station {
+            id: non_empty(value, "stationuuid")?,
+            name: non_empty(value, "name").unwrap_or_default(),
+            stream_url: non_empty(value, "url_resolved").or_else(|| non_empty(value, "url"))?,
+            homepage: non_empty(value, "homepage"),
+            favicon: non_empty(value, "favicon"),
+            tags: non_empty(value, "tags")
+                .map(|tags| tags.split(',').map(|tag| tag.trim().to_string()).filter(|tag| !tag.is_empty()).collect())
+                .unwrap_or_default(),
+            country_code: non_empty(value, "countrycode"),
+            codec: non_empty(value, "codec"),
+            bitrate: value.get("bitrate").and_then(Value::as_u64).filter(|rate| *rate > 0).map(|rate| rate as u32)
+        })
+    }
+
+    pub fn stations(&self, query: &StationQuery) -> Result<Vec<Station>, String> {
+        let url = format!("{}/json/stations/search", self.api_url);
+        let mut params = vec![
+            ("limit", self.limit.to_string()),
+            ("hidebroken", "true".to_string()),
+            ("order", "clickcount".to_string()),
+            ("reverse", "true".to_string())
+        ];
+        params.extend([("name", &query.name), ("tag", &query.tag), ("countrycode", &query.country_code)]
+            .into_iter()
+            .filter_map(|(key, value)| value.as_ref().map(|value| (key, value.clone()))));
+        log::info!("GET {url} {params:?}");
+        let stations: Vec<Value> = self.network.client(USER_AGENT)?
+            .get(&url)
+            .query(&params)
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| format!("Request to {url} failed: {err}"))
+            .and_then(|resp| serde_json::from_reader(resp).map_err(|err| format!("Bad JSON from {url}: {err}")))?;
+        Ok(stations.iter().filter_map(Self::parse_station).collect())
+    }
+
+    /// What `station` is playing right now, see [icy::now_playing]
+    pub fn now_playing(&self, station: &Station) -> Result<Option<String>, String> {
+        icy::now_playing(&self.network.client(USER_AGENT)?, &station.stream_url)
+    }
+}
+
+impl ProvideSearch for RadioBrowser {
+    /// Searches stations by name and returns their stream URLs
+    fn search(&self, query: SearchQuery) -> Result<Vec<String>, String> {
+        let query = StationQuery { name: Some(query.keywords.join(" ")), ..Default::default() };
+        self.stations(&query)
+            .map(|stations| stations.into_iter().map(|station| station.stream_url).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::common::test_server::{TestServer, TestResponse};
+
+    use super::*;
+
+    #[test]
+    fn stations_by_tag_and_country() {
+        let server = TestServer::serve(|req| {
+            if !req.path.starts_with("/json/stations/search?") {
+                return TestResponse::not_found();
+            }
+            assert!(req.path.contains("tag=jazz"), "{}", req.path);
+            assert!(req.path.contains("countrycode=DE"), "{}", req.path);
+            assert!(!req.path.contains("name="), "{}", req.path);
+            TestResponse::ok(std::fs::read(format!("{}/tests/fixtures/radio/stations.json", env!("CARGO_MANIFEST_DIR"))).unwrap())
+        });
+        let radio = RadioBrowser::new(server.url(""));
+        let stations = radio.stations(&StationQuery {
+            tag: Some("jazz".to_string()),
+            country_code: Some("DE".to_string()),
+            ..Default::default()
+        }).unwrap();
+        // the last station has no stream URL at all
+        assert_eq!(2, stations.len());
+        assert_eq!("https://stream.example/jazz.mp3", stations[0].stream_url);
+        assert_eq!(vec!["jazz".to_string(), "smooth jazz".to_string()], stations[0].tags);
+        assert_eq!(Some(128), stations[0].bitrate);
+        // falls back to the unresolved URL
+        assert_eq!("https://stream.example/bebop.pls", stations[1].stream_url);
+        assert_eq!(None, stations[1].bitrate);
+    }
+
+    #[test]
+    fn goes_through_the_proxy() {
+        // a proxy gets the whole URL
+        let server = TestServer::serve(|req| match req.path.starts_with("http://radio.example.invalid/json/stations/search?") {
+            true => TestResponse::ok(std::fs::read(format!("{}/tests/fixtures/radio/stations.json", env!("CARGO_MANIFEST_DIR"))).unwrap()),
+            false => TestResponse::not_found()
+        });
+        let network = NetworkOptions { proxy: Some(server.url("")), ..Default::default() };
+        let radio = RadioBrowser { network, ..RadioBrowser::new("http://radio.example.invalid") };
+        assert_eq!(2, radio.stations(&StationQuery::default()).unwrap().len());
+        assert!(RadioBrowser::new("http://radio.example.invalid").stations(&StationQuery::default()).is_err());
+    }
+}
@@ -0,0 +1,299 @@
+//! Follows YouTube channels through their public Atom feeds
+//! (`/feeds/videos.xml?channel_id=`), which need neither a browser nor an
+//! API key. Polling reports uploads we have not seen before, which gives us
+//! new-release notifications for artists.
+
+use std::{collections::BTreeSet, path::{Path, PathBuf}};
+
+use chrono::{DateTime, Utc};
+use serde::{Serialize, Deserialize};
+
+use crate::{common::{self_setup::SelfSetup, config::{load_json, project_dirs, save_json}, feed::{Feed, FeedEntry}}, download_provider::network::{NetworkOptions, USER_AGENT}};
+use super::interface::{ProvideSearch, SearchQuery};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct FollowedChannel {
+    pub channel_id: String,
+    pub title: String,
+    /// Entry ids present in the feed the last time we looked
+    #[serde(default)]
+    pub seen: BTreeSet<String>,
+    #[serde(default)]
+    pub last_checked: Option<DateTime<Utc>>
+}
+
+/// What we persist between polls
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(default)]
+pub struct FeedState {
+    pub channels: Vec<FollowedChannel>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Upload {
+    pub channel_id: String,
+    pub channel_title: String,
+    pub video_id: String,
+    pub title: String,
+    pub url: String,
+    pub published: Option<DateTime<Utc>>,
+    pub thumbnail_url: Option<String>
+}
+
+/// Outcome of [YoutubeFeed::poll]. A channel failing to load does not
+/// prevent the others from being checked.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct PollReport {
+    /// Oldest first
+    pub new_uploads: Vec<Upload>,
+    /// (channel id, reason)
+    pub failures: Vec<(String, String)>
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct YoutubeFeed {
+    /// Where the Atom feeds are served; the channel id is appended as
+    /// the `channel_id` query parameter.
+    ///
+    /// Default: "https://www.youtube.com/feeds/videos.xml"
+    pub feed_url: String,
+    /// JSON file holding the [FeedState]
+    ///
+    /// Default: `<data dir>/youtube_feed.json`
+    pub state_path: PathBuf,
+    /// Proxy the feeds are fetched through
+    ///
+    /// Default: [NetworkOptions::default]
+    pub network: NetworkOptions
+}
+
+impl Default for YoutubeFeed {
+    fn default() -> Self {
+        Self {
+            feed_url: "https://www.youtube.com/feeds/videos.xml".to_string(),
+            state_path: project_dirs().data_dir().join("youtube_feed.json"),
+            network: NetworkOptions::default()
+        }
+    }
+}
+
+impl SelfSetup for YoutubeFeed {
+    fn setup(&self) -> Result<(), String> {
+        match self.state_path.parent() {
+            Some(dir) if !dir.exists() => std::fs::create_dir_all(dir)
+                .map_err(|err| format!("Cannot std::fs::create_dir_all({dir:?}): {err:?}")),
+            _ => Ok(())
+        }
+    }
+}
+
+impl YoutubeFeed {
+    pub fn new<AnyStr: AsRef<str>, P: AsRef<Path>>(feed_url: AnyStr, state_path: P) -> Self {
+        Self { feed_url: feed_url.as_ref().to_string(), state_path: state_path.as_ref().to_path_buf(), ..Default::default() }
+    }
+
+    /// Accepts a channel id or a `/channel/<id>` URL
+    /// ```
+    /// use cli_music_player::search_provider::youtube_feed::YoutubeFeed;
+    ///
+    /// assert_eq!(Ok("UCabc".to_string()), YoutubeFeed::channel_id("UCabc"));
+    /// assert_eq!(Ok("UCabc".to_string()), YoutubeFeed::channel_id("https://www.youtube.com/channel/UCabc/videos"));
+    /// assert!(YoutubeFeed::channel_id("https://www.youtube.com/@handle").is_err());
+    /// ```
+    pub fn channel_id<AnyStr: AsRef<str>>(channel: AnyStr) -> Result<String, String> {
+        let channel = channel.as_ref().trim();
+        let id = match channel.find("/channel/") {
+            Some(idx) => channel[idx + "/channel/".len()..].split(['/', '?']).next().unwrap_or_default(),
+            None => channel
+        };
+        (id.starts_with("UC") && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .then(|| id.to_string())
+            .ok_or_else(|| format!("{channel:?} is not a channel id (UC...) nor a /channel/ URL"))
+    }
+
+    pub fn state(&self) -> Result<FeedState, String> {
+        load_json(&self.state_path, "feed state")
+    }
+
+    fn save(&self, state: &FeedState) -> Result<(), String> {
+        save_json(&self.state_path, "feed state", state)
+    }
+
+    fn fetch(&self, channel_id: &str) -> Result<Feed, String> {
+        Feed::fetch(&self.network.client(USER_AGENT)?, format!("{}?channel_id={channel_id}", self.feed_url))
+    }
+
+    fn upload_of(channel: &FollowedChannel, entry: FeedEntry) -> Upload {
+        let video_id = entry.id.strip_prefix("yt:video:").unwrap_or(&entry.id).to_string();
+        Upload {
+            channel_id: channel.channel_id.clone(),
+            channel_title: channel.title.clone(),
+            url: entry.link.unwrap_or_else(|| format!("https://www.youtube.com/watch?v={video_id}")),
+            video_id,
+            title: entry.title,
+            published: entry.published,
+            thumbnail_url: entry.thumbnail_url
+        }
+    }
+
+    /// Starts following a channel. Whatever is currently in its feed counts
+    /// as seen, so only later uploads get reported by [YoutubeFeed::poll].
+    pub fn follow<AnyStr: AsRef<str>>(&self, channel: AnyStr) -> Result<FollowedChannel, String> {
+        let channel_id = Self::channel_id(channel)?;
+        let mut state = self.state()?;
+        if let Some(existing) = state.channels.iter().find(|c| c.channel_id == channel_id) {
+            return Ok(existing.clone());
+        }
+        let feed = self.fetch(&channel_id)?;
+        let followed = FollowedChannel {
+            channel_id,
+            title: feed.author.unwrap_or(feed.title),
+            seen: feed.entries.into_iter().map(|entry| entry.id).collect(),
+            last_checked: Some(Utc::now())
+        };
+        state.channels.push(followed.clone());
+        self.save(&state)?;
+        Ok(followed)
+    }
+
+    /// Returns whether we were following the channel
+    pub fn unfollow<AnyStr: AsRef<str>>(&self, channel: AnyStr) -> Result<bool, String> {
+        let channel_id = Self::channel_id(channel)?;
+        let mut state = self.state()?;
+        let before = state.channels.len();
+        state.channels.retain(|c| c.channel_id != channel_id);
+        if state.channels.len() == before {
+            return Ok(false);
+        }
+        self.save(&state).map(|_| true)
+    }
+
+    /// Checks every followed channel and reports uploads not seen before.
+    /// Seen ids are pruned to what the feed still lists, since YouTube only
+    /// ever serves the latest handful of entries.
+    pub fn poll(&self) -> Result<PollReport, String> {
+        let mut state = self.state()?;
+        let mut report = PollReport::default();
+        for channel in state.channels.iter_mut() {
+            let feed = match self.fetch(&channel.channel_id) {
+                Ok(feed) => feed,
+                Err(err) => {
+                    log::warn!("Cannot poll channel {}: {err}", channel.channel_id);
+                    report.failures.push((channel.channel_id.clone(), err));
+                    continue;
+                }
+            };
+            let current = feed.entries.iter().map(|entry| entry.id.clone()).collect::<BTreeSet<_>>();
+            let mut fresh = feed.entries.into_iter()
+                .filter(|entry| !channel.seen.contains(&entry.id))
+                .map(|entry| Self::upload_of(channel, entry))
+                .collect::<Vec<_>>();
+            fresh.sort_by_key(|upload| upload.published);
+            report.new_uploads.extend(fresh);
+            channel.seen = current;
+            channel.last_checked = Some(Utc::now());
+        }
+        self.save(&state)?;
+        Ok(report)
+    }
+}
+
+impl ProvideSearch for YoutubeFeed {
+    /// Matches keywords against the recent uploads of followed channels,
+    /// without touching the seen state.
+    fn search(&self, query: SearchQuery) -> Result<Vec<String>, String> {
+        let keywords = query.keywords.iter().map(|kw| kw.to_lowercase()).collect::<Vec<_>>();
+        let mut urls = vec![];
+        for channel in self.state()?.channels {
+            // as when polling, one failing channel should not hide the others
+            let feed = match self.fetch(&channel.channel_id) {
+                Ok(feed) => feed,
+                Err(err) => {
+                    log::warn!("Cannot search channel {}: {err}", channel.channel_id);
+                    continue;
+                }
+            };
+            for entry in feed.entries {
+                let upload = Self::upload_of(&channel, entry);
+                let haystack = format!("{} {}", upload.channel_title, upload.title).to_lowercase();
+                if keywords.iter().all(|kw| haystack.contains(kw)) {
+                    urls.push(upload.url);
+                }
+            }
+        }
+        Ok(urls)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use crate::common::test_server::{TestServer, TestResponse};
+
+    use super::*;
+
+    fn entry(id: &str, title: &str, published: &str) -> String {
+        format!(r#"<entry>
+            <id>yt:video:{id}</id><yt:videoId>{id}</yt:videoId>
+            <title>{title}</title>
+            <link rel="alternate" href="https://www.youtube.com/watch?v={id}"/>
+            <published>{published}</published>
+            <media:group><media:thumbnail url="https://i.ytimg.com/vi/{id}/hqdefault.jpg" width="480" height="360"/></media:group>
+        </entry>"#)
+    }
+
+    fn channel_feed(entries: &[String]) -> String {
+        format!(r#"<?xml version="1.0" encoding="UTF-8"?>
+            <feed xmlns:yt="http://www.youtube.com/xml/schemas/2015" xmlns:media="http://search.yahoo.com/mrss/" xmlns="http://www.w3.org/2005/Atom">
+            <title>The Fixtures</title><author><name>The Fixtures</name></author>
+            {}</feed>"#, entries.concat())
+    }
+
+    #[test]
+    fn poll_reports_only_new_uploads() {
+        let entries = Arc::new(Mutex::new(vec![entry("old1", "Old Song", "2022-07-01T00:00:00+00:00")]));
+        let served = entries.clone();
+        let server = TestServer::serve(move |req| match req.path.as_str() {
+            "/feeds/videos.xml?channel_id=UCfixtures" => TestResponse::ok(channel_feed(&served.lock().unwrap())),
+            _ => TestResponse::not_found()
+        });
+        let dir = tempfile::tempdir().unwrap();
+        let feed = YoutubeFeed::new(server.url("/feeds/videos.xml"), dir.path().join("state.json"));
+
+        let followed = feed.follow("https://www.youtube.com/channel/UCfixtures").unwrap();
+        assert_eq!("The Fixtures", followed.title);
+        assert_eq!(PollReport::default(), feed.poll().unwrap());
+
+        entries.lock().unwrap().insert(0, entry("new2", "Second Single", "2022-07-20T00:00:00+00:00"));
+        entries.lock().unwrap().insert(0, entry("new3", "Third Single", "2022-07-21T00:00:00+00:00"));
+        let report = feed.poll().unwrap();
+        assert_eq!(vec!["new2", "new3"], report.new_uploads.iter().map(|u| u.video_id.as_str()).collect::<Vec<_>>());
+        assert_eq!("https://www.youtube.com/watch?v=new2", report.new_uploads[0].url);
+        assert_eq!(Some("https://i.ytimg.com/vi/new2/hqdefault.jpg".to_string()), report.new_uploads[0].thumbnail_url);
+        // already reported
+        assert!(feed.poll().unwrap().new_uploads.is_empty());
+
+        assert!(feed.unfollow("UCfixtures").unwrap());
+        assert!(feed.state().unwrap().channels.is_empty());
+    }
+
+    #[test]
+    fn poll_keeps_going_when_a_channel_fails() {
+        let dir = tempfile::tempdir().unwrap();
+        let server = TestServer::serve(|_| TestResponse::not_found());
+        let feed = YoutubeFeed::new(server.url("/feeds/videos.xml"), dir.path().join("state.json"));
+        feed.save(&FeedState { channels: vec![FollowedChannel {
+            channel_id: "UCgone".to_string(),
+            title: "Gone".to_string(),
+            seen: BTreeSet::new(),
+            last_checked: None
+        }]}).unwrap();
+        let report = feed.poll().unwrap();
+        assert_eq!(1, report.failures.len());
+        assert_eq!("UCgone", report.failures[0].0);
+        // and so does searching
+        assert_eq!(Ok(vec![]), feed.search(SearchQuery { keywords: vec!["gone".to_string()] }));
+    }
+}
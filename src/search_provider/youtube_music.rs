@@ -0,0 +1,360 @@
+//! Implementation of a search provider backed by YouTube Music's internal
+//! (innertube) JSON API. Unlike [super::youtube_scraper::YoutubeScraper],
+//! this needs no browser since music.youtube.com answers plain POST requests.
+
+use serde::{Serialize, Deserialize};
+use serde_json::{json, Value};
+
+use crate::{common::{self_setup::SelfSetup, metadata::parse_clock_duration}, download_provider::network::{NetworkOptions, USER_AGENT}};
+use super::interface::{ProvideSearch, SearchQuery, ProvideAlbum, Album, AlbumTrack, AlbumSummary, Discography};
+
+/// Search params that restricts results to songs
+const SONGS_FILTER: &str = "EgWKAQIIAWoKEAkQBRAKEAMQBA%3D%3D";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct YoutubeMusic {
+    /// Root of the innertube API; overridable so tests can point to a
+    /// local server.
+    ///
+    /// Default: "https://music.youtube.com/youtubei/v1"
+    pub api_url: String,
+    /// Prefix of the watch URLs we hand out
+    ///
+    /// Default: "https://music.youtube.com"
+    pub watch_url: String,
+    /// The `WEB_REMIX` client version we claim to be
+    ///
+    /// Default: "1.20220715.01.00"
+    pub client_version: String,
+    /// Language of the returned texts. We parse things like "Album" and
+    /// "Singles" so this should stay English.
+    ///
+    /// Default: "en"
+    pub language: String,
+    /// Proxy the API is reached through
+    ///
+    /// Default: [NetworkOptions::default]
+    pub network: NetworkOptions
+}
+
+impl Default for YoutubeMusic {
+    fn default() -> Self {
+        Self {
+            api_url: "https://music.youtube.com/youtubei/v1".to_string(),
+            watch_url: "https://music.youtube.com".to_string(),
+            client_version: "1.20220715.01.00".to_string(),
+            language: "en".to_string(),
+            network: NetworkOptions::default()
+        }
+    }
+}
+
+impl SelfSetup for YoutubeMusic {
+    fn setup(&self) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+/// Walks a chain of JSON pointers, returning the first that resolves
+fn first_of<'a>(value: &'a Value, pointers: &[&str]) -> Option<&'a Value> {
+    pointers.iter().find_map(|pointer| value.pointer(pointer))
+}
+
+fn text_of(value: &Value, pointer: &str) -> Option<String> {
+    value.pointer(pointer).and_then(Value::as_str).map(str::to_string)
+}
+
+/// Concatenates every run of a `{"runs": [{"text": ...}]}` object
+fn runs_text(runs: Option<&Value>) -> Vec<String> {
+    runs.and_then(|runs| runs.pointer("/runs"))
+        .and_then(Value::as_array)
+        .map(|runs| runs.iter().filter_map(|run| text_of(run, "/text")).collect())
+        .unwrap_or_default()
+}
+
+/// Subtitles look like ["Album", " • ", "Artist", " • ", "2019"];
+/// a year is any run that is a plain 4-digit number.
+fn year_of(subtitle: &[String]) -> Option<i32> {
+    subtitle.iter().rev()
+        .find(|run| run.len() == 4 && run.chars().all(|c| c.is_ascii_digit()))
+        .and_then(|run| run.parse().ok())
+}
+
+fn best_thumbnail(value: &Value) -> Option<String> {
+    value.as_array()
+        .and_then(|thumbs| thumbs.iter().max_by_key(|thumb| thumb.pointer("/width").and_then(Value::as_u64).unwrap_or(0)))
+        .and_then(|thumb| text_of(thumb, "/url"))
+}
+
+impl YoutubeMusic {
+    pub fn new<AnyStr: AsRef<str>>(api_url: AnyStr) -> Self {
+        Self { api_url: api_url.as_ref().to_string(), ..Default::default() }
+    }
+
+    fn post(&self, endpoint: &str, mut body: Value) -> Result<Value, String> {
+        body["context"] = json!({
+            "client": {
+                "clientName": "WEB_REMIX",
+                "clientVersion": self.client_version,
+                "hl": self.language
+            }
+        });
+        let url = format!("{}/{endpoint}?prettyPrint=false", self.api_url);
+        log::info!("POST {url}");
+        self.network.client(USER_AGENT)?
+            .post(&url)
+            .header("Content-Type", "application/json")
+            .header("Referer", "https://music.youtube.com/")
+            .body(body.to_string())
+            .send()
+            .and_then(|resp| resp.error_for_status())
+            .map_err(|err| format!("Request to {url} failed: {err}"))
+            .and_then(|resp| serde_json::from_reader(resp).map_err(|err| format!("Bad JSON from {url}: {err}")))
+    }
+
+    fn browse(&self, browse_id: &str, params: Option<&str>) -> Result<Value, String> {
+        let mut body = json!({"browseId": browse_id});
+        if let Some(params) = params {
+            body["params"] = json!(params);
+        }
+        self.post("browse", body)
+    }
+
+    /// Accepts either a bare browse id or a music.youtube.com URL to it.
+    /// ```
+    /// use cli_music_player::search_provider::youtube_music::YoutubeMusic;
+    ///
+    /// assert_eq!("MPREb_abc", YoutubeMusic::browse_id("MPREb_abc"));
+    /// assert_eq!("MPREb_abc", YoutubeMusic::browse_id("https://music.youtube.com/browse/MPREb_abc"));
+    /// assert_eq!("UCxyz", YoutubeMusic::browse_id("https://music.youtube.com/channel/UCxyz?feature=share"));
+    /// ```
+    pub fn browse_id(id_or_url: &str) -> &str {
+        id_or_url.rsplit('/').next()
+            .and_then(|last| last.split('?').next())
+            .unwrap_or(id_or_url)
+    }
+
+    fn watch_url_of(&self, video_id: &str) -> String {
+        format!("{}/watch?v={video_id}", self.watch_url)
+    }
+
+    fn video_id_of(item: &Value) -> Option<String> {
+        first_of(item, &[
+            "/playlistItemData/videoId",
+            "/flexColumns/0/musicResponsiveListItemFlexColumnRenderer/text/runs/0/navigationEndpoint/watchEndpoint/videoId",
+            "/overlay/musicItemThumbnailOverlayRenderer/content/musicPlayButtonRenderer/playNavigationEndpoint/watchEndpoint/videoId"
+        ]).and_then(Value::as_str).map(str::to_string)
+    }
+
+    fn flex_column(item: &Value, idx: usize) -> Option<&Value> {
+        item.pointer(&format!("/flexColumns/{idx}/musicResponsiveListItemFlexColumnRenderer/text"))
+    }
+
+    /// Parses the response of browsing an album id
+    pub fn parse_album(&self, album_id: &str, response: &Value) -> Result<Album, String> {
+        let header = first_of(response, &["/header/musicDetailHeaderRenderer", "/header/musicResponsiveHeaderRenderer"])
+            .ok_or_else(|| format!("Album {album_id} has no header"))?;
+        let title = runs_text(header.pointer("/title")).concat();
+        let subtitle = runs_text(header.pointer("/subtitle"));
+        // ["Album", " • ", "Artist", " • ", "2019"]: the artist is the second non-separator run
+        let artist = runs_text(header.pointer("/straplineTextOne")).first().cloned()
+            .or_else(|| subtitle.iter().filter(|run| run.trim() != "•").nth(1).cloned())
+            .filter(|artist| year_of(std::slice::from_ref(artist)).is_none());
+        let thumbnail_url = first_of(header, &[
+            "/thumbnail/croppedSquareThumbnailRenderer/thumbnail/thumbnails",
+            "/thumbnail/musicThumbnailRenderer/thumbnail/thumbnails"
+        ]).and_then(best_thumbnail);
+
+        let shelf = first_of(response, &[
+            "/contents/singleColumnBrowseResultsRenderer/tabs/0/tabRenderer/content/sectionListRenderer/contents/0/musicShelfRenderer/contents",
+            "/contents/twoColumnBrowseResultsRenderer/secondaryContents/sectionListRenderer/contents/0/musicShelfRenderer/contents"
+        ]).and_then(Value::as_array)
+            .ok_or_else(|| format!("Album {album_id} has no track list"))?;
+        let mut tracks = shelf.iter()
+            .filter_map(|item| item.pointer("/musicResponsiveListItemRenderer"))
+            .enumerate()
+            .map(|(position, item)| AlbumTrack {
+                track_number: runs_text(item.pointer("/index")).first()
+                    .and_then(|idx| idx.trim().parse().ok())
+                    .unwrap_or(position as u32 + 1),
+                title: runs_text(Self::flex_column(item, 0)).concat(),
+                artists: runs_text(Self::flex_column(item, 1)).into_iter()
+                    .filter(|run| !matches!(run.trim(), "" | "," | "&" | "•"))
+                    .collect(),
+                duration: runs_text(item.pointer("/fixedColumns/0/musicResponsiveListItemFixedColumnRenderer/text"))
+                    .first().and_then(parse_clock_duration),
+                url: Self::video_id_of(item).map(|id| self.watch_url_of(&id))
+            })
+            .collect::<Vec<_>>();
+        tracks.sort_by_key(|track| track.track_number);
+
+        Ok(Album {
+            id: album_id.to_string(),
+            title,
+            year: year_of(&subtitle),
+            artist,
+            thumbnail_url,
+            tracks
+        })
+    }
+
+    fn parse_two_row_item(item: &Value) -> Option<AlbumSummary> {
+        let item = item.pointer("/musicTwoRowItemRenderer")?;
+        Some(AlbumSummary {
+            id: text_of(item, "/navigationEndpoint/browseEndpoint/browseId")?,
+            title: runs_text(item.pointer("/title")).concat(),
+            year: year_of(&runs_text(item.pointer("/subtitle"))),
+            thumbnail_url: item.pointer("/thumbnailRenderer/musicThumbnailRenderer/thumbnail/thumbnails")
+                .and_then(best_thumbnail)
+        })
+    }
+
+    /// Lists a carousel's items, following its "More" button when present
+    /// since carousels only show the latest handful of releases.
+    fn carousel_items(&self, carousel: &Value) -> Result<Vec<AlbumSummary>, String> {
+        let more = carousel.pointer("/header/musicCarouselShelfBasicHeaderRenderer/moreContentButton/buttonRenderer/navigationEndpoint/browseEndpoint");
+        let items = match more.and_then(|more| text_of(more, "/browseId").map(|id| (id, text_of(more, "/params")))) {
+            Some((browse_id, params)) => {
+                let response = self.browse(&browse_id, params.as_deref())?;
+                first_of(&response, &[
+                    "/contents/singleColumnBrowseResultsRenderer/tabs/0/tabRenderer/content/sectionListRenderer/contents/0/gridRenderer/items"
+                ]).and_then(Value::as_array).cloned().unwrap_or_default()
+            },
+            None => carousel.pointer("/contents").and_then(Value::as_array).cloned().unwrap_or_default()
+        };
+        Ok(items.iter().filter_map(Self::parse_two_row_item).collect())
+    }
+
+    /// Parses the response of browsing an artist (channel) id
+    pub fn parse_discography(&self, artist_id: &str, response: &Value) -> Result<Discography, String> {
+        let name = first_of(response, &[
+            "/header/musicImmersiveHeaderRenderer/title",
+            "/header/musicVisualHeaderRenderer/title"
+        ]).map(|title| runs_text(Some(title)).concat())
+            .ok_or_else(|| format!("Artist {artist_id} has no header"))?;
+        let sections = response.pointer("/contents/singleColumnBrowseResultsRenderer/tabs/0/tabRenderer/content/sectionListRenderer/contents")
+            .and_then(Value::as_array).cloned().unwrap_or_default();
+        let mut discography = Discography { artist_id: artist_id.to_string(), name, albums: vec![], singles: vec![] };
+        for carousel in sections.iter().filter_map(|section| section.pointer("/musicCarouselShelfRenderer")) {
+            let heading = runs_text(carousel.pointer("/header/musicCarouselShelfBasicHeaderRenderer/title")).concat();
+            match heading.as_str() {
+                "Albums" => discography.albums = self.carousel_items(carousel)?,
+                "Singles" | "Singles & EPs" => discography.singles = self.carousel_items(carousel)?,
+                _ => {}
+            }
+        }
+        Ok(discography)
+    }
+
+    /// Parses the response of a song search into watch URLs
+    pub fn parse_search(&self, response: &Value) -> Vec<String> {
+        response.pointer("/contents/tabbedSearchResultsRenderer/tabs/0/tabRenderer/content/sectionListRenderer/contents")
+            .and_then(Value::as_array)
+            .into_iter()
+            .flatten()
+            .filter_map(|section| section.pointer("/musicShelfRenderer/contents").and_then(Value::as_array))
+            .flatten()
+            .filter_map(|item| item.pointer("/musicResponsiveListItemRenderer"))
+            .filter_map(Self::video_id_of)
+            .map(|id| self.watch_url_of(&id))
+            .collect()
+    }
+}
+
+impl ProvideSearch for YoutubeMusic {
+    fn search(&self, query: SearchQuery) -> Result<Vec<String>, String> {
+        let response = self.post("search", json!({
+            "query": query.keywords.join(" "),
+            "params": SONGS_FILTER
+        }))?;
+        Ok(self.parse_search(&response))
+    }
+}
+
+impl ProvideAlbum for YoutubeMusic {
+    fn album(&self, album_id: &str) -> Result<Album, String> {
+        let album_id = Self::browse_id(album_id);
+        self.browse(album_id, None)
+            .and_then(|response| self.parse_album(album_id, &response))
+    }
+
+    fn discography(&self, artist_id: &str) -> Result<Discography, String> {
+        let artist_id = Self::browse_id(artist_id);
+        self.browse(artist_id, None)
+            .and_then(|response| self.parse_discography(artist_id, &response))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use crate::common::test_server::{TestServer, TestResponse};
+
+    use super::*;
+
+    fn fixture(name: &str) -> Vec<u8> {
+        std::fs::read(format!("{}/tests/fixtures/youtube_music/{name}", env!("CARGO_MANIFEST_DIR"))).unwrap()
+    }
+
+    /// Answers browse requests by browseId, and search requests with the search fixture
+    fn mock_music() -> (TestServer, YoutubeMusic) {
+        let server = TestServer::serve(|req| {
+            let body: Value = serde_json::from_slice(&req.body).unwrap_or_default();
+            let name = match (req.path.split('?').next().unwrap_or_default(), body["browseId"].as_str()) {
+                ("/browse", Some("MPREb_album")) => "album.json",
+                ("/browse", Some("UCartist")) => "artist.json",
+                ("/browse", Some("UCartist_albums")) => "artist_albums.json",
+                ("/search", _) => "search.json",
+                _ => return TestResponse::not_found()
+            };
+            TestResponse::ok(fixture(name))
+        });
+        let music = YoutubeMusic::new(server.url(""));
+        (server, music)
+    }
+
+    #[test]
+    fn album_tracks_are_ordered() {
+        let (_server, music) = mock_music();
+        let album = music.album("https://music.youtube.com/browse/MPREb_album").unwrap();
+        assert_eq!("Glass Houses", album.title);
+        assert_eq!(Some("The Fixtures".to_string()), album.artist);
+        assert_eq!(Some(2019), album.year);
+        assert_eq!(vec![1, 2, 3], album.tracks.iter().map(|t| t.track_number).collect::<Vec<_>>());
+        assert_eq!("Opening", album.tracks[0].title);
+        assert_eq!(Some(Duration::from_secs(225)), album.tracks[0].duration);
+        assert_eq!(vec!["The Fixtures".to_string(), "Guest".to_string()], album.tracks[1].artists);
+        // the third track is greyed out
+        assert_eq!(None, album.tracks[2].url);
+
+        let configs = album.download_configs("/tmp");
+        assert_eq!(2, configs.len());
+        let meta = configs[1].metadata.as_ref().unwrap();
+        assert_eq!(Some(2), meta.track_number);
+        assert_eq!(Some(3), meta.track_total);
+        assert_eq!(Some("Glass Houses".to_string()), meta.album);
+        assert_eq!(Some("The Fixtures, Guest".to_string()), meta.artist);
+        assert_eq!("https://music.youtube.com/watch?v=vid2", configs[1].uri);
+    }
+
+    #[test]
+    fn discography_follows_more_button() {
+        let (_server, music) = mock_music();
+        let discography = music.discography("UCartist").unwrap();
+        assert_eq!("The Fixtures", discography.name);
+        // the carousel shows one album, the "More" page lists two
+        assert_eq!(vec!["MPREb_album", "MPREb_older"], discography.albums.iter().map(|a| a.id.as_str()).collect::<Vec<_>>());
+        assert_eq!(Some(2017), discography.albums[1].year);
+        assert_eq!(1, discography.singles.len());
+        assert_eq!("Lone Single", discography.singles[0].title);
+    }
+
+    #[test]
+    fn search_returns_watch_urls() {
+        let (_server, music) = mock_music();
+        let results = music.search(SearchQuery { keywords: vec!["glass".to_string(), "houses".to_string()] }).unwrap();
+        assert_eq!(vec!["https://music.youtube.com/watch?v=vid1".to_string()], results);
+    }
+}
@@ -184,7 +184,7 @@ pub struct ProxyConfigComponents {
 
 impl AsRef<ProxyConfigComponents> for ProxyConfigComponents {
     fn as_ref(&self) -> &ProxyConfigComponents {
-        &self
+        self
     }
 }
 
@@ -420,7 +420,7 @@ impl BrowserType {
             .or_else(|_err| Self::try_fit(value))
             .ok()
     }
-    fn try_fit(value: serde_json::Value) -> Result<BrowserType, serde_json::error::Error> {
+    fn try_fit(_value: serde_json::Value) -> Result<BrowserType, serde_json::error::Error> {
         todo!("This iterates through each schema of BrowerType and attempt to parse each of them")
     }
 }
@@ -487,7 +487,7 @@ impl YoutubeScraper {
                 e.get_attributes().ok()
                   .and_then(|e| e)
                   .and_then(|mut attrs| attrs.remove("href"))
-                  .and_then(|watch_url| Some(format!("https://www.youtube.com{watch_url}")))
+                  .map(|watch_url| format!("https://www.youtube.com{watch_url}"))
             ).collect::<Vec<_>>();
         log::info!("elems: {velems:?}");
         Ok(velems)
@@ -506,6 +506,7 @@ mod test {
 
     use super::*;
 
+    #[allow(clippy::len_zero)]
     fn provide_search_test<AnyStr: AsRef<str>>(provider: SearchProviders, keywords: Vec<String>, expect_contains: AnyStr) 
         -> Result<(), String> 
     {